@@ -1,6 +1,15 @@
 #![feature(lang_items)]
 #![feature(asm)]
-#![no_std]
+#![cfg_attr(not(test), no_std)]
+
+/// Binary format of system call messages in the task buffer.
+mod message;
+
+pub use self::message::{TaskBuffer, Message, MessageError, opcode, read_fast_call,
+                        ABI_VERSION, ARGUMENT_COUNT, RESPONSE_COUNT, DATA_LENGTH,
+                        STATUS_OK, STATUS_INVALID_VERSION, STATUS_INVALID_OPCODE,
+                        STATUS_INVALID_ARGUMENT, STATUS_INVALID_RESPONSE,
+                        STATUS_INVALID_CAPABILITY, STATUS_INVALID_STATE};
 
 /// A trait that allows setting a struct back to its default value.
 pub trait SetDefault {
@@ -22,8 +31,10 @@ pub enum CapSendMessage {
 #[derive(Debug, Clone)]
 pub enum SystemCall {
     CPoolListDebug,
+    /// Print the bytes at `(offset, length)` of the data area of the
+    /// task buffer.
     Print {
-        request: ([u8; 32], usize)
+        request: (usize, usize)
     },
     RetypeCPool {
         request: (usize, usize),
@@ -59,16 +70,163 @@ pub enum SystemCall {
     TaskSetInactive {
         request: usize
     },
+    TaskSetPriority {
+        request: (usize, u8),
+    },
+    TaskSetAffinity {
+        request: (usize, Option<usize>),
+    },
+    TaskSetTimeslice {
+        request: (usize, u64),
+    },
+    TaskSetBudget {
+        request: (usize, u64, u64),
+    },
+    TaskCpuTime {
+        request: usize,
+        response: Option<u64>,
+    },
+    RetypeSchedContext {
+        request: (usize, usize),
+    },
+    SchedContextConfigure {
+        request: (usize, u8, u64, u64),
+    },
+    TaskBindSchedContext {
+        request: (usize, usize),
+    },
+    TaskSetPassive {
+        request: (usize, bool),
+    },
+    TaskReadRegisters {
+        request: usize,
+        response: Option<TaskRegisters>,
+    },
+    TaskWriteRegisters {
+        request: (usize, TaskRegisters),
+    },
+    TaskDelete {
+        request: usize,
+    },
+    TaskExit {
+        request: u64,
+    },
+    TaskSetExitChannel {
+        request: (usize, usize),
+    },
+    TaskYield,
+    TaskSleep {
+        request: u64,
+    },
+    TaskWakeup {
+        request: usize,
+    },
+    RetypeIRQHandler {
+        request: (usize, u8, usize),
+    },
+    IRQHandlerSetChannel {
+        request: (usize, usize),
+    },
+    IRQAck {
+        request: usize
+    },
+    IRQHandlerDelete {
+        request: usize,
+    },
+    RetypeTopPageTable {
+        request: (usize, usize),
+    },
+    TopPageTableMapCopyOnWrite {
+        request: (usize, usize, u64, usize),
+    },
+    TaskSetFaultUntyped {
+        request: (usize, usize),
+    },
+    TaskSetPagerChannel {
+        request: (usize, usize),
+    },
+    RetypeRawPage {
+        request: (usize, usize),
+    },
+    RetypeTaskBuffer {
+        request: (usize, usize),
+    },
+    TopPageTableMap {
+        request: (usize, u64, usize, usize),
+    },
+    TopPageTableMapGuard {
+        request: (usize, u64, usize, usize),
+    },
+    /// Name the target task with the bytes at `(offset, length)` of
+    /// the data area of the task buffer. The name is used in kernel
+    /// logs and is at most `TASK_NAME_LENGTH` bytes long.
+    TaskSetName {
+        request: (usize, usize, usize),
+    },
+    /// Read the kernel log from a position into `(offset, length)` of
+    /// the data area of the task buffer, using the kernel log
+    /// capability at the first slot. The response is the position
+    /// after the bytes read and the number of bytes read.
+    KernelLogRead {
+        request: (usize, u64, usize, usize),
+        response: Option<(u64, usize)>,
+    },
 }
 
-/// Represents a task buffer used for system calls.
-#[derive(Debug)]
-pub struct TaskBuffer {
-    pub call: Option<SystemCall>
-}
+/// Maximum length in bytes of a task name.
+pub const TASK_NAME_LENGTH: usize = 16;
+
+/// Number of slots in a capability pool. Capability pool slots passed
+/// in system calls must be below it.
+pub const CPOOL_SIZE: usize = 256;
+
+/// Fast system call number, passed in `rax` to the `syscall`
+/// instruction: the system call is read from the task buffer, like
+/// with `int 0x80`. On return, `rax` holds the status of the fast
+/// system call and `rdx` its value, as in the task buffer; the status
+/// of a call read from the task buffer is in the buffer.
+pub const FAST_CALL_BUFFER: u64 = 0;
+/// Fast system call number of `ChannelPut`. The channel is passed in
+/// `rdi` and the value in `rsi`.
+pub const FAST_CALL_CHANNEL_PUT: u64 = 1;
+/// Fast system call number of `TaskYield`.
+pub const FAST_CALL_TASK_YIELD: u64 = 2;
+/// Fast system call number of `TaskSleep`. The ticks are passed in
+/// `rdi`.
+pub const FAST_CALL_TASK_SLEEP: u64 = 3;
+/// Fast system call number of `TaskWakeup`. The target task is
+/// passed in `rdi`.
+pub const FAST_CALL_TASK_WAKEUP: u64 = 4;
+
+/// Page fault message flag: the faulting page was present.
+pub const PAGE_FAULT_PRESENT: u64 = 0b1;
+/// Page fault message flag: the faulting access was a write.
+pub const PAGE_FAULT_WRITE: u64 = 0b10;
+/// Page fault message flag: the faulting page is a stack guard page,
+/// and the stack starts at the page after it.
+pub const PAGE_FAULT_STACK_OVERFLOW: u64 = 0b100;
+/// Mask of the page-aligned faulting address in a page fault message.
+pub const PAGE_FAULT_ADDRESS_MASK: u64 = !0xfff;
 
-impl SetDefault for TaskBuffer {
-    fn set_default(&mut self) {
-        self.call = None;
-    }
+/// Saved registers of a suspended task.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TaskRegisters {
+    pub rip: u64,
+    pub rsp: u64,
+    pub rflags: u64,
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rbp: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
 }
@@ -0,0 +1,903 @@
+use super::{SystemCall, TaskRegisters, SetDefault, TASK_NAME_LENGTH, CPOOL_SIZE,
+            FAST_CALL_BUFFER, FAST_CALL_CHANNEL_PUT, FAST_CALL_TASK_YIELD,
+            FAST_CALL_TASK_SLEEP, FAST_CALL_TASK_WAKEUP};
+
+/// Version of the system call message format. It is increased
+/// whenever an opcode or an argument layout changes.
+pub const ABI_VERSION: u64 = 5;
+
+/// Number of argument words in a system call message.
+pub const ARGUMENT_COUNT: usize = 20;
+
+/// Number of response words in a system call message.
+pub const RESPONSE_COUNT: usize = 18;
+
+/// Number of bytes in the data area of a task buffer, taking the rest
+/// of its page after the message.
+pub const DATA_LENGTH: usize = 4096 - 8 * (6 + ARGUMENT_COUNT + RESPONSE_COUNT);
+
+/// Number of words taken by `TaskRegisters`.
+const REGISTER_COUNT: usize = 18;
+
+/// System call opcodes. Opcodes are never reused.
+pub mod opcode {
+    pub const CPOOL_LIST_DEBUG: u64 = 1;
+    pub const PRINT: u64 = 2;
+    pub const RETYPE_CPOOL: u64 = 3;
+    pub const CHANNEL_TAKE: u64 = 4;
+    pub const CHANNEL_PUT: u64 = 5;
+    pub const RETYPE_TASK: u64 = 6;
+    pub const TASK_SET_INSTRUCTION_POINTER: u64 = 7;
+    pub const TASK_SET_STACK_POINTER: u64 = 8;
+    pub const TASK_SET_CPOOL: u64 = 9;
+    pub const TASK_SET_TOP_PAGE_TABLE: u64 = 10;
+    pub const TASK_SET_BUFFER: u64 = 11;
+    pub const TASK_SET_ACTIVE: u64 = 12;
+    pub const TASK_SET_INACTIVE: u64 = 13;
+    pub const TASK_SET_PRIORITY: u64 = 14;
+    pub const TASK_SET_AFFINITY: u64 = 15;
+    pub const TASK_SET_TIMESLICE: u64 = 16;
+    pub const TASK_SET_BUDGET: u64 = 17;
+    pub const TASK_CPU_TIME: u64 = 18;
+    pub const RETYPE_SCHED_CONTEXT: u64 = 19;
+    pub const SCHED_CONTEXT_CONFIGURE: u64 = 20;
+    pub const TASK_BIND_SCHED_CONTEXT: u64 = 21;
+    pub const TASK_SET_PASSIVE: u64 = 22;
+    pub const TASK_READ_REGISTERS: u64 = 23;
+    pub const TASK_WRITE_REGISTERS: u64 = 24;
+    pub const TASK_DELETE: u64 = 25;
+    pub const TASK_EXIT: u64 = 26;
+    pub const TASK_SET_EXIT_CHANNEL: u64 = 27;
+    pub const TASK_YIELD: u64 = 28;
+    pub const TASK_SLEEP: u64 = 29;
+    pub const TASK_WAKEUP: u64 = 30;
+    pub const RETYPE_IRQ_HANDLER: u64 = 31;
+    pub const IRQ_HANDLER_SET_CHANNEL: u64 = 32;
+    pub const IRQ_ACK: u64 = 33;
+    pub const RETYPE_TOP_PAGE_TABLE: u64 = 34;
+    pub const TOP_PAGE_TABLE_MAP_COPY_ON_WRITE: u64 = 35;
+    pub const TASK_SET_FAULT_UNTYPED: u64 = 36;
+    pub const TASK_SET_PAGER_CHANNEL: u64 = 37;
+    pub const RETYPE_RAW_PAGE: u64 = 38;
+    pub const RETYPE_TASK_BUFFER: u64 = 39;
+    pub const TOP_PAGE_TABLE_MAP: u64 = 40;
+    pub const TOP_PAGE_TABLE_MAP_GUARD: u64 = 41;
+    pub const TASK_SET_NAME: u64 = 42;
+    pub const KERNEL_LOG_READ: u64 = 43;
+    pub const IRQ_HANDLER_DELETE: u64 = 44;
+}
+
+/// Status of a handled system call.
+pub const STATUS_OK: u64 = 0;
+/// Status of a message with another ABI version.
+pub const STATUS_INVALID_VERSION: u64 = 1;
+/// Status of a message with an unknown opcode.
+pub const STATUS_INVALID_OPCODE: u64 = 2;
+/// Status of a message with an argument out of range.
+pub const STATUS_INVALID_ARGUMENT: u64 = 3;
+/// Status of a message with a wrong response length.
+pub const STATUS_INVALID_RESPONSE: u64 = 4;
+/// Status of a call naming a capability pool slot that does not hold
+/// a capability of the type the call needs.
+pub const STATUS_INVALID_CAPABILITY: u64 = 5;
+/// Status of a call on a capability whose state does not allow it.
+pub const STATUS_INVALID_STATE: u64 = 6;
+
+/// Errors of decoding a system call message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageError {
+    /// The message was written for another ABI version.
+    Version(u64),
+    /// The opcode is unknown.
+    Opcode(u64),
+    /// An argument word, given by its index, is out of range for its
+    /// type.
+    Argument(usize),
+    /// The response length does not match the system call.
+    Response(u64),
+    /// The capability pool slot in an argument word, given by its
+    /// index, does not hold a capability of the type the call needs.
+    Capability(usize),
+    /// The capability in an argument word, given by its index, is in
+    /// a state that does not allow the call.
+    State(usize),
+}
+
+impl MessageError {
+    /// The status word and its value reporting the error.
+    pub fn to_status(&self) -> (u64, u64) {
+        match self {
+            &MessageError::Version(version) => (STATUS_INVALID_VERSION, version),
+            &MessageError::Opcode(opcode) => (STATUS_INVALID_OPCODE, opcode),
+            &MessageError::Argument(index) => (STATUS_INVALID_ARGUMENT, index as u64),
+            &MessageError::Response(length) => (STATUS_INVALID_RESPONSE, length),
+            &MessageError::Capability(index) => (STATUS_INVALID_CAPABILITY, index as u64),
+            &MessageError::State(index) => (STATUS_INVALID_STATE, index as u64),
+        }
+    }
+
+    /// The error reported by a status word and its value, or `None`
+    /// for `STATUS_OK` and unknown statuses.
+    pub fn from_status(status: u64, value: u64) -> Option<MessageError> {
+        match status {
+            STATUS_INVALID_VERSION => Some(MessageError::Version(value)),
+            STATUS_INVALID_OPCODE => Some(MessageError::Opcode(value)),
+            STATUS_INVALID_ARGUMENT => Some(MessageError::Argument(value as usize)),
+            STATUS_INVALID_RESPONSE => Some(MessageError::Response(value)),
+            STATUS_INVALID_CAPABILITY => Some(MessageError::Capability(value as usize)),
+            STATUS_INVALID_STATE => Some(MessageError::State(value as usize)),
+            _ => None,
+        }
+    }
+}
+
+/// Decode a system call made with the `syscall` instruction from its
+/// call number and arguments. `None` is returned for
+/// `FAST_CALL_BUFFER`, whose system call is read from the task buffer.
+/// Slots are checked like in `TaskBuffer::read_call`.
+pub fn read_fast_call(number: u64, arg0: u64, arg1: u64)
+                      -> Result<Option<SystemCall>, MessageError> {
+    let args = [arg0, arg1];
+
+    Ok(Some(match number {
+        FAST_CALL_BUFFER => return Ok(None),
+        FAST_CALL_CHANNEL_PUT => SystemCall::ChannelPut {
+            request: (try!(decode_slot(&args, 0)), args[1]),
+        },
+        FAST_CALL_TASK_YIELD => SystemCall::TaskYield,
+        FAST_CALL_TASK_SLEEP => SystemCall::TaskSleep {
+            request: args[0],
+        },
+        FAST_CALL_TASK_WAKEUP => SystemCall::TaskWakeup {
+            request: try!(decode_slot(&args, 0)),
+        },
+        _ => return Err(MessageError::Opcode(number)),
+    }))
+}
+
+/// A system call message. The layout is fixed and does not depend on
+/// how Rust lays out `SystemCall`: a version, an opcode, argument
+/// words, response words, of which `response_length` are set, and the
+/// status written by the kernel.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct Message {
+    pub version: u64,
+    pub opcode: u64,
+    pub arguments: [u64; ARGUMENT_COUNT],
+    pub response_length: u64,
+    pub response: [u64; RESPONSE_COUNT],
+    pub status: u64,
+    pub status_value: u64,
+    _reserved: u64,
+}
+
+/// Represents a task buffer used for system calls. It takes a whole
+/// page: one system call message, followed by a data area for
+/// system calls passing byte strings.
+#[repr(C)]
+pub struct TaskBuffer {
+    pub message: Message,
+    pub data: [u8; DATA_LENGTH],
+}
+
+impl SetDefault for TaskBuffer {
+    fn set_default(&mut self) {
+        self.message = Message {
+            version: 0,
+            opcode: 0,
+            arguments: [0; ARGUMENT_COUNT],
+            response_length: 0,
+            response: [0; RESPONSE_COUNT],
+            status: STATUS_OK,
+            status_value: 0,
+            _reserved: 0,
+        };
+        for byte in self.data.iter_mut() {
+            *byte = 0;
+        }
+    }
+}
+
+/// A capability pool slot must be within the pool.
+fn decode_slot(words: &[u64], index: usize) -> Result<usize, MessageError> {
+    if words[index] >= CPOOL_SIZE as u64 {
+        Err(MessageError::Argument(index))
+    } else {
+        Ok(words[index] as usize)
+    }
+}
+
+fn decode_u8(words: &[u64], index: usize) -> Result<u8, MessageError> {
+    if words[index] > 0xff {
+        Err(MessageError::Argument(index))
+    } else {
+        Ok(words[index] as u8)
+    }
+}
+
+fn decode_bool(words: &[u64], index: usize) -> Result<bool, MessageError> {
+    match words[index] {
+        0 => Ok(false),
+        1 => Ok(true),
+        _ => Err(MessageError::Argument(index)),
+    }
+}
+
+/// An optional word is encoded as a presence flag followed by the
+/// value.
+fn decode_option(words: &[u64], index: usize) -> Result<Option<u64>, MessageError> {
+    if try!(decode_bool(words, index)) {
+        Ok(Some(words[index + 1]))
+    } else {
+        Ok(None)
+    }
+}
+
+/// The response words of `buffer`, if it has a response, which must
+/// be `length` words long.
+fn decode_response(buffer: &Message, length: usize) -> Result<Option<&[u64]>, MessageError> {
+    match buffer.response_length {
+        0 => Ok(None),
+        response_length if response_length == length as u64 =>
+            Ok(Some(&buffer.response[0..length])),
+        response_length => Err(MessageError::Response(response_length)),
+    }
+}
+
+fn encode_option(words: &mut [u64], index: usize, value: Option<u64>) {
+    words[index] = value.is_some() as u64;
+    words[index + 1] = value.unwrap_or(0);
+}
+
+impl TaskRegisters {
+    fn encode(&self, words: &mut [u64]) {
+        let registers = [self.rip, self.rsp, self.rflags,
+                         self.rax, self.rbx, self.rcx, self.rdx,
+                         self.rbp, self.rsi, self.rdi,
+                         self.r8, self.r9, self.r10, self.r11,
+                         self.r12, self.r13, self.r14, self.r15];
+        words[0..REGISTER_COUNT].copy_from_slice(&registers);
+    }
+
+    fn decode(words: &[u64]) -> TaskRegisters {
+        TaskRegisters {
+            rip: words[0], rsp: words[1], rflags: words[2],
+            rax: words[3], rbx: words[4], rcx: words[5], rdx: words[6],
+            rbp: words[7], rsi: words[8], rdi: words[9],
+            r8: words[10], r9: words[11], r10: words[12], r11: words[13],
+            r12: words[14], r13: words[15], r14: words[16], r15: words[17],
+        }
+    }
+}
+
+impl TaskBuffer {
+    /// Write `call`, including its response if it has one, to the
+    /// buffer. The status is reset to `STATUS_OK`.
+    pub fn write_call(&mut self, call: &SystemCall) {
+        use self::opcode::*;
+
+        let mut args = [0u64; ARGUMENT_COUNT];
+        let mut response = [0u64; RESPONSE_COUNT];
+        let mut response_length = 0;
+
+        let opcode = match call {
+            &SystemCall::CPoolListDebug => CPOOL_LIST_DEBUG,
+            &SystemCall::Print { request: (offset, length) } => {
+                args[0] = offset as u64; args[1] = length as u64;
+                PRINT
+            },
+            &SystemCall::RetypeCPool { request: (source, target) } => {
+                args[0] = source as u64; args[1] = target as u64;
+                RETYPE_CPOOL
+            },
+            &SystemCall::ChannelTake { request, response: value } => {
+                args[0] = request as u64;
+                if let Some(value) = value {
+                    response[0] = value;
+                    response_length = 1;
+                }
+                CHANNEL_TAKE
+            },
+            &SystemCall::ChannelPut { request: (target, value) } => {
+                args[0] = target as u64; args[1] = value;
+                CHANNEL_PUT
+            },
+            &SystemCall::RetypeTask { request: (source, target) } => {
+                args[0] = source as u64; args[1] = target as u64;
+                RETYPE_TASK
+            },
+            &SystemCall::TaskSetInstructionPointer { request: (target, ptr) } => {
+                args[0] = target as u64; args[1] = ptr;
+                TASK_SET_INSTRUCTION_POINTER
+            },
+            &SystemCall::TaskSetStackPointer { request: (target, ptr) } => {
+                args[0] = target as u64; args[1] = ptr;
+                TASK_SET_STACK_POINTER
+            },
+            &SystemCall::TaskSetCPool { request: (target, cpool) } => {
+                args[0] = target as u64; args[1] = cpool as u64;
+                TASK_SET_CPOOL
+            },
+            &SystemCall::TaskSetTopPageTable { request: (target, table) } => {
+                args[0] = target as u64; args[1] = table as u64;
+                TASK_SET_TOP_PAGE_TABLE
+            },
+            &SystemCall::TaskSetBuffer { request: (target, buffer) } => {
+                args[0] = target as u64; args[1] = buffer as u64;
+                TASK_SET_BUFFER
+            },
+            &SystemCall::TaskSetActive { request } => {
+                args[0] = request as u64;
+                TASK_SET_ACTIVE
+            },
+            &SystemCall::TaskSetInactive { request } => {
+                args[0] = request as u64;
+                TASK_SET_INACTIVE
+            },
+            &SystemCall::TaskSetPriority { request: (target, priority) } => {
+                args[0] = target as u64; args[1] = priority as u64;
+                TASK_SET_PRIORITY
+            },
+            &SystemCall::TaskSetAffinity { request: (target, cpu) } => {
+                args[0] = target as u64;
+                encode_option(&mut args, 1, cpu.map(|cpu| cpu as u64));
+                TASK_SET_AFFINITY
+            },
+            &SystemCall::TaskSetTimeslice { request: (target, ticks) } => {
+                args[0] = target as u64; args[1] = ticks;
+                TASK_SET_TIMESLICE
+            },
+            &SystemCall::TaskSetBudget { request: (target, budget, period) } => {
+                args[0] = target as u64; args[1] = budget; args[2] = period;
+                TASK_SET_BUDGET
+            },
+            &SystemCall::TaskCpuTime { request, response: value } => {
+                args[0] = request as u64;
+                if let Some(value) = value {
+                    response[0] = value;
+                    response_length = 1;
+                }
+                TASK_CPU_TIME
+            },
+            &SystemCall::RetypeSchedContext { request: (source, target) } => {
+                args[0] = source as u64; args[1] = target as u64;
+                RETYPE_SCHED_CONTEXT
+            },
+            &SystemCall::SchedContextConfigure { request: (target, priority, budget, period) } => {
+                args[0] = target as u64; args[1] = priority as u64;
+                args[2] = budget; args[3] = period;
+                SCHED_CONTEXT_CONFIGURE
+            },
+            &SystemCall::TaskBindSchedContext { request: (target, sched_context) } => {
+                args[0] = target as u64; args[1] = sched_context as u64;
+                TASK_BIND_SCHED_CONTEXT
+            },
+            &SystemCall::TaskSetPassive { request: (target, passive) } => {
+                args[0] = target as u64; args[1] = passive as u64;
+                TASK_SET_PASSIVE
+            },
+            &SystemCall::TaskReadRegisters { request, response: ref registers } => {
+                args[0] = request as u64;
+                if let &Some(ref registers) = registers {
+                    registers.encode(&mut response);
+                    response_length = REGISTER_COUNT as u64;
+                }
+                TASK_READ_REGISTERS
+            },
+            &SystemCall::TaskWriteRegisters { request: (target, ref registers) } => {
+                args[0] = target as u64;
+                registers.encode(&mut args[1..]);
+                TASK_WRITE_REGISTERS
+            },
+            &SystemCall::TaskDelete { request } => {
+                args[0] = request as u64;
+                TASK_DELETE
+            },
+            &SystemCall::TaskExit { request } => {
+                args[0] = request;
+                TASK_EXIT
+            },
+            &SystemCall::TaskSetExitChannel { request: (target, channel) } => {
+                args[0] = target as u64; args[1] = channel as u64;
+                TASK_SET_EXIT_CHANNEL
+            },
+            &SystemCall::TaskYield => TASK_YIELD,
+            &SystemCall::TaskSleep { request } => {
+                args[0] = request;
+                TASK_SLEEP
+            },
+            &SystemCall::TaskWakeup { request } => {
+                args[0] = request as u64;
+                TASK_WAKEUP
+            },
+            &SystemCall::RetypeIRQHandler { request: (source, irq, target) } => {
+                args[0] = source as u64; args[1] = irq as u64; args[2] = target as u64;
+                RETYPE_IRQ_HANDLER
+            },
+            &SystemCall::IRQHandlerSetChannel { request: (target, channel) } => {
+                args[0] = target as u64; args[1] = channel as u64;
+                IRQ_HANDLER_SET_CHANNEL
+            },
+            &SystemCall::IRQAck { request } => {
+                args[0] = request as u64;
+                IRQ_ACK
+            },
+            &SystemCall::IRQHandlerDelete { request } => {
+                args[0] = request as u64;
+                IRQ_HANDLER_DELETE
+            },
+            &SystemCall::RetypeTopPageTable { request: (source, target) } => {
+                args[0] = source as u64; args[1] = target as u64;
+                RETYPE_TOP_PAGE_TABLE
+            },
+            &SystemCall::TopPageTableMapCopyOnWrite { request: (source, target, vaddr, untyped) } => {
+                args[0] = source as u64; args[1] = target as u64;
+                args[2] = vaddr; args[3] = untyped as u64;
+                TOP_PAGE_TABLE_MAP_COPY_ON_WRITE
+            },
+            &SystemCall::TaskSetFaultUntyped { request: (target, untyped) } => {
+                args[0] = target as u64; args[1] = untyped as u64;
+                TASK_SET_FAULT_UNTYPED
+            },
+            &SystemCall::TaskSetPagerChannel { request: (target, channel) } => {
+                args[0] = target as u64; args[1] = channel as u64;
+                TASK_SET_PAGER_CHANNEL
+            },
+            &SystemCall::RetypeRawPage { request: (source, target) } => {
+                args[0] = source as u64; args[1] = target as u64;
+                RETYPE_RAW_PAGE
+            },
+            &SystemCall::RetypeTaskBuffer { request: (source, target) } => {
+                args[0] = source as u64; args[1] = target as u64;
+                RETYPE_TASK_BUFFER
+            },
+            &SystemCall::TopPageTableMap { request: (table, vaddr, page, untyped) } => {
+                args[0] = table as u64; args[1] = vaddr;
+                args[2] = page as u64; args[3] = untyped as u64;
+                TOP_PAGE_TABLE_MAP
+            },
+            &SystemCall::TopPageTableMapGuard { request: (table, vaddr, pages, untyped) } => {
+                args[0] = table as u64; args[1] = vaddr;
+                args[2] = pages as u64; args[3] = untyped as u64;
+                TOP_PAGE_TABLE_MAP_GUARD
+            },
+            &SystemCall::TaskSetName { request: (target, offset, length) } => {
+                args[0] = target as u64; args[1] = offset as u64; args[2] = length as u64;
+                TASK_SET_NAME
+            },
+            &SystemCall::KernelLogRead { request: (log, position, offset, length), response: read } => {
+                args[0] = log as u64; args[1] = position;
+                args[2] = offset as u64; args[3] = length as u64;
+                if let Some((position, length)) = read {
+                    response[0] = position; response[1] = length as u64;
+                    response_length = 2;
+                }
+                KERNEL_LOG_READ
+            },
+        };
+
+        self.message.version = ABI_VERSION;
+        self.message.opcode = opcode;
+        self.message.arguments = args;
+        self.message.response_length = response_length;
+        self.message.response = response;
+        self.message.status = STATUS_OK;
+        self.message.status_value = 0;
+    }
+
+    /// Report a malformed message, or a call that cannot be handled,
+    /// back to the task. The rest of the
+    /// buffer is left as the task wrote it.
+    pub fn set_error(&mut self, error: MessageError) {
+        let (status, value) = error.to_status();
+        self.message.status = status;
+        self.message.status_value = value;
+    }
+
+    /// The status of the last system call made with the buffer.
+    pub fn status(&self) -> Result<(), MessageError> {
+        match MessageError::from_status(self.message.status, self.message.status_value) {
+            Some(error) => Err(error),
+            None => Ok(()),
+        }
+    }
+
+    /// Read the system call in the buffer. The message is copied
+    /// first, so that it cannot change while it is decoded, and every
+    /// word is checked against the type it is decoded to. Capability
+    /// pool slots must lie in the pool, and byte ranges in the data
+    /// area.
+    pub fn read_call(&self) -> Result<SystemCall, MessageError> {
+        use self::opcode::*;
+
+        let buffer = self.message;
+        let args = &buffer.arguments;
+
+        if buffer.version != ABI_VERSION {
+            return Err(MessageError::Version(buffer.version));
+        }
+
+        Ok(match buffer.opcode {
+            CPOOL_LIST_DEBUG => SystemCall::CPoolListDebug,
+            PRINT => {
+                if args[0] > DATA_LENGTH as u64 {
+                    return Err(MessageError::Argument(0));
+                }
+                if args[1] > DATA_LENGTH as u64 - args[0] {
+                    return Err(MessageError::Argument(1));
+                }
+                SystemCall::Print { request: (args[0] as usize, args[1] as usize) }
+            },
+            RETYPE_CPOOL => SystemCall::RetypeCPool {
+                request: (try!(decode_slot(args, 0)), try!(decode_slot(args, 1))),
+            },
+            CHANNEL_TAKE => SystemCall::ChannelTake {
+                request: try!(decode_slot(args, 0)),
+                response: try!(decode_response(&buffer, 1)).map(|words| words[0]),
+            },
+            CHANNEL_PUT => SystemCall::ChannelPut {
+                request: (try!(decode_slot(args, 0)), args[1]),
+            },
+            RETYPE_TASK => SystemCall::RetypeTask {
+                request: (try!(decode_slot(args, 0)), try!(decode_slot(args, 1))),
+            },
+            TASK_SET_INSTRUCTION_POINTER => SystemCall::TaskSetInstructionPointer {
+                request: (try!(decode_slot(args, 0)), args[1]),
+            },
+            TASK_SET_STACK_POINTER => SystemCall::TaskSetStackPointer {
+                request: (try!(decode_slot(args, 0)), args[1]),
+            },
+            TASK_SET_CPOOL => SystemCall::TaskSetCPool {
+                request: (try!(decode_slot(args, 0)), try!(decode_slot(args, 1))),
+            },
+            TASK_SET_TOP_PAGE_TABLE => SystemCall::TaskSetTopPageTable {
+                request: (try!(decode_slot(args, 0)), try!(decode_slot(args, 1))),
+            },
+            TASK_SET_BUFFER => SystemCall::TaskSetBuffer {
+                request: (try!(decode_slot(args, 0)), try!(decode_slot(args, 1))),
+            },
+            TASK_SET_ACTIVE => SystemCall::TaskSetActive {
+                request: try!(decode_slot(args, 0)),
+            },
+            TASK_SET_INACTIVE => SystemCall::TaskSetInactive {
+                request: try!(decode_slot(args, 0)),
+            },
+            TASK_SET_PRIORITY => SystemCall::TaskSetPriority {
+                request: (try!(decode_slot(args, 0)), try!(decode_u8(args, 1))),
+            },
+            TASK_SET_AFFINITY => SystemCall::TaskSetAffinity {
+                request: (try!(decode_slot(args, 0)),
+                          try!(decode_option(args, 1)).map(|cpu| cpu as usize)),
+            },
+            TASK_SET_TIMESLICE => SystemCall::TaskSetTimeslice {
+                request: (try!(decode_slot(args, 0)), args[1]),
+            },
+            TASK_SET_BUDGET => SystemCall::TaskSetBudget {
+                request: (try!(decode_slot(args, 0)), args[1], args[2]),
+            },
+            TASK_CPU_TIME => SystemCall::TaskCpuTime {
+                request: try!(decode_slot(args, 0)),
+                response: try!(decode_response(&buffer, 1)).map(|words| words[0]),
+            },
+            RETYPE_SCHED_CONTEXT => SystemCall::RetypeSchedContext {
+                request: (try!(decode_slot(args, 0)), try!(decode_slot(args, 1))),
+            },
+            SCHED_CONTEXT_CONFIGURE => SystemCall::SchedContextConfigure {
+                request: (try!(decode_slot(args, 0)), try!(decode_u8(args, 1)), args[2], args[3]),
+            },
+            TASK_BIND_SCHED_CONTEXT => SystemCall::TaskBindSchedContext {
+                request: (try!(decode_slot(args, 0)), try!(decode_slot(args, 1))),
+            },
+            TASK_SET_PASSIVE => SystemCall::TaskSetPassive {
+                request: (try!(decode_slot(args, 0)), try!(decode_bool(args, 1))),
+            },
+            TASK_READ_REGISTERS => SystemCall::TaskReadRegisters {
+                request: try!(decode_slot(args, 0)),
+                response: try!(decode_response(&buffer, REGISTER_COUNT)).map(TaskRegisters::decode),
+            },
+            TASK_WRITE_REGISTERS => SystemCall::TaskWriteRegisters {
+                request: (try!(decode_slot(args, 0)), TaskRegisters::decode(&args[1..])),
+            },
+            TASK_DELETE => SystemCall::TaskDelete {
+                request: try!(decode_slot(args, 0)),
+            },
+            TASK_EXIT => SystemCall::TaskExit {
+                request: args[0],
+            },
+            TASK_SET_EXIT_CHANNEL => SystemCall::TaskSetExitChannel {
+                request: (try!(decode_slot(args, 0)), try!(decode_slot(args, 1))),
+            },
+            TASK_YIELD => SystemCall::TaskYield,
+            TASK_SLEEP => SystemCall::TaskSleep {
+                request: args[0],
+            },
+            TASK_WAKEUP => SystemCall::TaskWakeup {
+                request: try!(decode_slot(args, 0)),
+            },
+            RETYPE_IRQ_HANDLER => SystemCall::RetypeIRQHandler {
+                request: (try!(decode_slot(args, 0)), try!(decode_u8(args, 1)),
+                          try!(decode_slot(args, 2))),
+            },
+            IRQ_HANDLER_SET_CHANNEL => SystemCall::IRQHandlerSetChannel {
+                request: (try!(decode_slot(args, 0)), try!(decode_slot(args, 1))),
+            },
+            IRQ_ACK => SystemCall::IRQAck {
+                request: try!(decode_slot(args, 0)),
+            },
+            IRQ_HANDLER_DELETE => SystemCall::IRQHandlerDelete {
+                request: try!(decode_slot(args, 0)),
+            },
+            RETYPE_TOP_PAGE_TABLE => SystemCall::RetypeTopPageTable {
+                request: (try!(decode_slot(args, 0)), try!(decode_slot(args, 1))),
+            },
+            TOP_PAGE_TABLE_MAP_COPY_ON_WRITE => SystemCall::TopPageTableMapCopyOnWrite {
+                request: (try!(decode_slot(args, 0)), try!(decode_slot(args, 1)),
+                          args[2], try!(decode_slot(args, 3))),
+            },
+            TASK_SET_FAULT_UNTYPED => SystemCall::TaskSetFaultUntyped {
+                request: (try!(decode_slot(args, 0)), try!(decode_slot(args, 1))),
+            },
+            TASK_SET_PAGER_CHANNEL => SystemCall::TaskSetPagerChannel {
+                request: (try!(decode_slot(args, 0)), try!(decode_slot(args, 1))),
+            },
+            RETYPE_RAW_PAGE => SystemCall::RetypeRawPage {
+                request: (try!(decode_slot(args, 0)), try!(decode_slot(args, 1))),
+            },
+            RETYPE_TASK_BUFFER => SystemCall::RetypeTaskBuffer {
+                request: (try!(decode_slot(args, 0)), try!(decode_slot(args, 1))),
+            },
+            TOP_PAGE_TABLE_MAP => SystemCall::TopPageTableMap {
+                request: (try!(decode_slot(args, 0)), args[1],
+                          try!(decode_slot(args, 2)), try!(decode_slot(args, 3))),
+            },
+            TOP_PAGE_TABLE_MAP_GUARD => SystemCall::TopPageTableMapGuard {
+                request: (try!(decode_slot(args, 0)), args[1],
+                          args[2] as usize, try!(decode_slot(args, 3))),
+            },
+            TASK_SET_NAME => {
+                if args[1] > DATA_LENGTH as u64 {
+                    return Err(MessageError::Argument(1));
+                }
+                if args[2] > DATA_LENGTH as u64 - args[1] || args[2] > TASK_NAME_LENGTH as u64 {
+                    return Err(MessageError::Argument(2));
+                }
+                SystemCall::TaskSetName {
+                    request: (try!(decode_slot(args, 0)), args[1] as usize, args[2] as usize),
+                }
+            },
+            KERNEL_LOG_READ => {
+                if args[2] > DATA_LENGTH as u64 {
+                    return Err(MessageError::Argument(2));
+                }
+                if args[3] > DATA_LENGTH as u64 - args[2] {
+                    return Err(MessageError::Argument(3));
+                }
+                SystemCall::KernelLogRead {
+                    request: (try!(decode_slot(args, 0)), args[1], args[2] as usize, args[3] as usize),
+                    response: try!(decode_response(&buffer, 2)).map(|words| (words[0], words[1] as usize)),
+                }
+            },
+            opcode => return Err(MessageError::Opcode(opcode)),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::{SystemCall, TaskRegisters, SetDefault, TASK_NAME_LENGTH, CPOOL_SIZE,
+                       FAST_CALL_BUFFER, FAST_CALL_CHANNEL_PUT, FAST_CALL_TASK_YIELD,
+                       FAST_CALL_TASK_SLEEP, FAST_CALL_TASK_WAKEUP};
+
+    fn buffer() -> Box<TaskBuffer> {
+        let mut buffer = Box::new(TaskBuffer {
+            message: Message {
+                version: 0, opcode: 0,
+                arguments: [0; ARGUMENT_COUNT],
+                response_length: 0, response: [0; RESPONSE_COUNT],
+                status: 0, status_value: 0, _reserved: 0,
+            },
+            data: [0; DATA_LENGTH],
+        });
+        buffer.set_default();
+        buffer
+    }
+
+    fn registers() -> TaskRegisters {
+        TaskRegisters {
+            rip: 1, rsp: 2, rflags: 3, rax: 4, rbx: 5, rcx: 6, rdx: 7,
+            rbp: 8, rsi: 9, rdi: 10, r8: 11, r9: 12, r10: 13, r11: 14,
+            r12: 15, r13: 16, r14: 17, r15: 18,
+        }
+    }
+
+    /// One call of every opcode, with responses where the call has
+    /// one.
+    fn calls() -> Vec<SystemCall> {
+        vec![
+            SystemCall::CPoolListDebug,
+            SystemCall::Print { request: (8, 16) },
+            SystemCall::RetypeCPool { request: (2, 3) },
+            SystemCall::ChannelTake { request: 4, response: None },
+            SystemCall::ChannelTake { request: 4, response: Some(0xdeadbeef) },
+            SystemCall::ChannelPut { request: (5, u64::max_value()) },
+            SystemCall::RetypeTask { request: (2, 6) },
+            SystemCall::TaskSetInstructionPointer { request: (6, 0x400000) },
+            SystemCall::TaskSetStackPointer { request: (6, 0x700000) },
+            SystemCall::TaskSetCPool { request: (6, 0) },
+            SystemCall::TaskSetTopPageTable { request: (6, 3) },
+            SystemCall::TaskSetBuffer { request: (6, 7) },
+            SystemCall::TaskSetActive { request: 6 },
+            SystemCall::TaskSetInactive { request: 6 },
+            SystemCall::TaskSetPriority { request: (6, 255) },
+            SystemCall::TaskSetAffinity { request: (6, None) },
+            SystemCall::TaskSetAffinity { request: (6, Some(3)) },
+            SystemCall::TaskSetTimeslice { request: (6, 10) },
+            SystemCall::TaskSetBudget { request: (6, 5, 20) },
+            SystemCall::TaskCpuTime { request: 6, response: Some(1234) },
+            SystemCall::RetypeSchedContext { request: (2, 8) },
+            SystemCall::SchedContextConfigure { request: (8, 3, 5, 20) },
+            SystemCall::TaskBindSchedContext { request: (6, 8) },
+            SystemCall::TaskSetPassive { request: (6, true) },
+            SystemCall::TaskReadRegisters { request: 6, response: None },
+            SystemCall::TaskReadRegisters { request: 6, response: Some(registers()) },
+            SystemCall::TaskWriteRegisters { request: (6, registers()) },
+            SystemCall::TaskDelete { request: 6 },
+            SystemCall::TaskExit { request: 42 },
+            SystemCall::TaskSetExitChannel { request: (6, 5) },
+            SystemCall::TaskYield,
+            SystemCall::TaskSleep { request: 100 },
+            SystemCall::TaskWakeup { request: 6 },
+            SystemCall::RetypeIRQHandler { request: (2, 33, 9) },
+            SystemCall::IRQHandlerSetChannel { request: (9, 5) },
+            SystemCall::IRQAck { request: 9 },
+            SystemCall::IRQHandlerDelete { request: 9 },
+            SystemCall::RetypeTopPageTable { request: (2, 10) },
+            SystemCall::TopPageTableMapCopyOnWrite { request: (3, 10, 0x400000, 2) },
+            SystemCall::TaskSetFaultUntyped { request: (6, 2) },
+            SystemCall::TaskSetPagerChannel { request: (6, 5) },
+            SystemCall::RetypeRawPage { request: (2, 11) },
+            SystemCall::RetypeTaskBuffer { request: (2, 7) },
+            SystemCall::TopPageTableMap { request: (10, 0x800000, 11, 2) },
+            SystemCall::TopPageTableMapGuard { request: (10, 0x900000, 0x4000, 2) },
+            SystemCall::TaskSetName { request: (6, 0, TASK_NAME_LENGTH) },
+            SystemCall::KernelLogRead { request: (12, 77, 0, DATA_LENGTH), response: None },
+            SystemCall::KernelLogRead { request: (12, 77, 0, DATA_LENGTH), response: Some((99, 22)) },
+        ]
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut opcodes = Vec::new();
+        for call in calls() {
+            let mut buffer = buffer();
+            buffer.write_call(&call);
+            assert_eq!(buffer.message.version, ABI_VERSION);
+            assert_eq!(buffer.status(), Ok(()));
+            let read = buffer.read_call().unwrap();
+            assert_eq!(format!("{:?}", read), format!("{:?}", call));
+            opcodes.push(buffer.message.opcode);
+        }
+
+        // Every opcode is covered, and no two calls share an opcode.
+        opcodes.sort();
+        opcodes.dedup();
+        assert_eq!(opcodes, (1..opcodes.len() as u64 + 1).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn version_rejected() {
+        let mut buffer = buffer();
+        buffer.write_call(&SystemCall::TaskYield);
+        buffer.message.version = ABI_VERSION + 1;
+        assert_eq!(buffer.read_call().unwrap_err(), MessageError::Version(ABI_VERSION + 1));
+        buffer.message.version = 0;
+        assert_eq!(buffer.read_call().unwrap_err(), MessageError::Version(0));
+    }
+
+    #[test]
+    fn opcode_rejected() {
+        let mut buffer = buffer();
+        buffer.write_call(&SystemCall::TaskYield);
+        for &opcode in [0, 1000, u64::max_value()].iter() {
+            buffer.message.opcode = opcode;
+            assert_eq!(buffer.read_call().unwrap_err(), MessageError::Opcode(opcode));
+        }
+    }
+
+    #[test]
+    fn slot_range_rejected() {
+        let mut buffer = buffer();
+        buffer.write_call(&SystemCall::RetypeCPool { request: (2, CPOOL_SIZE - 1) });
+        assert!(buffer.read_call().is_ok());
+        buffer.message.arguments[1] = CPOOL_SIZE as u64;
+        assert_eq!(buffer.read_call().unwrap_err(), MessageError::Argument(1));
+        buffer.message.arguments[0] = u64::max_value();
+        assert_eq!(buffer.read_call().unwrap_err(), MessageError::Argument(0));
+
+        buffer.write_call(&SystemCall::TopPageTableMap { request: (10, 0x800000, 11, 2) });
+        buffer.message.arguments[3] = CPOOL_SIZE as u64;
+        assert_eq!(buffer.read_call().unwrap_err(), MessageError::Argument(3));
+    }
+
+    #[test]
+    fn word_range_rejected() {
+        let mut buffer = buffer();
+        buffer.write_call(&SystemCall::TaskSetPriority { request: (6, 1) });
+        buffer.message.arguments[1] = 256;
+        assert_eq!(buffer.read_call().unwrap_err(), MessageError::Argument(1));
+
+        buffer.write_call(&SystemCall::TaskSetPassive { request: (6, false) });
+        buffer.message.arguments[1] = 2;
+        assert_eq!(buffer.read_call().unwrap_err(), MessageError::Argument(1));
+
+        buffer.write_call(&SystemCall::TaskSetAffinity { request: (6, None) });
+        buffer.message.arguments[1] = 2;
+        assert_eq!(buffer.read_call().unwrap_err(), MessageError::Argument(1));
+    }
+
+    #[test]
+    fn data_range_rejected() {
+        let mut buffer = buffer();
+        buffer.write_call(&SystemCall::Print { request: (DATA_LENGTH + 1, 0) });
+        assert_eq!(buffer.read_call().unwrap_err(), MessageError::Argument(0));
+        buffer.write_call(&SystemCall::Print { request: (1, DATA_LENGTH) });
+        assert_eq!(buffer.read_call().unwrap_err(), MessageError::Argument(1));
+        buffer.write_call(&SystemCall::Print { request: (1, usize::max_value()) });
+        assert_eq!(buffer.read_call().unwrap_err(), MessageError::Argument(1));
+
+        buffer.write_call(&SystemCall::TaskSetName { request: (6, 0, TASK_NAME_LENGTH + 1) });
+        assert_eq!(buffer.read_call().unwrap_err(), MessageError::Argument(2));
+
+        buffer.write_call(&SystemCall::KernelLogRead {
+            request: (12, 0, DATA_LENGTH, 1), response: None,
+        });
+        assert_eq!(buffer.read_call().unwrap_err(), MessageError::Argument(3));
+    }
+
+    #[test]
+    fn response_length_rejected() {
+        let mut buffer = buffer();
+        buffer.write_call(&SystemCall::ChannelTake { request: 4, response: Some(1) });
+        buffer.message.response_length = 2;
+        assert_eq!(buffer.read_call().unwrap_err(), MessageError::Response(2));
+    }
+
+    #[test]
+    fn error_status() {
+        let mut buffer = buffer();
+        buffer.write_call(&SystemCall::TaskYield);
+        buffer.set_error(MessageError::Capability(3));
+        assert_eq!(buffer.status(), Err(MessageError::Capability(3)));
+        buffer.write_call(&SystemCall::TaskYield);
+        assert_eq!(buffer.status(), Ok(()));
+
+        for &error in [MessageError::Version(7), MessageError::Opcode(8),
+                       MessageError::Argument(1), MessageError::Response(3),
+                       MessageError::Capability(2), MessageError::State(0)].iter() {
+            let (status, value) = error.to_status();
+            assert!(status != STATUS_OK);
+            assert_eq!(MessageError::from_status(status, value), Some(error));
+        }
+    }
+
+    #[test]
+    fn fast_calls() {
+        assert!(read_fast_call(FAST_CALL_BUFFER, 0, 0).unwrap().is_none());
+        assert_eq!(format!("{:?}", read_fast_call(FAST_CALL_CHANNEL_PUT, 5, 9).unwrap()),
+                   format!("{:?}", Some(SystemCall::ChannelPut { request: (5, 9) })));
+        assert_eq!(format!("{:?}", read_fast_call(FAST_CALL_TASK_YIELD, 0, 0).unwrap()),
+                   format!("{:?}", Some(SystemCall::TaskYield)));
+        assert_eq!(format!("{:?}", read_fast_call(FAST_CALL_TASK_SLEEP, 3, 0).unwrap()),
+                   format!("{:?}", Some(SystemCall::TaskSleep { request: 3 })));
+        assert_eq!(format!("{:?}", read_fast_call(FAST_CALL_TASK_WAKEUP, 6, 0).unwrap()),
+                   format!("{:?}", Some(SystemCall::TaskWakeup { request: 6 })));
+
+        assert_eq!(read_fast_call(FAST_CALL_CHANNEL_PUT, CPOOL_SIZE as u64, 0).unwrap_err(),
+                   MessageError::Argument(0));
+        assert_eq!(read_fast_call(FAST_CALL_TASK_WAKEUP, u64::max_value(), 0).unwrap_err(),
+                   MessageError::Argument(0));
+        assert_eq!(read_fast_call(99, 0, 0).unwrap_err(), MessageError::Opcode(99));
+    }
+}
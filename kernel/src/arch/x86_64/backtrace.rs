@@ -0,0 +1,62 @@
+use core::{slice, str};
+use super::KERNEL_BASE;
+
+/// Maximum number of frames walked by `backtrace`.
+const BACKTRACE_DEPTH: usize = 32;
+
+extern {
+    /// Start of the kernel symbol table, generated from the kernel ELF
+    /// at link time.
+    static kernel_symbols_start: u8;
+    /// End of the kernel symbol table.
+    static kernel_symbols_end: u8;
+}
+
+/// Walk the frame pointer chain of the caller, calling `f` with each
+/// return address. Frame pointers are never eliminated in the kernel
+/// (see the target specification). The walk stops at a frame pointer
+/// outside of the kernel, or after `BACKTRACE_DEPTH` frames.
+#[inline(never)]
+pub fn backtrace<F: FnMut(u64)>(mut f: F) {
+    let mut rbp: u64;
+    unsafe { asm!("mov $0, rbp" : "=r"(rbp) ::: "intel") };
+
+    for _ in 0..BACKTRACE_DEPTH {
+        if rbp < KERNEL_BASE || rbp % 8 != 0 {
+            break;
+        }
+
+        let return_address = unsafe { *((rbp + 8) as *const u64) };
+        if return_address == 0 {
+            break;
+        }
+        f(return_address);
+
+        rbp = unsafe { *(rbp as *const u64) };
+    }
+}
+
+/// Find the kernel function containing `addr`. Returns its name and
+/// the offset of `addr` from its start.
+///
+/// The table is a list of entries sorted by address, each an address
+/// word, a length word and the name, padded to eight bytes.
+pub fn symbol(addr: u64) -> Option<(&'static str, u64)> {
+    let end = unsafe { &kernel_symbols_end as *const u8 as usize };
+    let mut entry = unsafe { &kernel_symbols_start as *const u8 as usize };
+    let mut found = None;
+
+    while entry < end {
+        let (start, length) = unsafe { (*(entry as *const u64), *((entry + 8) as *const u64) as usize) };
+        if start > addr {
+            break;
+        }
+
+        let name = unsafe { str::from_utf8_unchecked(slice::from_raw_parts((entry + 16) as *const u8,
+                                                                           length)) };
+        found = Some((name, addr - start));
+        entry = (entry + 16 + length + 7) & !7;
+    }
+
+    found
+}
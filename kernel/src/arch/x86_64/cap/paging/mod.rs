@@ -3,14 +3,17 @@ mod pml4;
 
 use common::*;
 use arch::paging::{BASE_PAGE_LENGTH,
-                   PT, PTEntry, PT_P, PT_RW, PT_US,
+                   PT, PTEntry, PT_P, PT_RW, PT_US, PT_GUARD, PT_RAW,
                    PD, PDEntry, PD_P, PD_RW, PD_US,
                    PDPT, PDPTEntry, PDPT_P, PDPT_RW, PDPT_US};
+use arch::paging::pcid::{PCID};
+use arch::smp::{MAX_CPU_COUNT};
+use arch::{USER_END};
 use util::{MemoryObject, UniqueReadGuard, UniqueWriteGuard, RwLock};
 use util::managed_arc::{ManagedArc, ManagedArcAny, ManagedWeakPool1Arc};
 use core::marker::{PhantomData};
-use core::any::{Any};
-use cap::{UntypedDescriptor, SetDefault};
+use core::any::{Any, TypeId};
+use cap::{UntypedDescriptor, SetDefault, RawPage};
 
 /// Page length used in current kernel. This is `BASE_PAGE_LENGTH` in x86_64.
 pub const PAGE_LENGTH: usize = BASE_PAGE_LENGTH;
@@ -18,10 +21,14 @@ pub const PAGE_LENGTH: usize = BASE_PAGE_LENGTH;
 /// PML4 page table descriptor.
 pub struct PML4Descriptor {
     start_paddr: PAddr,
+    pcid: Option<PCID>,
+    stale: [bool; MAX_CPU_COUNT],
     next: Option<ManagedArcAny>,
 }
 
-/// PML4 page table capability.
+/// PML4 page table capability. This represents an address space. If
+/// the processor supports it, each address space gets its own PCID,
+/// so that its TLB entries are kept across address space switches.
 pub type PML4Cap = ManagedArc<RwLock<PML4Descriptor>>;
 
 
@@ -181,7 +188,58 @@ impl PTCap {
         assert!(!current[index].is_present());
 
         sub_desc.mapped_weak_pool.read().downgrade_at(self, 0);
-        current[index] = PTEntry::new(sub_desc.start_paddr(), PT_P | PT_RW | PT_US);
+        current[index] = PTEntry::new(sub_desc.start_paddr(), page_flags::<T>());
+    }
+
+    /// Like `map_page`, but replace the entry already present at
+    /// `index`. Used when a copy-on-write page is copied.
+    pub fn remap_page<T: SetDefault + Any>(&mut self, index: usize, sub: &PageCap<T>) {
+        let mut current_desc = self.write();
+        let mut current = current_desc.write();
+        let sub_desc = sub.read();
+        assert!(current[index].is_present());
+
+        sub_desc.mapped_weak_pool.read().downgrade_at(self, 0);
+        current[index] = PTEntry::new(sub_desc.start_paddr(), page_flags::<T>());
+    }
+
+    /// Reserve a guard page at `index`, below a stack of
+    /// `stack_length` bytes. The entry is left non-present, with the
+    /// length in its address field, so the length must be whole pages
+    /// below the end of the user address space.
+    pub fn map_guard(&mut self, index: usize, stack_length: usize) {
+        let mut current_desc = self.write();
+        let mut current = current_desc.write();
+        assert!(!current[index].is_present());
+        assert!(stack_length % BASE_PAGE_LENGTH == 0 && (stack_length as u64) < USER_END);
+
+        current[index] = PTEntry::new(PAddr::from(stack_length), PT_GUARD);
+    }
+
+    /// Map a raw entry at `index`. The page in the entry is owned by
+    /// another page table, and is only shared into this one. Returns
+    /// `false`, leaving the PT unchanged, if a page or a guard page is
+    /// already at `index`.
+    pub fn map_entry(&mut self, index: usize, entry: PTEntry) -> bool {
+        let mut current_desc = self.write();
+        let mut current = current_desc.write();
+        if current[index].is_present() || current[index].is_guard() {
+            return false;
+        }
+
+        current[index] = entry;
+        true
+    }
+}
+
+/// Flags of a PT entry mapping a page of type `T`. Only `RawPage`
+/// entries are marked `PT_RAW`, so that task buffers and other
+/// kernel-interpreted pages are never shared copy-on-write.
+fn page_flags<T: Any>() -> PTEntry {
+    if TypeId::of::<T>() == TypeId::of::<RawPage>() {
+        PT_P | PT_RW | PT_US | PT_RAW
+    } else {
+        PT_P | PT_RW | PT_US
     }
 }
 
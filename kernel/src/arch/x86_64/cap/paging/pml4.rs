@@ -1,11 +1,14 @@
 use common::*;
-use arch::{KERNEL_BASE};
+use arch::{KERNEL_BASE, is_user_vaddr};
 use arch::init::{KERNEL_PDPT};
-use arch::paging::{BASE_PAGE_LENGTH, PML4, PML4Entry, pml4_index};
+use arch::paging::{BASE_PAGE_LENGTH, PML4, PML4Entry, PTEntry, pml4_index};
 use util::{MemoryObject, UniqueReadGuard, UniqueWriteGuard, RwLock};
 use util::managed_arc::{ManagedWeakPool1Arc};
+use arch::paging::pcid::{PCID};
+use arch::smp::{self, cpu_index, MAX_CPU_COUNT};
 use super::{PML4Descriptor, PML4Cap, PDPTCap, PDCap, PTCap, PageCap};
-use cap::{self, UntypedCap, UntypedDescriptor, CPoolDescriptor, CPoolCap, SetDefault};
+use cap::{self, UntypedCap, UntypedDescriptor, CPoolDescriptor, CPoolCap, SetDefault,
+          RawPage, RawPageCap};
 use core::ops::{Deref, DerefMut};
 use core::any::{Any};
 
@@ -16,11 +19,15 @@ impl PML4Cap {
         let start_paddr = unsafe { untyped.allocate(BASE_PAGE_LENGTH, BASE_PAGE_LENGTH) };
 
         unsafe {
-            use arch::paging::{PML4_P, PML4_RW};
+            use arch::paging::{pcid, PML4_P, PML4_RW};
 
             untyped.derive(Self::inner_length(), Self::inner_alignment(), |paddr, next_child| {
                 let mut desc = PML4Descriptor {
                     start_paddr: start_paddr,
+                    pcid: pcid::allocate(),
+                    // The PCID may be a reused one, with TLB entries
+                    // left on any processor.
+                    stale: [true; MAX_CPU_COUNT],
                     next: next_child,
                 };
 
@@ -55,9 +62,103 @@ impl PML4Cap {
         current[index] = PML4Entry::new(sub_desc.start_paddr(), PML4_P | PML4_RW | PML4_US);
     }
 
+    /// Map `page` at `vaddr`, which must be in the user address
+    /// space.
     pub fn map<T: SetDefault + Any>(&mut self, vaddr: VAddr, page: &PageCap<T>,
-                                    untyped: &mut UntypedDescriptor, cpool: &mut CPoolDescriptor) {
-        use arch::paging::{pml4_index, pdpt_index, pd_index, pt_index,
+                                    untyped: &mut UntypedDescriptor, cpool: &CPoolDescriptor) {
+        use arch::paging::{pt_index};
+
+        assert!(is_user_vaddr(vaddr));
+        let mut pt_cap = self.ensure_pt(vaddr, untyped, cpool);
+        pt_cap.map_page(pt_index(vaddr), page);
+    }
+
+    /// Reserve the page at `vaddr` as a guard page, below a stack of
+    /// `stack_length` bytes starting at the next page. Faults on the
+    /// guard page are reported as stack overflows. `vaddr` must be in
+    /// the user address space.
+    pub fn map_guard(&mut self, vaddr: VAddr, stack_length: usize,
+                     untyped: &mut UntypedDescriptor, cpool: &CPoolDescriptor) {
+        use arch::paging::{pt_index};
+
+        assert!(is_user_vaddr(vaddr));
+        let mut pt_cap = self.ensure_pt(vaddr, untyped, cpool);
+        pt_cap.map_guard(pt_index(vaddr), stack_length);
+    }
+
+    /// Share the page mapped at `vaddr` with `target`, at the same
+    /// virtual address. A writable page is made read-only and marked
+    /// copy-on-write in both page tables. Returns `false` if nothing
+    /// is mapped at `vaddr` in this page table, if the page there is
+    /// not a `RawPage` (e.g. a task buffer), or if something is
+    /// already mapped there in `target`.
+    pub fn map_copy_on_write(&mut self, target: &mut PML4Cap, vaddr: VAddr,
+                             untyped: &mut UntypedDescriptor, cpool: &CPoolDescriptor) -> bool {
+        use arch::paging::{pt_index, PT_RW, PT_COW};
+
+        let entry = match self.read().entry(vaddr) {
+            Some(entry) if entry.is_raw() => entry,
+            _ => return false,
+        };
+
+        if target.read().entry(vaddr).is_some() || target.read().guarded_stack(vaddr).is_some() {
+            return false;
+        }
+
+        let shared_entry = if entry.is_writeable() {
+            let shared_entry = (entry - PT_RW) | PT_COW;
+            self.write().set_entry(vaddr, shared_entry);
+            shared_entry
+        } else {
+            entry
+        };
+
+        let mut pt_cap = target.ensure_pt(vaddr, untyped, cpool);
+        pt_cap.map_entry(pt_index(vaddr), shared_entry)
+    }
+
+    /// Resolve a write fault at `vaddr` on a copy-on-write page. A
+    /// new page is retyped from `untyped`, filled with the content of
+    /// the shared page, and mapped writable in place of it. Returns
+    /// `false` if the page at `vaddr` is not copy-on-write.
+    pub fn resolve_copy_on_write(&mut self, vaddr: VAddr,
+                                 untyped: &mut UntypedDescriptor, cpool: &CPoolDescriptor) -> bool {
+        use arch::paging::{pt_index};
+
+        let entry = match self.read().entry(vaddr) {
+            Some(entry) => entry,
+            None => return false,
+        };
+
+        if !entry.is_copy_on_write() {
+            return false;
+        }
+
+        let page_cap = RawPageCap::retype_from(untyped);
+        cpool.downgrade_free(&page_cap);
+
+        {
+            let shared: UniqueReadGuard<RawPage> =
+                unsafe { UniqueReadGuard::new(MemoryObject::new(entry.get_address())) };
+            let mut page = page_cap.write();
+            let mut page_raw = page.write();
+            page_raw.0 = shared.0;
+        }
+
+        let mut pt_cap = self.ensure_pt(vaddr, untyped, cpool);
+        pt_cap.remap_page(pt_index(vaddr), &page_cap);
+        self.write().invalidate(vaddr);
+
+        trace!("resolved copy-on-write at 0x{:x} with {:?}", vaddr, page_cap);
+        true
+    }
+
+    /// Find the PT capability covering `vaddr`, retyping the PDPT,
+    /// PD and PT from `untyped` if they are not yet mapped. Newly
+    /// created page tables are put into `cpool`.
+    fn ensure_pt(&mut self, vaddr: VAddr,
+                 untyped: &mut UntypedDescriptor, cpool: &CPoolDescriptor) -> PTCap {
+        use arch::paging::{pml4_index, pdpt_index, pd_index,
                            PML4Entry, PDPTEntry, PDEntry, PTEntry};
 
         let mut pdpt_cap: PDPTCap = {
@@ -88,7 +189,7 @@ impl PML4Cap {
             cpool.upgrade(position)
         }.unwrap();
 
-        log!("pdpt_cap: {:?}", pdpt_cap);
+        trace!("pdpt_cap: {:?}", pdpt_cap);
 
         let mut pd_cap: PDCap = {
             let index = pdpt_index(vaddr);
@@ -118,9 +219,9 @@ impl PML4Cap {
             cpool.upgrade(position)
         }.unwrap();
 
-        log!("pd_cap: {:?}", pd_cap);
+        trace!("pd_cap: {:?}", pd_cap);
 
-        let mut pt_cap: PTCap = {
+        let pt_cap: PTCap = {
             let index = pd_index(vaddr);
 
             if !{ pd_cap.read().read()[index] }.is_present() {
@@ -148,9 +249,49 @@ impl PML4Cap {
             cpool.upgrade(position)
         }.unwrap();
 
-        log!("pt_cap: {:?}", pt_cap);
+        trace!("pt_cap: {:?}", pt_cap);
 
-        pt_cap.map_page(pt_index(vaddr), page);
+        pt_cap
+    }
+
+    /// Switch to the address space. CR3 is not reloaded if the
+    /// address space is already current. With a PCID, TLB entries
+    /// from the last switch are kept unless the address space was
+    /// modified in between. The processor is recorded as running in
+    /// the address space until the task returns to the kernel. The
+    /// descriptor lives in an object pool window, so its guard is
+    /// released before CR3 is written.
+    pub fn switch_to(&self) {
+        use arch::paging::{self, pcid};
+
+        let (start_paddr, id, flush) = {
+            let mut desc = self.write();
+            let cpu = cpu_index();
+            smp::set_running_address_space(Some(desc.start_paddr));
+            if desc.is_current() && !desc.stale[cpu] {
+                return;
+            }
+
+            let flush = desc.stale[cpu];
+            desc.stale[cpu] = false;
+            (desc.start_paddr, desc.pcid, flush)
+        };
+
+        match id {
+            Some(id) => unsafe { pcid::switch_to(start_paddr, id, flush) },
+            None => unsafe { paging::switch_to(start_paddr) },
+        }
+    }
+}
+
+impl Drop for PML4Descriptor {
+    /// Free the PCID of the address space, so that it is reused.
+    fn drop(&mut self) {
+        use arch::paging::pcid;
+
+        if let Some(id) = self.pcid.take() {
+            pcid::free(id);
+        }
     }
 }
 
@@ -175,9 +316,125 @@ impl PML4Descriptor {
         unsafe { UniqueWriteGuard::new(self.page_object()) }
     }
 
-    pub fn switch_to(&mut self) {
+    /// Find the physical address of the PT covering `vaddr` by
+    /// walking the page tables. Returns `None` if any level is not
+    /// present, or maps a huge page instead of a lower-level table.
+    /// Addresses outside the user address space, such as the kernel
+    /// mapping, are never walked.
+    fn pt_paddr(&self, vaddr: VAddr) -> Option<PAddr> {
+        use arch::paging::{pml4_index, pdpt_index, pd_index, PDPT, PD};
+
+        if !is_user_vaddr(vaddr) {
+            return None;
+        }
+
+        let pml4_entry = self.read()[pml4_index(vaddr)];
+        if !pml4_entry.is_present() {
+            return None;
+        }
+
+        let pdpt: UniqueReadGuard<PDPT> =
+            unsafe { UniqueReadGuard::new(MemoryObject::new(pml4_entry.get_address())) };
+        let pdpt_entry = pdpt[pdpt_index(vaddr)];
+        if !pdpt_entry.is_present() || pdpt_entry.is_page() {
+            return None;
+        }
+
+        let pd: UniqueReadGuard<PD> =
+            unsafe { UniqueReadGuard::new(MemoryObject::new(pdpt_entry.get_address())) };
+        let pd_entry = pd[pd_index(vaddr)];
+        if !pd_entry.is_present() || pd_entry.is_page() {
+            return None;
+        }
+
+        Some(pd_entry.get_address())
+    }
+
+    /// The PT entry mapping `vaddr`. Returns `None` if the page is
+    /// not present.
+    pub fn entry(&self, vaddr: VAddr) -> Option<PTEntry> {
+        use arch::paging::{pt_index, PT};
+
+        self.pt_paddr(vaddr).and_then(|pt_paddr| {
+            let pt: UniqueReadGuard<PT> =
+                unsafe { UniqueReadGuard::new(MemoryObject::new(pt_paddr)) };
+            let entry = pt[pt_index(vaddr)];
+
+            if entry.is_present() {
+                Some(entry)
+            } else {
+                None
+            }
+        })
+    }
+
+    /// If `vaddr` is in a guard page, the stack region guarded by
+    /// it. Returns `None` otherwise.
+    pub fn guarded_stack(&self, vaddr: VAddr) -> Option<(VAddr, usize)> {
+        use arch::paging::{pt_index, PT};
+
+        self.pt_paddr(vaddr).and_then(|pt_paddr| {
+            let pt: UniqueReadGuard<PT> =
+                unsafe { UniqueReadGuard::new(MemoryObject::new(pt_paddr)) };
+            let entry = pt[pt_index(vaddr)];
+
+            if !entry.is_present() && entry.is_guard() {
+                let guard_vaddr = VAddr::from((vaddr.into(): u64) & !(BASE_PAGE_LENGTH as u64 - 1));
+                Some((guard_vaddr + BASE_PAGE_LENGTH, entry.get_address().into(): usize))
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Replace the PT entry mapping `vaddr`. The PT must already
+    /// exist.
+    fn set_entry(&mut self, vaddr: VAddr, entry: PTEntry) {
+        use arch::paging::{pt_index, PT};
+
+        {
+            let pt_paddr = self.pt_paddr(vaddr).unwrap();
+            let mut pt: UniqueWriteGuard<PT> =
+                unsafe { UniqueWriteGuard::new(MemoryObject::new(pt_paddr)) };
+            pt[pt_index(vaddr)] = entry;
+        }
+
+        self.invalidate(vaddr);
+    }
+
+    /// Invalidate the TLB entries of `vaddr` after its PT entry has
+    /// been replaced. If the address space is not current, its TLB
+    /// entries are flushed the next time it is switched to. This is
+    /// tracked for each processor, and other processors running a
+    /// task in the address space are made to leave it.
+    fn invalidate(&mut self, vaddr: VAddr) {
         use arch::paging;
 
-        unsafe { paging::switch_to(self.start_paddr); }
+        let current_cpu = cpu_index();
+        for cpu in 0..MAX_CPU_COUNT {
+            if cpu != current_cpu {
+                self.stale[cpu] = true;
+            }
+        }
+
+        if self.is_current() {
+            unsafe { paging::flush(vaddr); }
+        } else {
+            self.stale[current_cpu] = true;
+        }
+
+        smp::shootdown_tlb(self.start_paddr);
+    }
+
+    /// The PCID of the address space, if one is allocated.
+    pub fn pcid(&self) -> Option<PCID> {
+        self.pcid
+    }
+
+    /// Whether this is the address space currently loaded in CR3.
+    pub fn is_current(&self) -> bool {
+        use arch::paging::pcid;
+
+        pcid::current() == self.start_paddr
     }
 }
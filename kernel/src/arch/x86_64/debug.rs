@@ -25,3 +25,42 @@ pub unsafe fn putb(b: u8)
 	// Also send to the bochs 0xe9 hack
         ::arch::outportb(0xe9, b);
 }
+
+/// Enable the receive interrupt of the serial port. OUT2 is set as
+/// well, as it gates the interrupt line of the port.
+///
+/// This method is unsafe because it does port accesses without synchronisation
+pub unsafe fn enable_receive()
+{
+	// Data terminal ready, request to send and OUT2
+        ::arch::outportb(0x3F8+4, 0x0B);
+	// Interrupt on received data only
+        ::arch::outportb(0x3F8+1, 0x01);
+}
+
+/// Read a received byte from the serial port, if there is one
+///
+/// This method is unsafe because it does port accesses without synchronisation
+pub unsafe fn getb() -> Option<u8>
+{
+	if (::arch::inportb(0x3F8+5) & 0x01) == 0
+	{
+		None
+	}
+	else
+	{
+		Some(::arch::inportb(0x3F8))
+	}
+}
+
+/// I/O port of the QEMU isa-debug-exit device
+const DEBUG_EXIT_PORT: u16 = 0xf4;
+
+/// Exit QEMU through the isa-debug-exit device. QEMU exits with status
+/// `(code << 1) | 1`. Nothing happens if the device is not present.
+pub fn exit_qemu(code: u8)
+{
+	unsafe {
+		::arch::outportb(DEBUG_EXIT_PORT, code);
+	}
+}
@@ -1,7 +1,8 @@
 use arch::interrupt::{self, IDT, IO_APIC, LOCAL_APIC, disable_pic};
 
-/// Initialize interrupt. Disable PIC and then initialize APIC
-/// together with keyboard interrupt on I/O APIC.
+/// Initialize interrupt. Disable PIC and then initialize APIC. IRQ
+/// lines on the I/O APIC stay masked until an IRQ handler
+/// capability is created for them.
 pub fn init() {
     unsafe { disable_pic() };
     IDT.load();
@@ -9,10 +10,18 @@ pub fn init() {
     {
         let mut local_apic = LOCAL_APIC.lock();
         let mut io_apic = IO_APIC.lock();
-        let local_apic_id = local_apic.id() as u8;
-        let vector_offset = 0x20;
-        io_apic.set_irq(0x1, local_apic_id, interrupt::KEYBOARD_INTERRUPT_CODE);
+
+        for irq in 0..interrupt::IRQ_COUNT {
+            io_apic.mask_irq(irq as u8);
+        }
 
         local_apic.set_siv(0x1FF);
     }
 }
+
+/// Initialize interrupt of an application processor. The IDT is
+/// shared with the boot processor.
+pub fn init_ap() {
+    IDT.load();
+    LOCAL_APIC.lock().set_siv(0x1FF);
+}
@@ -11,10 +11,24 @@ mod interrupt;
 /// Segmentation initialization code.
 mod segmentation;
 
+/// Application processor start-up code.
+mod smp;
+
+/// Fast system call entry initialization code.
+mod syscall;
+
 pub use self::paging::{KERNEL_PML4, KERNEL_PDPT, KERNEL_PD,
                        OBJECT_POOL_PT, OBJECT_POOL_START_VADDR,
                        LOCAL_APIC_PAGE_VADDR, IO_APIC_PAGE_VADDR};
-pub use self::segmentation::{set_kernel_stack};
+pub use self::smp::{start_application_processors};
+
+/// Set the current kernel stack of the current processor, both for
+/// interrupts and for the `syscall` instruction. Essential for
+/// context switching.
+pub unsafe fn set_kernel_stack(addr: u64) {
+    segmentation::set_kernel_stack(addr);
+    syscall::set_kernel_stack(addr);
+}
 
 use ::{kmain};
 use super::{kernel_end_paddr, kernel_start_paddr, kernel_start_vaddr, kernel_end_vaddr, KERNEL_BASE};
@@ -120,10 +134,14 @@ fn bootstrap_archinfo() -> (InitInfo, MemoryRegion) {
         })
     }.unwrap();
 
-    log!("bootinfo: {:?}", bootinfo);
+    if let Some(command_line) = bootinfo.command_line() {
+        ::logging::init(command_line);
+    }
+
+    debug!("bootinfo: {:?}", bootinfo);
 
     let rinit_module = bootinfo.modules().unwrap().next().unwrap();
-    log!("rinit module: {:?}", rinit_module);
+    debug!("rinit module: {:?}", rinit_module);
     
     let mut archinfo = InitInfo::new(
         MemoryRegion::new(kernel_start_paddr(),
@@ -156,29 +174,33 @@ fn bootstrap_archinfo() -> (InitInfo, MemoryRegion) {
 
 /// Kernel entrypoint. This function calls `bootstrap_archinfo`, and
 /// then use the information to initialize paging, segmentation,
-/// interrupt, and APIC. It then jumps to `kmain`.
+/// interrupt, APIC and the `syscall` entry of the boot processor. It
+/// then jumps to `kmain`.
 #[lang="start"]
 #[no_mangle]
 pub fn kinit() {
     let (mut archinfo, mut alloc_region) = bootstrap_archinfo();
 
-    log!("kernel_start_vaddr: 0x{:x}", kernel_start_vaddr());
-    log!("archinfo: {:?}", archinfo);
-    log!("alloc_region: {:?}", alloc_region);
+    debug!("kernel_start_vaddr: 0x{:x}", kernel_start_vaddr());
+    debug!("archinfo: {:?}", archinfo);
+    debug!("alloc_region: {:?}", alloc_region);
 
     paging::init(&mut alloc_region);
+    unsafe { ::arch::paging::pcid::init(); }
+    ::arch::smp::register(0);
     segmentation::init();
     interrupt::init();
+    syscall::init_cpu(0);
 
     archinfo.push_free_region(alloc_region);
 
     {
         let local_apic = ::arch::interrupt::LOCAL_APIC.lock();
         let io_apic = ::arch::interrupt::IO_APIC.lock();
-        log!("Local APIC id: 0x{:x}", local_apic.id());
-        log!("Local APIC version: 0x{:x}", local_apic.version());
-        log!("I/O APIC id: 0x{:x}", io_apic.id());
-        log!("I/O APIC version: 0x{:x}", io_apic.version());
+        debug!("Local APIC id: 0x{:x}", local_apic.id());
+        debug!("Local APIC version: 0x{:x}", local_apic.version());
+        debug!("I/O APIC id: 0x{:x}", io_apic.id());
+        debug!("I/O APIC version: 0x{:x}", io_apic.version());
     }
 
     kmain(archinfo);
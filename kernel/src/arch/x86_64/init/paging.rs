@@ -80,7 +80,7 @@ fn alloc_kernel_pml4(region: &mut MemoryRegion, alloc_base: PAddr) -> Unique<PML
     let paddr = alloc_base + INITIAL_ALLOC_PML4_OFFSET;
     let vaddr = INITIAL_ALLOC_START_VADDR + INITIAL_ALLOC_PML4_OFFSET;
 
-    log!("pml4, paddr: 0x{:x}, vaddr: 0x{:x}", paddr, vaddr);
+    debug!("pml4, paddr: 0x{:x}, vaddr: 0x{:x}", paddr, vaddr);
 
     let mut pml4_unique = unsafe { Unique::new(vaddr.into(): usize as *mut PML4) };
 
@@ -104,7 +104,7 @@ fn alloc_kernel_pdpt(region: &mut MemoryRegion, pml4: &mut PML4, alloc_base: PAd
     let paddr = alloc_base + INITIAL_ALLOC_PDPT_OFFSET;
     let vaddr = INITIAL_ALLOC_START_VADDR + INITIAL_ALLOC_PDPT_OFFSET;
 
-    log!("pdpt, paddr: 0x{:x}, vaddr: 0x{:x}", paddr, vaddr);
+    debug!("pdpt, paddr: 0x{:x}, vaddr: 0x{:x}", paddr, vaddr);
 
     let mut pdpt_unique = unsafe { Unique::new(vaddr.into(): usize as *mut PDPT) };
 
@@ -130,7 +130,7 @@ fn alloc_kernel_pd(region: &mut MemoryRegion, pdpt: &mut PDPT, alloc_base: PAddr
     let paddr = alloc_base + INITIAL_ALLOC_PD_OFFSET;
     let vaddr = INITIAL_ALLOC_START_VADDR + INITIAL_ALLOC_PD_OFFSET;
 
-    log!("pd, paddr: 0x{:x}, vaddr: 0x{:x}", paddr, vaddr);
+    debug!("pd, paddr: 0x{:x}, vaddr: 0x{:x}", paddr, vaddr);
 
     let mut pd_unique = unsafe { Unique::new(vaddr.into(): usize as *mut PD) };
 
@@ -151,12 +151,12 @@ fn alloc_kernel_pd(region: &mut MemoryRegion, pdpt: &mut PDPT, alloc_base: PAddr
 /// Allocate the object pool PT. It also maps a reverse ObjectPool PT
 /// access point, and APIC pages (local and I/O).
 fn alloc_object_pool_pt(region: &mut MemoryRegion, pd: &mut PD, alloc_base: PAddr) -> Unique<PT> {
-    use arch::paging::{PTEntry, PDEntry, PD_P, PD_RW, PT_P, PT_RW, PT_PWT, PT_PCD};
+    use arch::paging::{PTEntry, PDEntry, PD_P, PD_RW, PT_P, PT_RW, PT_G, PT_PWT, PT_PCD};
     
     let paddr = alloc_base + INITIAL_ALLOC_OBJECT_POOL_PT_OFFSET;
     let vaddr = INITIAL_ALLOC_START_VADDR + INITIAL_ALLOC_OBJECT_POOL_PT_OFFSET;
 
-    log!("object_pool_pt, paddr: 0x{:x}, vaddr: 0x{:x}", paddr, vaddr);
+    debug!("object_pool_pt, paddr: 0x{:x}, vaddr: 0x{:x}", paddr, vaddr);
 
     let mut pt_unique = unsafe { Unique::new(vaddr.into(): usize as *mut PT) };
 
@@ -167,7 +167,7 @@ fn alloc_object_pool_pt(region: &mut MemoryRegion, pd: &mut PD, alloc_base: PAdd
         {
             // Mapping reverse ObjectPool PT Page
             let reverse_pt_index = pt_index(OBJECT_POOL_PT_VADDR);
-            pt[reverse_pt_index] = PTEntry::new(paddr, PT_P | PT_RW);
+            pt[reverse_pt_index] = PTEntry::new(paddr, PT_P | PT_RW | PT_G);
         }
 
         {
@@ -178,13 +178,13 @@ fn alloc_object_pool_pt(region: &mut MemoryRegion, pd: &mut PD, alloc_base: PAdd
             let apic_base = PAddr::from((apic_msr >> 12) * 0x1000);
             // Mapping APIC Page
             let apic_pt_index = pt_index(LOCAL_APIC_PAGE_VADDR);
-            pt[apic_pt_index] = PTEntry::new(apic_base, PT_P | PT_RW | PT_PWT | PT_PCD);
+            pt[apic_pt_index] = PTEntry::new(apic_base, PT_P | PT_RW | PT_G | PT_PWT | PT_PCD);
         }
 
         {
             let io_apic_base = PAddr::from(0xfec00000: u64);
             let io_apic_pt_index = pt_index(IO_APIC_PAGE_VADDR);
-            pt[io_apic_pt_index] = PTEntry::new(io_apic_base, PT_P | PT_RW | PT_PWT | PT_PCD);
+            pt[io_apic_pt_index] = PTEntry::new(io_apic_base, PT_P | PT_RW | PT_G | PT_PWT | PT_PCD);
         }
     }
 
@@ -197,14 +197,14 @@ fn alloc_object_pool_pt(region: &mut MemoryRegion, pd: &mut PD, alloc_base: PAdd
 
 /// Allocate one kernel page using `offset_size`.
 fn alloc_kernel_page(pt: &mut PT, offset_size: usize, alloc_base: PAddr) {
-    use arch::paging::{PT_P, PT_RW};
+    use arch::paging::{PT_P, PT_RW, PT_G};
     
     let paddr = kernel_start_paddr() + (offset_size * BASE_PAGE_LENGTH);
     let vaddr = kernel_start_vaddr() + (offset_size * BASE_PAGE_LENGTH);
 
-    log!("kernel page allocated at 0x{:x}", vaddr);
+    trace!("kernel page allocated at 0x{:x}", vaddr);
 
-    pt[pt_index(vaddr)] = PTEntry::new(paddr, PT_P | PT_RW);
+    pt[pt_index(vaddr)] = PTEntry::new(paddr, PT_P | PT_RW | PT_G);
 }
 
 /// Allocate the kernel guard page specified by `offset_size`.
@@ -214,7 +214,7 @@ fn alloc_kernel_guard_page(pt: &mut PT, offset_size: usize, alloc_base: PAddr) {
     let paddr = kernel_start_paddr() + (offset_size * BASE_PAGE_LENGTH);
     let vaddr = kernel_start_vaddr() + (offset_size * BASE_PAGE_LENGTH);
 
-    log!("guard page allocated at 0x{:x}", vaddr);
+    trace!("guard page allocated at 0x{:x}", vaddr);
 
     pt[pt_index(vaddr)] = PTEntry::empty();
 }
@@ -228,7 +228,7 @@ fn alloc_kernel_pts(region: &mut MemoryRegion, pd: &mut PD, alloc_base: PAddr) {
     let guard_page_index = (kernel_stack_guard_page_vaddr().into(): usize -
                             kernel_start_vaddr().into(): usize) / BASE_PAGE_LENGTH;
 
-    log!("guard_page_index: {}", guard_page_index);
+    debug!("guard_page_index: {}", guard_page_index);
 
     for i in 0..kernel_page_size {
         if i % 512 == 0 {
@@ -288,7 +288,7 @@ pub fn init(mut alloc_region: &mut MemoryRegion) {
     
     let alloc_base_paddr = map_alloc_region(&mut alloc_region);
 
-    log!("alloc_base_paddr: 0x{:x}", alloc_base_paddr);
+    debug!("alloc_base_paddr: 0x{:x}", alloc_base_paddr);
 
     let mut pml4_unique = alloc_kernel_pml4(&mut alloc_region,
                                             alloc_base_paddr);
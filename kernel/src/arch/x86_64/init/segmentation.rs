@@ -1,47 +1,62 @@
 use arch::{KERNEL_BASE};
-use arch::segmentation::{SegmentDescriptor, SegmentSelector, TaskStateSegment};
+use arch::segmentation::{SegmentDescriptor, SegmentSelector, TaskStateSegment,
+                         TSS_GDT_INDEX};
+use arch::smp::{cpu_index, MAX_CPU_COUNT};
 use common::*;
 use core::mem::{size_of};
 
 extern {
-    /// GDT memory address exposed by linker.
-    static mut GDT: [SegmentDescriptor; 9];
+    /// GDT memory address exposed by linker. It ends with a TSS
+    /// descriptor (two entries) for each processor.
+    static mut GDT: [SegmentDescriptor; TSS_GDT_INDEX + 2 * MAX_CPU_COUNT];
     /// Initial stack address exposed by linker.
     static init_stack: u64;
 }
 
-/// Task State Segment static.
-static mut TSS: TaskStateSegment = TaskStateSegment::empty();
+/// Task State Segments, indexed by processor.
+static mut TSS: [TaskStateSegment; MAX_CPU_COUNT] = [
+    TaskStateSegment::empty(), TaskStateSegment::empty(),
+    TaskStateSegment::empty(), TaskStateSegment::empty(),
+    TaskStateSegment::empty(), TaskStateSegment::empty(),
+    TaskStateSegment::empty(), TaskStateSegment::empty(),
+];
 
 /// Load the task state register.
 pub unsafe fn load_tr(sel: SegmentSelector) {
     asm!("ltr $0" :: "r" (sel));
 }
 
-/// Set the current kernel stack. Essential for context switching.
+/// Set the kernel stack of the current processor used on interrupts,
+/// in its TSS.
 pub unsafe fn set_kernel_stack(addr: u64) {
-    TSS.sp0 = addr;
-    TSS.ist1 = addr;
+    let tss = &mut TSS[cpu_index()];
+    tss.sp0 = addr;
+    tss.ist1 = addr;
 }
 
-/// Main function to initialize interrupt.
+/// Main function to initialize segmentation of the boot processor.
 pub fn init() {
+    init_cpu(0, &init_stack as *const _ as u64);
+}
+
+/// Initialize segmentation of the processor with index `cpu`, using
+/// `kernel_stack` as its initial kernel stack.
+pub fn init_cpu(cpu: usize, kernel_stack: u64) {
     unsafe {
         use arch::segmentation::{DESC_P, DESC_L, DESC_AVL, DESC_DPL3,
                                  TYPE_SYS_TSS_AVAILABLE,
                                  TYPE_C_ER};
-        let kernel_stack = &init_stack as *const _ as u64;
-        let tss_vaddr = &TSS as *const _ as u64;
-
-        set_kernel_stack(kernel_stack);
-        GDT[7] = SegmentDescriptor::new((tss_vaddr & 0xFFFFFFFF) as u32,
-                                        size_of::<TaskStateSegment>() as u32);
-        GDT[7].insert(DESC_P | TYPE_SYS_TSS_AVAILABLE | DESC_DPL3);
-        GDT[8] = SegmentDescriptor::from_raw(tss_vaddr >> 32);
-
-        log!("kernel_stack = 0x{:x}", kernel_stack);
-        // asm!("ltr ax" :: "{rax}"(&GDT[7] as *const _ as usize)
-        //      : "rax" : "intel", "volatile");
-        load_tr(SegmentSelector::new(7));
+        let tss_vaddr = &TSS[cpu] as *const _ as u64;
+        let gdt_index = TSS_GDT_INDEX + 2 * cpu;
+
+        TSS[cpu].sp0 = kernel_stack;
+        TSS[cpu].ist1 = kernel_stack;
+        GDT[gdt_index] = SegmentDescriptor::new((tss_vaddr & 0xFFFFFFFF) as u32,
+                                                size_of::<TaskStateSegment>() as u32);
+        GDT[gdt_index].insert(DESC_P | TYPE_SYS_TSS_AVAILABLE | DESC_DPL3);
+        GDT[gdt_index + 1] = SegmentDescriptor::from_raw(tss_vaddr >> 32);
+
+        debug!("kernel_stack = 0x{:x}", kernel_stack);
+        load_tr(SegmentSelector::new(gdt_index as u16));
     }
 }
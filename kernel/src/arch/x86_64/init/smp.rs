@@ -0,0 +1,140 @@
+use core::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use core::ptr;
+use arch::{KERNEL_BASE, io_wait};
+use arch::interrupt::{LOCAL_APIC};
+use arch::paging::{MemoryObject};
+use arch::smp::{self};
+use common::{PAddr};
+
+use super::{segmentation, interrupt, syscall, KERNEL_PML4};
+
+/// Physical address application processors start at. It must be
+/// page-aligned and below 1 MiB.
+const AP_TRAMPOLINE_PADDR: usize = 0x8000;
+
+/// Time, in microseconds, application processors are given to enter
+/// the trampoline after the start-up IPIs. Processors arriving later
+/// are taken as absent.
+const AP_START_TIMEOUT: usize = 100000;
+
+extern {
+    /// Start of the trampoline code exposed by linker.
+    static ap_trampoline: u8;
+    /// End of the trampoline code exposed by linker.
+    static ap_trampoline_end: u8;
+    /// Number of application processors that have entered the
+    /// trampoline, in the trampoline code.
+    static ap_entered: u64;
+    /// Set once no more application processors are waited for, in
+    /// the trampoline code.
+    static ap_closed: u64;
+    /// Number of application processors that entered the trampoline
+    /// after `ap_closed` was set, in the trampoline code.
+    static ap_late_count: u64;
+    /// Number of application processors halted for being beyond
+    /// `MAX_CPU_COUNT`, exposed by linker.
+    static ap_halted: u64;
+    /// `init_pml4` exposed by linker.
+    static init_pml4: u64;
+    /// `low_pdpt` exposed by linker.
+    static low_pdpt: u64;
+}
+
+/// Number of application processors that have finished
+/// initialization.
+static AP_READY: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Busy-wait for about `us` microseconds, using writes to the unused
+/// port 0x80.
+fn delay(us: usize) {
+    for _ in 0..us {
+        unsafe { io_wait(); }
+    }
+}
+
+/// Physical address of a symbol in the kernel image.
+fn kernel_symbol_paddr<T>(symbol: &T) -> PAddr {
+    PAddr::from(symbol as *const T as u64 - KERNEL_BASE)
+}
+
+/// The counter or flag `symbol` of the trampoline code, in its copy
+/// mapped by `trampoline`.
+unsafe fn trampoline_word<'a>(trampoline: &'a MemoryObject<u8>, symbol: &u64) -> &'a AtomicUsize {
+    let offset = symbol as *const u64 as usize - &ap_trampoline as *const u8 as usize;
+    &*((*trampoline).offset(offset as isize) as *const AtomicUsize)
+}
+
+/// Set the low identity mapping of the initial page table. The
+/// trampoline uses it to enable paging.
+unsafe fn set_low_identity_mapping(mapped: bool) {
+    let entry = MemoryObject::<u64>::new(kernel_symbol_paddr(&init_pml4));
+    let value = if mapped { kernel_symbol_paddr(&low_pdpt).into(): u64 + 3 } else { 0 };
+    ptr::write_volatile(*entry, value);
+}
+
+/// Start all application processors, using INIT and start-up IPIs
+/// broadcast to all other processors. Processors that have not
+/// entered the trampoline within `AP_START_TIMEOUT` are taken as
+/// absent. Returns after all other processors have either finished
+/// initialization or halted, so that the low identity mapping can be
+/// removed.
+pub fn start_application_processors() {
+    let trampoline_start = &ap_trampoline as *const u8 as usize;
+    let trampoline_length = &ap_trampoline_end as *const u8 as usize - trampoline_start;
+
+    let trampoline = unsafe {
+        MemoryObject::<u8>::slice(PAddr::from(AP_TRAMPOLINE_PADDR), trampoline_length)
+    };
+    unsafe {
+        ptr::copy_nonoverlapping(trampoline_start as *const u8, *trampoline, trampoline_length);
+        set_low_identity_mapping(true);
+    }
+
+    {
+        let mut local_apic = LOCAL_APIC.lock();
+        local_apic.send_init_all();
+        delay(10000);
+        local_apic.send_startup_all((AP_TRAMPOLINE_PADDR / 0x1000) as u8);
+        delay(200);
+        local_apic.send_startup_all((AP_TRAMPOLINE_PADDR / 0x1000) as u8);
+    }
+    delay(AP_START_TIMEOUT);
+
+    unsafe {
+        let entered = trampoline_word(&trampoline, &ap_entered);
+        let late = trampoline_word(&trampoline, &ap_late_count);
+        let halted = &*(&ap_halted as *const u64 as *const AtomicUsize);
+
+        // Processors entering from now on halt before paging is
+        // enabled. Those already in are waited for until they have
+        // left the low identity mapping.
+        trampoline_word(&trampoline, &ap_closed).store(1, Ordering::SeqCst);
+        while AP_READY.load(Ordering::SeqCst) + halted.load(Ordering::SeqCst) +
+            late.load(Ordering::SeqCst) < entered.load(Ordering::SeqCst) { }
+
+        set_low_identity_mapping(false);
+    }
+
+    log!("{} application processors started, {} processors in total",
+         AP_READY.load(Ordering::SeqCst), smp::cpu_count());
+}
+
+/// Entrypoint of application processors, called by the trampoline
+/// with the processor index and its kernel stack. It switches to the
+/// kernel page table, initializes segmentation, interrupt and the
+/// `syscall` entry of the processor, and then jumps to `kmain_ap`.
+#[no_mangle]
+pub extern "C" fn ap_kinit(index: usize, kernel_stack: u64) -> ! {
+    unsafe {
+        ::arch::paging::switch_to(KERNEL_PML4.paddr());
+        ::arch::paging::pcid::init();
+    }
+    smp::register(index);
+    segmentation::init_cpu(index, kernel_stack);
+    interrupt::init_ap();
+    syscall::init_cpu(index);
+
+    AP_READY.fetch_add(1, Ordering::SeqCst);
+
+    ::kmain_ap();
+}
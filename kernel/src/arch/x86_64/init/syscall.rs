@@ -0,0 +1,61 @@
+use arch::interrupt::{fast_system_call_entry};
+use arch::smp::{cpu_index, MAX_CPU_COUNT};
+
+/// Segment selector bases of the `syscall` and `sysret` instructions.
+const IA32_STAR: u32 = 0xC0000081;
+/// Entrypoint of the `syscall` instruction.
+const IA32_LSTAR: u32 = 0xC0000082;
+/// CPU flags cleared by the `syscall` instruction.
+const IA32_FMASK: u32 = 0xC0000084;
+/// GS base swapped in by `swapgs`.
+const IA32_KERNEL_GS_BASE: u32 = 0xC0000102;
+
+/// `syscall` loads the kernel code segment 0x08 and the stack
+/// segment 0x10. `sysret` loads the user code segment 0x28 and the
+/// stack segment 0x20, the same selectors `iretq` returns to user
+/// mode with.
+const STAR_VALUE: u64 = (0x08 << 32) | ((0x18 | 0x3) << 48);
+
+/// TF, IF, DF and AC are cleared on entry, so the entry runs with
+/// interrupts disabled.
+const FMASK_VALUE: u64 = (1 << 8) | (1 << 9) | (1 << 10) | (1 << 18);
+
+/// Scratch area of the `syscall` entry of a processor, reached
+/// through GS after `swapgs`. The layout is fixed, as it is used from
+/// assembly.
+#[repr(C)]
+struct SystemCallArea {
+    user_stack: u64,
+    kernel_stack: u64,
+}
+
+/// Scratch areas, indexed by processor.
+static mut SYSTEM_CALL_AREAS: [SystemCallArea; MAX_CPU_COUNT] = [
+    SystemCallArea { user_stack: 0, kernel_stack: 0 },
+    SystemCallArea { user_stack: 0, kernel_stack: 0 },
+    SystemCallArea { user_stack: 0, kernel_stack: 0 },
+    SystemCallArea { user_stack: 0, kernel_stack: 0 },
+    SystemCallArea { user_stack: 0, kernel_stack: 0 },
+    SystemCallArea { user_stack: 0, kernel_stack: 0 },
+    SystemCallArea { user_stack: 0, kernel_stack: 0 },
+    SystemCallArea { user_stack: 0, kernel_stack: 0 },
+];
+
+/// Set the kernel stack the `syscall` entry of the current processor
+/// switches to.
+pub unsafe fn set_kernel_stack(addr: u64) {
+    SYSTEM_CALL_AREAS[cpu_index()].kernel_stack = addr;
+}
+
+/// Enable the `syscall` instruction on the processor with index
+/// `cpu`. `syscall` is already enabled in EFER by the start-up code.
+pub fn init_cpu(cpu: usize) {
+    use x86::shared::msr::{wrmsr};
+
+    unsafe {
+        wrmsr(IA32_STAR, STAR_VALUE);
+        wrmsr(IA32_LSTAR, fast_system_call_entry as u64);
+        wrmsr(IA32_FMASK, FMASK_VALUE);
+        wrmsr(IA32_KERNEL_GS_BASE, &SYSTEM_CALL_AREAS[cpu] as *const _ as u64);
+    }
+}
@@ -9,6 +9,25 @@ pub struct LocalAPIC {
     address: VAddr,
 }
 
+/// Interrupt command register, low half.
+const ICR_LOW: u32 = 0x300;
+/// Interrupt command register, high half, holding the destination.
+const ICR_HIGH: u32 = 0x310;
+/// Set in the interrupt command register while an IPI is pending.
+const ICR_SEND_PENDING: u32 = 1 << 12;
+/// Fixed delivery mode, delivering the vector in the low byte.
+const ICR_DELIVERY_FIXED: u32 = 0b000 << 8;
+/// INIT delivery mode.
+const ICR_DELIVERY_INIT: u32 = 0b101 << 8;
+/// Non-maskable interrupt delivery mode.
+const ICR_DELIVERY_NMI: u32 = 0b100 << 8;
+/// Start-up delivery mode.
+const ICR_DELIVERY_STARTUP: u32 = 0b110 << 8;
+/// Assert level, required by INIT and start-up IPIs.
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+/// Destination shorthand for all processors but the sender.
+const ICR_ALL_EXCLUDING_SELF: u32 = 0b11 << 18;
+
 /// I/O APIC pointer.
 #[derive(Debug)]
 pub struct IOAPIC {
@@ -25,6 +44,16 @@ pub static IO_APIC: Mutex<IOAPIC> = Mutex::new(IOAPIC {
     address: IO_APIC_PAGE_VADDR
 });
 
+/// APIC id of the current processor. Unlike `LocalAPIC::id`, this
+/// does not lock `LOCAL_APIC`, so it can be used in context switching.
+pub fn local_apic_id() -> u8 {
+    use core::intrinsics::{volatile_load};
+    let id = unsafe {
+        volatile_load((LOCAL_APIC_PAGE_VADDR.into(): usize + 0x20) as *const u32)
+    };
+    (id >> 24) as u8
+}
+
 impl LocalAPIC {
     /// Read a value from the local APIC.
     ///
@@ -77,7 +106,7 @@ impl LocalAPIC {
             self.write(0x3E0, 0x3);
             self.write(0x380, 0x10000);
             self.write(0x320, (1<<17) | 0x40);
-            log!("timer register is 0b{:b}", self.read(0x320));
+            debug!("timer register is 0b{:b}", self.read(0x320));
         }
     }
 
@@ -85,6 +114,50 @@ impl LocalAPIC {
     pub fn error_status(&self) -> u32 {
         unsafe { self.read(0x280) }
     }
+
+    /// Send an inter-processor interrupt to the processor with APIC
+    /// id `destination`, or to all other processors if it is `None`,
+    /// and wait until it is delivered.
+    ///
+    /// # Safety
+    ///
+    /// `command` must be a valid interrupt command.
+    unsafe fn send_ipi(&mut self, destination: Option<u8>, command: u32) {
+        match destination {
+            Some(apic_id) => {
+                self.write(ICR_HIGH, (apic_id as u32) << 24);
+                self.write(ICR_LOW, command);
+            },
+            None => {
+                self.write(ICR_LOW, command | ICR_ALL_EXCLUDING_SELF);
+            },
+        }
+
+        while self.read(ICR_LOW) & ICR_SEND_PENDING != 0 { }
+    }
+
+    /// Send an IPI with interrupt vector `vector` to the processor
+    /// with APIC id `apic_id`.
+    pub fn send_ipi_to(&mut self, apic_id: u8, vector: InterruptVector) {
+        unsafe { self.send_ipi(Some(apic_id), ICR_DELIVERY_FIXED | ICR_LEVEL_ASSERT | vector as u8 as u32) }
+    }
+
+    /// Send a non-maskable interrupt to all other processors. It is
+    /// delivered even if they run with interrupts disabled.
+    pub fn send_nmi_all(&mut self) {
+        unsafe { self.send_ipi(None, ICR_DELIVERY_NMI | ICR_LEVEL_ASSERT) }
+    }
+
+    /// Send an INIT IPI to all other processors.
+    pub fn send_init_all(&mut self) {
+        unsafe { self.send_ipi(None, ICR_DELIVERY_INIT | ICR_LEVEL_ASSERT) }
+    }
+
+    /// Send a start-up IPI to all other processors. They start in
+    /// real mode at physical address `page * 0x1000`.
+    pub fn send_startup_all(&mut self, page: u8) {
+        unsafe { self.send_ipi(None, ICR_DELIVERY_STARTUP | ICR_LEVEL_ASSERT | page as u32) }
+    }
 }
 
 impl IOAPIC {
@@ -145,4 +218,23 @@ impl IOAPIC {
         low |= (vector as u32);
         unsafe { self.write(low_index, low) };
     }
+
+    /// Mask an IRQ. The interrupt will not be delivered until it is
+    /// unmasked.
+    pub fn mask_irq(&mut self, irq: u8) {
+        let low_index: u32 = 0x10 + (irq as u32) * 2;
+
+        let mut low = unsafe { self.read(low_index) };
+        low |= 1<<16;
+        unsafe { self.write(low_index, low) };
+    }
+
+    /// Unmask an IRQ previously masked by `mask_irq`.
+    pub fn unmask_irq(&mut self, irq: u8) {
+        let low_index: u32 = 0x10 + (irq as u32) * 2;
+
+        let mut low = unsafe { self.read(low_index) };
+        low &= !(1<<16);
+        unsafe { self.write(low_index, low) };
+    }
 }
@@ -15,26 +15,83 @@ mod switch;
 
 use lazy_static;
 use common::*;
-use self::switch::{last_exception_return_value, switch_to_raw, ExceptionInfo};
+use abi::{TaskRegisters};
+use super::{USER_END};
+use self::switch::{last_exception_return_value, switch_to_raw, switch_to_raw_sysret, ExceptionInfo};
 
-pub use self::switch::{HandlerFunc, Registers};
-pub use self::apic::{LOCAL_APIC, IO_APIC};
+pub use self::switch::{HandlerFunc, Registers, fast_system_call_entry};
+pub use self::apic::{LOCAL_APIC, IO_APIC, local_apic_id};
 pub use self::pic::{disable_pic};
 
 /// Interrupt vector type.
 pub type InterruptVector = u64;
 
 pub const TIMER_INTERRUPT_CODE: InterruptVector = 0x40;
+/// Inter-processor interrupt asking a processor to pick its next task
+/// again.
+pub const RESCHEDULE_INTERRUPT_CODE: InterruptVector = 0x41;
+/// Inter-processor interrupt asking a processor to leave the address
+/// space it is running, because its page table has been modified.
+pub const TLB_SHOOTDOWN_INTERRUPT_CODE: InterruptVector = 0x42;
 pub const SPURIOUS_INTERRUPT_CODE: InterruptVector = 0xFF;
-pub const KEYBOARD_INTERRUPT_CODE: InterruptVector = 0x21;
 pub const SYSTEM_CALL_INTERRUPT_CODE: InterruptVector = 0x80;
 pub const DEBUG_CALL_INTERRUPT_CODE: InterruptVector = 0x81;
+pub const PAGE_FAULT_INTERRUPT_CODE: InterruptVector = 0xE;
+/// Non-maskable interrupt. The kernel only sends it to halt the other
+/// processors on a panic, so it halts the processor receiving it.
+pub const NMI_INTERRUPT_CODE: InterruptVector = 0x2;
+/// Exception code of system calls made with the `syscall`
+/// instruction. This is not an interrupt vector.
+pub const FAST_SYSTEM_CALL_CODE: InterruptVector = 0x100;
+
+/// Interrupt vector of IRQ line 0. IRQ line `n` of the I/O APIC is
+/// routed to `IRQ_INTERRUPT_CODE_BASE + n`.
+pub const IRQ_INTERRUPT_CODE_BASE: InterruptVector = 0x20;
+/// Number of IRQ lines (I/O APIC redirection entries) supported.
+pub const IRQ_COUNT: usize = 24;
+/// IRQ line of the PS/2 keyboard.
+pub const KEYBOARD_IRQ: u8 = 0x1;
+/// IRQ line of the first serial port, COM1.
+pub const SERIAL_IRQ: u8 = 0x4;
 
 return_to_raw_fn!(timer_return_to_raw, TIMER_INTERRUPT_CODE);
+return_to_raw_fn!(reschedule_return_to_raw, RESCHEDULE_INTERRUPT_CODE);
+return_to_raw_fn!(tlb_shootdown_return_to_raw, TLB_SHOOTDOWN_INTERRUPT_CODE);
 return_to_raw_fn!(spurious_return_to_raw, SPURIOUS_INTERRUPT_CODE);
-return_to_raw_fn!(keyboard_return_to_raw, KEYBOARD_INTERRUPT_CODE);
 return_to_raw_fn!(system_call_return_to_raw, SYSTEM_CALL_INTERRUPT_CODE);
 return_to_raw_fn!(debug_call_return_to_raw, DEBUG_CALL_INTERRUPT_CODE);
+return_to_raw_fn!(fast_system_call_return_to_raw, FAST_SYSTEM_CALL_CODE);
+return_error_to_raw_fn!(page_fault_return_to_raw, PAGE_FAULT_INTERRUPT_CODE);
+
+return_to_raw_fn!(irq0_return_to_raw, IRQ_INTERRUPT_CODE_BASE + 0);
+return_to_raw_fn!(irq1_return_to_raw, IRQ_INTERRUPT_CODE_BASE + 1);
+return_to_raw_fn!(irq2_return_to_raw, IRQ_INTERRUPT_CODE_BASE + 2);
+return_to_raw_fn!(irq3_return_to_raw, IRQ_INTERRUPT_CODE_BASE + 3);
+return_to_raw_fn!(irq4_return_to_raw, IRQ_INTERRUPT_CODE_BASE + 4);
+return_to_raw_fn!(irq5_return_to_raw, IRQ_INTERRUPT_CODE_BASE + 5);
+return_to_raw_fn!(irq6_return_to_raw, IRQ_INTERRUPT_CODE_BASE + 6);
+return_to_raw_fn!(irq7_return_to_raw, IRQ_INTERRUPT_CODE_BASE + 7);
+return_to_raw_fn!(irq8_return_to_raw, IRQ_INTERRUPT_CODE_BASE + 8);
+return_to_raw_fn!(irq9_return_to_raw, IRQ_INTERRUPT_CODE_BASE + 9);
+return_to_raw_fn!(irq10_return_to_raw, IRQ_INTERRUPT_CODE_BASE + 10);
+return_to_raw_fn!(irq11_return_to_raw, IRQ_INTERRUPT_CODE_BASE + 11);
+return_to_raw_fn!(irq12_return_to_raw, IRQ_INTERRUPT_CODE_BASE + 12);
+return_to_raw_fn!(irq13_return_to_raw, IRQ_INTERRUPT_CODE_BASE + 13);
+return_to_raw_fn!(irq14_return_to_raw, IRQ_INTERRUPT_CODE_BASE + 14);
+return_to_raw_fn!(irq15_return_to_raw, IRQ_INTERRUPT_CODE_BASE + 15);
+return_to_raw_fn!(irq16_return_to_raw, IRQ_INTERRUPT_CODE_BASE + 16);
+return_to_raw_fn!(irq17_return_to_raw, IRQ_INTERRUPT_CODE_BASE + 17);
+return_to_raw_fn!(irq18_return_to_raw, IRQ_INTERRUPT_CODE_BASE + 18);
+return_to_raw_fn!(irq19_return_to_raw, IRQ_INTERRUPT_CODE_BASE + 19);
+return_to_raw_fn!(irq20_return_to_raw, IRQ_INTERRUPT_CODE_BASE + 20);
+return_to_raw_fn!(irq21_return_to_raw, IRQ_INTERRUPT_CODE_BASE + 21);
+return_to_raw_fn!(irq22_return_to_raw, IRQ_INTERRUPT_CODE_BASE + 22);
+return_to_raw_fn!(irq23_return_to_raw, IRQ_INTERRUPT_CODE_BASE + 23);
+
+/// Handler of non-maskable interrupts. It never returns.
+unsafe extern "C" fn nmi_halt() {
+    ::arch::halt();
+}
 
 lazy_static! {
     /// The interrupt descriptor table static.
@@ -45,12 +102,27 @@ lazy_static! {
             .set_privilege_level(0x3);
         idt.set_handler(DEBUG_CALL_INTERRUPT_CODE, debug_call_return_to_raw)
             .set_privilege_level(0x3);
-        idt.set_handler(KEYBOARD_INTERRUPT_CODE, keyboard_return_to_raw)
-            .set_privilege_level(0x3);
         idt.set_handler(SPURIOUS_INTERRUPT_CODE, spurious_return_to_raw)
             .set_privilege_level(0x3);
         idt.set_handler(TIMER_INTERRUPT_CODE, timer_return_to_raw)
             .set_privilege_level(0x3);
+        idt.set_handler(RESCHEDULE_INTERRUPT_CODE, reschedule_return_to_raw);
+        idt.set_handler(TLB_SHOOTDOWN_INTERRUPT_CODE, tlb_shootdown_return_to_raw);
+        idt.set_handler(PAGE_FAULT_INTERRUPT_CODE, page_fault_return_to_raw);
+        idt.set_handler(NMI_INTERRUPT_CODE, nmi_halt);
+
+        let irq_handlers: [HandlerFunc; IRQ_COUNT] = [
+            irq0_return_to_raw, irq1_return_to_raw, irq2_return_to_raw, irq3_return_to_raw,
+            irq4_return_to_raw, irq5_return_to_raw, irq6_return_to_raw, irq7_return_to_raw,
+            irq8_return_to_raw, irq9_return_to_raw, irq10_return_to_raw, irq11_return_to_raw,
+            irq12_return_to_raw, irq13_return_to_raw, irq14_return_to_raw, irq15_return_to_raw,
+            irq16_return_to_raw, irq17_return_to_raw, irq18_return_to_raw, irq19_return_to_raw,
+            irq20_return_to_raw, irq21_return_to_raw, irq22_return_to_raw, irq23_return_to_raw,
+        ];
+
+        for (irq, handler) in irq_handlers.iter().enumerate() {
+            idt.set_handler(IRQ_INTERRUPT_CODE_BASE + irq as InterruptVector, *handler);
+        }
 
         idt
     };
@@ -61,10 +133,18 @@ lazy_static! {
 #[derive(Debug)]
 pub enum Exception {
     SystemCall,
+    FastSystemCall,
     DebugCall,
-    Keyboard,
+    IRQ(u8),
     Spurious,
-    Timer
+    Timer,
+    Reschedule,
+    TLBShootdown,
+    PageFault {
+        vaddr: VAddr,
+        present: bool,
+        write: bool,
+    }
 }
 
 impl Exception {
@@ -73,10 +153,25 @@ impl Exception {
     fn new(code: u64, error: Option<u64>) -> Exception {
         match code {
             TIMER_INTERRUPT_CODE => Exception::Timer,
+            RESCHEDULE_INTERRUPT_CODE => Exception::Reschedule,
+            TLB_SHOOTDOWN_INTERRUPT_CODE => Exception::TLBShootdown,
             SPURIOUS_INTERRUPT_CODE => Exception::Spurious,
-            KEYBOARD_INTERRUPT_CODE => Exception::Keyboard,
             SYSTEM_CALL_INTERRUPT_CODE => Exception::SystemCall,
+            FAST_SYSTEM_CALL_CODE => Exception::FastSystemCall,
             DEBUG_CALL_INTERRUPT_CODE => Exception::DebugCall,
+            PAGE_FAULT_INTERRUPT_CODE => {
+                use x86::shared::control_regs::{cr2};
+
+                let error = error.unwrap();
+                Exception::PageFault {
+                    vaddr: VAddr::from(unsafe { cr2() }),
+                    present: error & (1<<0) != 0,
+                    write: error & (1<<1) != 0,
+                }
+            },
+            code if code >= IRQ_INTERRUPT_CODE_BASE &&
+                code < IRQ_INTERRUPT_CODE_BASE + IRQ_COUNT as InterruptVector =>
+                Exception::IRQ((code - IRQ_INTERRUPT_CODE_BASE) as u8),
             _ => panic!(),
         }
     }
@@ -85,19 +180,30 @@ impl Exception {
     pub unsafe fn send_eoi(&self) {
         match self {
             &Exception::Timer => LOCAL_APIC.lock().eoi(),
-            &Exception::Keyboard => LOCAL_APIC.lock().eoi(),
+            &Exception::IRQ(_) => LOCAL_APIC.lock().eoi(),
+            &Exception::Reschedule => LOCAL_APIC.lock().eoi(),
+            &Exception::TLBShootdown => LOCAL_APIC.lock().eoi(),
             _ => (),
         }
     }
 }
 
+/// CPU flags a task may change through `TaskRuntime::set_registers`:
+/// CF, PF, AF, ZF, SF, TF, DF and OF.
+const USER_CPU_FLAGS_MASK: u64 = 0b110111010101;
+
 /// Represents a task runtime. Used by the task capability.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct TaskRuntime {
     instruction_pointer: u64,
     cpu_flags: u64,
     stack_pointer: u64,
-    registers: Registers
+    registers: Registers,
+    /// Whether the task can be returned to with `sysret`. This is the
+    /// case if it last entered the kernel with `syscall`, and its
+    /// registers have not been changed since. It is only used if the
+    /// instruction pointer is a user address.
+    sysret: bool,
 }
 
 impl Default for TaskRuntime {
@@ -107,6 +213,7 @@ impl Default for TaskRuntime {
             cpu_flags: 0b11001000000110,
             stack_pointer: 0x0,
             registers: Registers::default(),
+            sysret: false,
         }
     }
 }
@@ -120,14 +227,33 @@ impl TaskRuntime {
     /// be set according to the task capability.
     pub unsafe fn switch_to(&mut self, mode_change: bool) -> Exception {
         let code_seg: u64 = if mode_change { 0x28 | 0x3 } else { 0x8 | 0x0 };
-        let data_seg: u64 = if mode_change { 0x30 | 0x3 } else { 0x10 | 0x0 };
+        let data_seg: u64 = if mode_change { 0x20 | 0x3 } else { 0x10 | 0x0 };
 
         switch::set_cur_registers(self.registers.clone());
-        switch_to_raw(self.stack_pointer, self.instruction_pointer, self.cpu_flags, code_seg, data_seg);
+        // `sysret` faults in kernel mode on a non-canonical return
+        // address, so the task is returned to with `iretq` unless it
+        // goes back to the user half.
+        if mode_change && self.sysret && self.instruction_pointer < USER_END {
+            switch_to_raw_sysret(self.stack_pointer, self.instruction_pointer, self.cpu_flags);
+        } else {
+            switch_to_raw(self.stack_pointer, self.instruction_pointer, self.cpu_flags, code_seg, data_seg);
+        }
         self.registers = switch::cur_registers();
+        ::arch::smp::set_running_address_space(None);
 
         let exception_info = last_exception_return_value().unwrap();
 
+        // Page faults are only expected from tasks. A fault taken in
+        // kernel mode is a kernel bug, and must not be handled as if
+        // the task had caused it.
+        if exception_info.exception_code == PAGE_FAULT_INTERRUPT_CODE &&
+            exception_info.code_segment & 0x3 == 0 {
+            use x86::shared::control_regs::{cr2};
+
+            panic!("page fault in kernel mode at 0x{:x}, instruction 0x{:x}",
+                   unsafe { cr2() }, exception_info.instruction_pointer);
+        }
+
         self.instruction_pointer = exception_info.instruction_pointer;
         self.cpu_flags = exception_info.cpu_flags;
         self.stack_pointer = exception_info.stack_pointer;
@@ -135,23 +261,76 @@ impl TaskRuntime {
         let exception = Exception::new(exception_info.exception_code, exception_info.error_code);
         unsafe { exception.send_eoi(); }
 
+        self.sysret = match exception {
+            Exception::FastSystemCall => true,
+            _ => false,
+        };
+
         return exception;
     }
 
     /// Set the instruction pointer of the task runtime.
     pub fn set_instruction_pointer(&mut self, instruction_pointer: VAddr) {
+        self.sysret = false;
         self.instruction_pointer = instruction_pointer.into();
     }
 
+    /// Set `rax` and `rdx`, the return values of a fast system call.
+    /// The task can still be returned to with `sysret`.
+    pub fn set_return_values(&mut self, rax: u64, rdx: u64) {
+        self.registers.rax = rax;
+        self.registers.rdx = rdx;
+    }
+
     /// Set the stack pointer of the task runtime.
     pub fn set_stack_pointer(&mut self, stack_pointer: VAddr) {
         self.stack_pointer = stack_pointer.into();
     }
+
+    /// Read the saved registers of the task runtime.
+    pub fn registers(&self) -> TaskRegisters {
+        TaskRegisters {
+            rip: self.instruction_pointer,
+            rsp: self.stack_pointer,
+            rflags: self.cpu_flags,
+            rax: self.registers.rax, rbx: self.registers.rbx,
+            rcx: self.registers.rcx, rdx: self.registers.rdx,
+            rbp: self.registers.rbp, rsi: self.registers.rsi,
+            rdi: self.registers.rdi, r8: self.registers.r8,
+            r9: self.registers.r9, r10: self.registers.r10,
+            r11: self.registers.r11, r12: self.registers.r12,
+            r13: self.registers.r13, r14: self.registers.r14,
+            r15: self.registers.r15,
+        }
+    }
+
+    /// Overwrite the saved registers of the task runtime. Only the
+    /// arithmetic, trap and direction flags are taken from `rflags`;
+    /// interrupts stay enabled and the I/O privilege level is kept.
+    pub fn set_registers(&mut self, registers: &TaskRegisters) {
+        self.sysret = false;
+        self.instruction_pointer = registers.rip;
+        self.stack_pointer = registers.rsp;
+        self.cpu_flags = (registers.rflags & USER_CPU_FLAGS_MASK) |
+            (self.cpu_flags & !USER_CPU_FLAGS_MASK);
+        self.registers = Registers {
+            rax: registers.rax, rbx: registers.rbx,
+            rcx: registers.rcx, rdx: registers.rdx,
+            rbp: registers.rbp, rsi: registers.rsi,
+            rdi: registers.rdi, r8: registers.r8,
+            r9: registers.r9, r10: registers.r10,
+            r11: registers.r11, r12: registers.r12,
+            r13: registers.r13, r14: registers.r14,
+            r15: registers.r15,
+        };
+    }
 }
 
 /// Enable interrupt. Not used.
 pub unsafe fn enable_interrupt() { }
-/// Disable interrupt. Not used.
-pub unsafe fn disable_interrupt() { }
+/// Disable interrupts on the current processor.
+pub unsafe fn disable_interrupt() {
+    asm!("cli" :::: "volatile");
+}
 /// Set interrupt handler. Not used.
 pub unsafe fn set_interrupt_handler() { }
@@ -1,4 +1,5 @@
 use arch::init;
+use arch::smp::{cpu_index, MAX_CPU_COUNT};
 
 /// Interrupt handler function type.
 pub type HandlerFunc = unsafe extern "C" fn();
@@ -24,7 +25,10 @@ pub struct ExceptionInfo {
     pub exception_code: u64
 }
 
+/// General-purpose registers. The layout is fixed, as they are pushed
+/// and read from assembly.
 #[derive(Debug, Clone)]
+#[repr(C)]
 pub struct Registers {
     pub rax: u64,
     pub rbx: u64,
@@ -118,63 +122,192 @@ pub unsafe extern "C" fn switch_to_raw_naked(stack_vaddr: u64, code_start: u64,
           push rbx
           push r8
           push rcx
-          push rdx
-          push r9"
-         :: "{rax}"(data_seg), "{rbx}"(stack_vaddr), "{rcx}"(code_seg), "{rdx}"(code_start), "{r8}"(cpu_flags),
-         "{r9}"(CUR_REGISTERS.rbp)
+          push rdx"
+         :: "{rax}"(data_seg), "{rbx}"(stack_vaddr), "{rcx}"(code_seg), "{rdx}"(code_start), "{r8}"(cpu_flags)
+         :: "volatile", "intel");
+
+    asm!("mov rax, [r15]
+          mov rbx, [r15 + 8]
+          mov rcx, [r15 + 16]
+          mov rdx, [r15 + 24]
+          mov rbp, [r15 + 32]
+          mov rsi, [r15 + 40]
+          mov rdi, [r15 + 48]
+          mov r8, [r15 + 56]
+          mov r9, [r15 + 64]
+          mov r10, [r15 + 72]
+          mov r11, [r15 + 80]
+          mov r12, [r15 + 88]
+          mov r13, [r15 + 96]
+          mov r14, [r15 + 104]
+          mov r15, [r15 + 112]
+          iretq" :: "{r15}"(cur_registers_ptr())
+         :: "volatile", "intel");
+}
+
+/// Switch to a task that entered the kernel with the `syscall`
+/// instruction, returning with `sysret`. The task continues at
+/// `code_start` in 64-bit user mode, with `cpu_flags` and the saved
+/// registers, except `rcx` and `r11`, which are set to `code_start`
+/// and `cpu_flags` as after `syscall`.
+pub unsafe fn switch_to_raw_sysret(stack_vaddr: u64, code_start: u64, cpu_flags: u64) {
+    asm!("call r15" :: "{rdi}"(stack_vaddr), "{rsi}"(code_start), "{rdx}"(cpu_flags), "{r15}"(switch_to_raw_sysret_naked as unsafe extern "C" fn(u64, u64, u64)) :: "volatile", "intel");
+
+    // WARNING: Everything below this before returning will not work.
+}
+
+#[no_mangle]
+#[naked]
+pub unsafe extern "C" fn switch_to_raw_sysret_naked(stack_vaddr: u64, code_start: u64, cpu_flags: u64) {
+    save_registers!();
+
+    // The arguments are kept in callee-saved registers across the
+    // calls below.
+    asm!("mov r12, rdi
+          mov r13, rsi
+          mov r14, rdx
+          mov rdi, rsp
+          call $0" :: "i"(set_kernel_stack as unsafe extern "C" fn(u64)) : "rdi" : "volatile", "intel");
+
+    // Interrupts stay disabled until `sysret` loads the task's flags,
+    // so nothing runs on the user stack in the kernel. The bytes are
+    // `sysretq`, returning to 64-bit mode.
+    asm!("mov rcx, r13
+          mov r11, r14
+          mov rsp, r12
+          mov rax, [r15]
+          mov rbx, [r15 + 8]
+          mov rdx, [r15 + 24]
+          mov rbp, [r15 + 32]
+          mov rsi, [r15 + 40]
+          mov rdi, [r15 + 48]
+          mov r8, [r15 + 56]
+          mov r9, [r15 + 64]
+          mov r10, [r15 + 72]
+          mov r12, [r15 + 88]
+          mov r13, [r15 + 96]
+          mov r14, [r15 + 104]
+          mov r15, [r15 + 112]
+          .byte 0x48, 0x0f, 0x07" :: "{r15}"(cur_registers_ptr())
          :: "volatile", "intel");
+}
 
-    asm!("pop rbp
-          iretq" :: "{rax}"(CUR_REGISTERS.rax), "{rbx}"(CUR_REGISTERS.rbx), "{rcx}"(CUR_REGISTERS.rcx),
-         "{rdx}"(CUR_REGISTERS.rdx), "{rsi}"(CUR_REGISTERS.rsi),
-         "{rdi}"(CUR_REGISTERS.rdi), "{r8}"(CUR_REGISTERS.r8), "{r9}"(CUR_REGISTERS.r9),
-         "{r10}"(CUR_REGISTERS.r10), "{r11}"(CUR_REGISTERS.r11), "{r12}"(CUR_REGISTERS.r12),
-         "{r13}"(CUR_REGISTERS.r13), "{r14}"(CUR_REGISTERS.r14), "{r15}"(CUR_REGISTERS.r15)
+/// Entrypoint of the `syscall` instruction. The user stack pointer is
+/// saved, and the kernel stack loaded, through the scratch area of the
+/// processor reached with `swapgs`. An interrupt stack frame is then
+/// built from `rcx` and `r11`, so that the rest is handled like an
+/// interrupt. `syscall` has already disabled interrupts.
+#[no_mangle]
+#[naked]
+pub unsafe extern "C" fn fast_system_call_entry() {
+    asm!("swapgs
+          mov qword ptr gs:[0], rsp
+          mov rsp, qword ptr gs:[8]
+          push 0x23
+          push qword ptr gs:[0]
+          push r11
+          push 0x2B
+          push rcx
+          swapgs
+          jmp $0"
+         :: "i"(super::fast_system_call_return_to_raw as unsafe extern "C" fn())
          :: "volatile", "intel");
 }
 
-static mut CUR_EXCEPTION_STACK_FRAME: Option<ExceptionStackFrame> = None;
-static mut CUR_EXCEPTION_ERROR_CODE: Option<u64> = None;
-static mut CUR_EXCEPTION_CODE: Option<u64> = None;
-static mut CUR_REGISTERS: Registers = Registers {
-    rax: 0, rbx: 0, rcx: 0, rdx: 0, rbp: 0, rsi: 0, rdi: 0,
-    r8: 0, r9: 0, r10: 0, r11: 0, r12: 0, r13: 0, r14: 0, r15: 0
-};
+/// Context switching state of a processor.
+struct SwitchState {
+    registers: Registers,
+    exception_stack_frame: Option<ExceptionStackFrame>,
+    exception_error_code: Option<u64>,
+    exception_code: Option<u64>,
+}
+
+impl SwitchState {
+    /// Create an empty switching state.
+    const fn new() -> SwitchState {
+        SwitchState {
+            registers: Registers {
+                rax: 0, rbx: 0, rcx: 0, rdx: 0, rbp: 0, rsi: 0, rdi: 0,
+                r8: 0, r9: 0, r10: 0, r11: 0, r12: 0, r13: 0, r14: 0, r15: 0
+            },
+            exception_stack_frame: None,
+            exception_error_code: None,
+            exception_code: None,
+        }
+    }
+}
+
+/// Switching states, indexed by processor. A processor only accesses
+/// its own state.
+static mut SWITCH_STATES: [SwitchState; MAX_CPU_COUNT] = [
+    SwitchState::new(), SwitchState::new(), SwitchState::new(), SwitchState::new(),
+    SwitchState::new(), SwitchState::new(), SwitchState::new(), SwitchState::new(),
+];
+
+/// Switching state of the current processor.
+unsafe fn cur_state() -> &'static mut SwitchState {
+    &mut SWITCH_STATES[cpu_index()]
+}
+
+/// Pointer to the registers to be restored on the current processor.
+unsafe extern "C" fn cur_registers_ptr() -> *const Registers {
+    &cur_state().registers as *const Registers
+}
 
 pub unsafe fn set_cur_registers(registers: Registers) {
-    CUR_REGISTERS = registers;
+    cur_state().registers = registers;
 }
 
 pub unsafe fn cur_registers() -> Registers {
-    CUR_REGISTERS.clone()
+    cur_state().registers.clone()
 }
 
 pub unsafe extern "C" fn store_exception_stack(exception_raw: *const ExceptionStackFrame, exception_code: u64) {
     let exception = unsafe {&*exception_raw};
-    CUR_EXCEPTION_STACK_FRAME = Some(exception.clone());
-    CUR_EXCEPTION_ERROR_CODE = None;
-    CUR_EXCEPTION_CODE = Some(exception_code);
+    let state = cur_state();
+    state.exception_stack_frame = Some(exception.clone());
+    state.exception_error_code = None;
+    state.exception_code = Some(exception_code);
 }
 
 pub unsafe extern "C" fn store_error_exception_stack(exception_raw: *const ExceptionStackFrame, error_code: u64, exception_code: u64) {
     let exception = unsafe {&*exception_raw};
-    CUR_EXCEPTION_STACK_FRAME = Some(exception.clone());
-    CUR_EXCEPTION_ERROR_CODE = Some(error_code);
-    CUR_EXCEPTION_CODE = Some(exception_code);
+    let state = cur_state();
+    state.exception_stack_frame = Some(exception.clone());
+    state.exception_error_code = Some(error_code);
+    state.exception_code = Some(exception_code);
+}
+
+pub unsafe extern "C" fn store_usermode_registers(registers_raw: *const Registers) {
+    let registers = unsafe {&*registers_raw};
+    cur_state().registers = registers.clone();
 }
 
+/// Save the registers of the interrupted task to the switching state
+/// of the current processor. The registers are pushed in the layout
+/// of `Registers`, so that they can be copied in one call.
 #[inline(always)]
 pub unsafe fn save_usermode_registers() {
-    asm!("push rbp" :::: "volatile", "intel");
-
-    asm!("" : "={rax}"(CUR_REGISTERS.rax), "={rbx}"(CUR_REGISTERS.rbx), "={rcx}"(CUR_REGISTERS.rcx),
-         "={rdx}"(CUR_REGISTERS.rdx), "={rsi}"(CUR_REGISTERS.rsi),
-         "={rdi}"(CUR_REGISTERS.rdi), "={r8}"(CUR_REGISTERS.r8), "={r9}"(CUR_REGISTERS.r9),
-         "={r10}"(CUR_REGISTERS.r10), "={r11}"(CUR_REGISTERS.r11), "={r12}"(CUR_REGISTERS.r12),
-         "={r13}"(CUR_REGISTERS.r13), "={r14}"(CUR_REGISTERS.r14), "={r15}"(CUR_REGISTERS.r15)
-         ::: "volatile", "intel");
-
-    asm!("pop r9" : "={r9}"(CUR_REGISTERS.rbp) ::: "volatile", "intel");
+    asm!("push r15
+          push r14
+          push r13
+          push r12
+          push r11
+          push r10
+          push r9
+          push r8
+          push rdi
+          push rsi
+          push rbp
+          push rdx
+          push rcx
+          push rbx
+          push rax
+          mov rdi, rsp
+          call $0
+          add rsp, 120"
+         :: "i"(::arch::interrupt::switch::store_usermode_registers as unsafe extern "C" fn(*const ::arch::interrupt::switch::Registers))
+         :: "volatile", "intel");
 }
 
 macro_rules! return_to_raw_fn {
@@ -218,15 +351,16 @@ macro_rules! return_error_to_raw_fn {
 
 pub fn last_exception_return_value() -> Option<ExceptionInfo> {
     unsafe {
-        CUR_EXCEPTION_STACK_FRAME.clone().map(|exp| {
+        let state = cur_state();
+        state.exception_stack_frame.clone().map(|exp| {
             ExceptionInfo {
                 instruction_pointer: exp.instruction_pointer,
                 code_segment: exp.code_segment,
                 cpu_flags: exp.cpu_flags,
                 stack_pointer: exp.stack_pointer,
                 stack_segment: exp.stack_segment,
-                error_code: CUR_EXCEPTION_ERROR_CODE,
-                exception_code: CUR_EXCEPTION_CODE.unwrap()
+                error_code: state.exception_error_code,
+                exception_code: state.exception_code.unwrap()
             }
         })
     }
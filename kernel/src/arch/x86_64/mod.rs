@@ -17,10 +17,26 @@ mod interrupt;
 /// Segment descriptor and task state segment representation.
 mod segmentation;
 
+/// Processor indexes, for multiprocessor support.
+mod smp;
+
+/// Frame pointer backtraces and kernel symbol lookup.
+mod backtrace;
+
 /// Architecture-specific capabilities. Re-exported also in `kernel::cap`.
 pub mod cap;
 const KERNEL_BASE: u64 = 0xFFFFFFFF80000000;
 
+/// End of the user address space, which is the lower canonical half.
+/// Tasks may only map pages below it, and never reach the kernel
+/// mapping at `KERNEL_BASE`.
+pub const USER_END: u64 = 0x0000800000000000;
+
+/// Whether `vaddr` is in the user address space.
+pub fn is_user_vaddr(vaddr: VAddr) -> bool {
+    vaddr.into(): u64 < USER_END
+}
+
 extern {
     static kernel_end: u64;
 }
@@ -69,11 +85,51 @@ pub fn enable_timer() {
     interrupt::LOCAL_APIC.lock().enable_timer();
 }
 
+/// Route an IRQ line to its interrupt vector on the current
+/// processor, and unmask it.
+pub fn enable_irq(irq: u8) {
+    let local_apic_id = interrupt::local_apic_id();
+    interrupt::IO_APIC.lock().set_irq(irq, local_apic_id,
+                                      interrupt::IRQ_INTERRUPT_CODE_BASE + irq as u64);
+}
+
+/// Mask an IRQ line.
+pub fn mask_irq(irq: u8) {
+    interrupt::IO_APIC.lock().mask_irq(irq);
+}
+
+/// Unmask an IRQ line.
+pub fn unmask_irq(irq: u8) {
+    interrupt::IO_APIC.lock().unmask_irq(irq);
+}
+
+/// Enable the receive interrupt of the serial port, and route its IRQ
+/// line to the current processor.
+pub fn enable_serial_input() {
+    unsafe { debug::enable_receive(); }
+    enable_irq(interrupt::SERIAL_IRQ);
+}
+
+/// Read a byte received on the serial port, if there is one.
+pub fn serial_read() -> Option<u8> {
+    unsafe { debug::getb() }
+}
+
+/// Halt the current processor forever, with interrupts disabled.
+pub fn halt() -> ! {
+    loop {
+        unsafe { asm!("cli; hlt" :::: "volatile"); }
+    }
+}
+
 // Public interfaces
 pub use self::paging::{MemoryObject};
 pub use self::interrupt::{enable_interrupt, disable_interrupt, set_interrupt_handler,
-                          Exception, TaskRuntime};
-pub use self::init::{InitInfo};
+                          Exception, TaskRuntime, IRQ_COUNT, KEYBOARD_IRQ, SERIAL_IRQ};
+pub use self::init::{InitInfo, start_application_processors};
+pub use self::smp::{cpu_index, cpu_count, send_reschedule, halt_others, MAX_CPU_COUNT};
+pub use self::backtrace::{backtrace, symbol};
+pub use self::debug::{exit_qemu};
 // pub use self::cap::{ArchCap, PageHalf, PageFull};
 pub use self::addr::{PAddr, VAddr};
 
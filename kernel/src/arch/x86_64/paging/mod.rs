@@ -10,6 +10,10 @@ mod table;
 /// Memory objects implementation.
 mod with;
 
+/// Process-context identifiers (PCID), tagging TLB entries by
+/// address space.
+pub mod pcid;
+
 /// Basic page length in x86_64 (4 KiB).
 pub const BASE_PAGE_LENGTH: usize = 4096; // 4 KiB
 
@@ -0,0 +1,108 @@
+use core::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
+use common::{PAddr};
+use util::{Mutex};
+use super::{ADDRESS_MASK};
+
+/// Process-context identifier. TLB entries are tagged with the PCID
+/// of the address space they are loaded from, so they survive CR3
+/// reloads.
+pub type PCID = u16;
+
+/// Number of PCIDs supported by x86_64. PCID 0 is not allocated, and
+/// is used by the kernel and page tables without a PCID.
+pub const PCID_COUNT: usize = 4096;
+
+/// PCID-enable bit in CR4.
+const CR4_PCIDE: usize = 1 << 17;
+
+/// PCID support bit in CPUID.01H:ECX.
+const CPUID_ECX_PCID: u32 = 1 << 17;
+
+/// If set when writing CR3, TLB entries of the new PCID are kept.
+const CR3_NO_FLUSH: u64 = 1 << 63;
+
+/// Whether PCID is enabled.
+static PCID_ENABLED: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// Allocated PCIDs, one bit for each.
+static PCID_USED: Mutex<[u64; PCID_COUNT / 64]> = Mutex::new([0; PCID_COUNT / 64]);
+
+/// Enable PCID on the current processor if it supports it. Kernel
+/// and object pool pages are global (CR4.PGE is set at boot), so they
+/// are not tagged with any PCID.
+///
+/// # Safety
+///
+/// CR3 must not have any PCID bits set. Must only be called once on
+/// each processor in kernel initialization.
+pub unsafe fn init() {
+    use x86::shared::control_regs::{cr4, cr4_write};
+
+    let ecx: u32;
+    asm!("cpuid" : "={ecx}"(ecx) : "{eax}"(1u32) : "ebx", "edx" : "volatile");
+
+    if ecx & CPUID_ECX_PCID != 0 {
+        cr4_write(cr4() | CR4_PCIDE);
+        PCID_ENABLED.store(true, Ordering::SeqCst);
+        log!("PCID enabled");
+    } else {
+        log!("PCID not supported, the TLB is flushed on every address space switch");
+    }
+}
+
+/// Whether PCID is enabled.
+pub fn is_enabled() -> bool {
+    PCID_ENABLED.load(Ordering::SeqCst)
+}
+
+/// Allocate a PCID. `None` is returned if PCID is not enabled, or if
+/// all PCIDs are in use. A PCID may have been used before, so TLB
+/// entries tagged with it must be flushed the first time it is
+/// switched to on each processor.
+pub fn allocate() -> Option<PCID> {
+    if !is_enabled() {
+        return None;
+    }
+
+    let mut used = PCID_USED.lock();
+    for pcid in 1..PCID_COUNT {
+        if used[pcid / 64] & (1 << (pcid % 64)) == 0 {
+            used[pcid / 64] |= 1 << (pcid % 64);
+            return Some(pcid as PCID);
+        }
+    }
+
+    warn!("all PCIDs are in use, the address space is not tagged");
+    None
+}
+
+/// Free a PCID allocated with `allocate`, so that it can be reused.
+pub fn free(pcid: PCID) {
+    let pcid = pcid as usize;
+    PCID_USED.lock()[pcid / 64] &= !(1 << (pcid % 64));
+}
+
+/// The physical address of the current top-level page table.
+pub fn current() -> PAddr {
+    use x86::shared::control_regs::{cr3};
+
+    PAddr::from(unsafe { cr3() } as u64 & ADDRESS_MASK)
+}
+
+/// Switch to a PML4 page table tagged with `pcid`. If `flush` is
+/// false, TLB entries previously loaded with `pcid` are kept.
+///
+/// # Safety
+///
+/// PCID must be enabled. The PML4 page table must have kernel mapped
+/// in `KERNEL_BASE`. `paddr` must point to a valid PML4 page table.
+pub unsafe fn switch_to(paddr: PAddr, pcid: PCID, flush: bool) {
+    use x86::shared::control_regs::{cr3_write};
+
+    let mut value = (paddr.into(): u64) | (pcid as u64);
+    if !flush {
+        value |= CR3_NO_FLUSH;
+    }
+
+    cr3_write(value as usize);
+}
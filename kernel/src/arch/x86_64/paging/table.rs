@@ -158,6 +158,8 @@ impl PDPTEntry {
                 is_page_level_cache_disabled, PDPT_PCD);
     check_flag!(doc = "Accessed; indicates whether this entry has been used for linear-address translation.",
                 is_accessed, PDPT_A);
+    check_flag!(doc = "Page size; if set this entry maps a 1-GByte page; otherwise, this entry references a page directory.",
+                is_page, PDPT_PS);
     check_flag!(doc = "Indirectly determines the memory type used to access the 1-GByte page referenced by this entry. if not PDPT_PS this is ignored.",
                 is_pat, PDPT_PAT);
     check_flag!(doc = "If IA32_EFER.NXE = 1, execute-disable. If 1, instruction fetches are not allowed from the 512-GByte region.",
@@ -257,6 +259,16 @@ bitflags! {
         const PT_D       = bit!(6),
         /// Global; if CR4.PGE = 1, determines whether the translation is global (see Section 4.10); ignored otherwise
         const PT_G       = bit!(8),
+        /// Ignored by the processor. Used by the kernel to mark a read-only page
+        /// shared copy-on-write.
+        const PT_COW     = bit!(9),
+        /// Ignored by the processor. Used by the kernel in a non-present
+        /// entry to mark a guard page below a stack. The address field of
+        /// the entry holds the length of the stack.
+        const PT_GUARD   = bit!(10),
+        /// Ignored by the processor. Used by the kernel to mark a page
+        /// holding plain memory, which may be shared copy-on-write.
+        const PT_RAW     = bit!(11),
         /// If IA32_EFER.NXE = 1, execute-disable
         /// If 1, instruction fetches are not allowed from the 512-GByte region.
         const PT_XD      = bit!(63),
@@ -297,6 +309,12 @@ impl PTEntry {
                 is_dirty, PT_D);
     check_flag!(doc = "Global; if PT_PS && CR4.PGE = 1, determines whether the translation is global; ignored otherwise if not PT_PS this is ignored.",
                 is_global, PT_G);
+    check_flag!(doc = "Is the page shared copy-on-write?",
+                is_copy_on_write, PT_COW);
+    check_flag!(doc = "Is the page a stack guard page?",
+                is_guard, PT_GUARD);
+    check_flag!(doc = "Does the page hold plain memory that can be shared?",
+                is_raw, PT_RAW);
     check_flag!(doc = "If IA32_EFER.NXE = 1, execute-disable. If 1, instruction fetches are not allowed from the 4-KByte region.",
                 is_instruction_fetching_disabled, PT_XD);
 }
@@ -4,7 +4,7 @@ use core::mem::{size_of};
 use core::slice;
 use util::{align_down, block_count};
 use core::ptr::{Unique};
-use super::{PTEntry, PT_P, PT_RW, flush, BASE_PAGE_LENGTH};
+use super::{PTEntry, PT_P, PT_RW, PT_G, flush, BASE_PAGE_LENGTH};
 use arch::init::{OBJECT_POOL_PT, OBJECT_POOL_START_VADDR};
 use common::{PAddr, VAddr};
 
@@ -20,7 +20,8 @@ use core::fmt;
 /// The struct is implemented using `ObjectPool`. When a new memory
 /// object is created, a new entry on the `ObjectPool` PT is created,
 /// thus makes it addressable. The entry is deleted once the memory
-/// object struct is dropped.
+/// object struct is dropped. Entries are global, so that `invlpg`
+/// invalidates them for every PCID when a window is reused.
 ///
 /// # Safety
 ///
@@ -90,7 +91,7 @@ impl<T: ?Sized> MemoryObject<T> {
 
 
         for i in 0..required_page_size {
-            object_pool[mapping_start_index + i] = PTEntry::new(aligned + (i * BASE_PAGE_LENGTH), PT_P | PT_RW);
+            object_pool[mapping_start_index + i] = PTEntry::new(aligned + (i * BASE_PAGE_LENGTH), PT_P | PT_RW | PT_G);
             unsafe { flush(OBJECT_POOL_START_VADDR + (mapping_start_index * BASE_PAGE_LENGTH) + i * BASE_PAGE_LENGTH); }
         }
 
@@ -5,6 +5,11 @@ use core::fmt;
 
 pub use self::tss::{TaskStateSegment};
 
+/// GDT index of the TSS descriptor of the boot processor. Each
+/// processor's descriptor takes two entries after it, in processor
+/// index order.
+pub const TSS_GDT_INDEX: usize = 6;
+
 bitflags! {
     /// Specifies which element to load into a segment from
     /// descriptor tables (i.e., is a index to LDT or GDT table
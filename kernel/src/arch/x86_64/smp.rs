@@ -0,0 +1,116 @@
+use core::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use common::{PAddr};
+use super::segmentation::{TSS_GDT_INDEX};
+use super::interrupt::{local_apic_id, LOCAL_APIC, RESCHEDULE_INTERRUPT_CODE,
+                       TLB_SHOOTDOWN_INTERRUPT_CODE};
+
+/// Maximum number of processors supported. Additional application
+/// processors are left halted.
+pub const MAX_CPU_COUNT: usize = 8;
+
+/// APIC ids of registered processors, indexed by processor index and
+/// offset by one. Zero marks an unused index.
+static CPU_APIC_IDS: [AtomicUsize; MAX_CPU_COUNT] = [
+    ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT,
+    ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT,
+];
+
+/// Physical address of the top-level page table of the task each
+/// processor is running, indexed by processor index. Zero if the
+/// processor is running in the kernel or idling.
+static RUNNING_ADDRESS_SPACES: [AtomicUsize; MAX_CPU_COUNT] = [
+    ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT,
+    ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT,
+];
+
+/// Number of registered processors.
+static CPU_COUNT: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Register the current processor with `index`. The boot processor
+/// has index 0, and application processors are numbered from 1 in
+/// the order they start.
+pub fn register(index: usize) {
+    assert!(index < MAX_CPU_COUNT);
+    let apic_id = local_apic_id();
+
+    let previous = CPU_APIC_IDS[index].swap(apic_id as usize + 1, Ordering::SeqCst);
+    assert!(previous == 0);
+    CPU_COUNT.fetch_add(1, Ordering::SeqCst);
+
+    debug!("processor {} registered with APIC id {}", index, apic_id);
+}
+
+/// Index of the current processor. Each processor loads the TSS
+/// descriptor at its own GDT entry, so the index is derived from the
+/// task register without touching memory. Only valid once the
+/// processor has initialized its segmentation.
+pub fn cpu_index() -> usize {
+    let selector: u16;
+    unsafe { asm!("str $0" : "=r"(selector)); }
+
+    let gdt_index = (selector >> 3) as usize;
+    debug_assert!(gdt_index >= TSS_GDT_INDEX);
+    (gdt_index - TSS_GDT_INDEX) / 2
+}
+
+/// Number of registered processors.
+pub fn cpu_count() -> usize {
+    CPU_COUNT.load(Ordering::SeqCst)
+}
+
+/// APIC id of the processor with `index`, if it is registered.
+fn cpu_apic_id(index: usize) -> Option<u8> {
+    match CPU_APIC_IDS[index].load(Ordering::SeqCst) {
+        0 => None,
+        apic_id => Some((apic_id - 1) as u8),
+    }
+}
+
+/// Send a reschedule IPI to processor `cpu`. The task it is running,
+/// or its idle loop, is interrupted, and it picks its next task
+/// again.
+pub fn send_reschedule(cpu: usize) {
+    if let Some(apic_id) = cpu_apic_id(cpu) {
+        LOCAL_APIC.lock().send_ipi_to(apic_id, RESCHEDULE_INTERRUPT_CODE);
+    }
+}
+
+/// Halt all other processors with a non-maskable interrupt, which is
+/// delivered even in the kernel. Used on a panic. Nothing is sent if
+/// the local APIC is locked.
+pub fn halt_others() {
+    if let Some(mut local_apic) = LOCAL_APIC.try_lock() {
+        local_apic.send_nmi_all();
+    }
+}
+
+/// Record the address space the current processor is about to run a
+/// task in, or `None` when it has left it.
+pub fn set_running_address_space(paddr: Option<PAddr>) {
+    let value = paddr.map_or(0, |paddr| paddr.into(): usize);
+    RUNNING_ADDRESS_SPACES[cpu_index()].store(value, Ordering::SeqCst);
+}
+
+/// Invalidate TLB entries of the address space at `paddr` on all
+/// other processors. Processors running a task in the address space
+/// are sent a TLB shootdown IPI, and this waits until they have left
+/// it. They flush the TLB the next time they switch to it.
+///
+/// The page table must be locked, so that no processor can enter the
+/// address space in the meantime.
+pub fn shootdown_tlb(paddr: PAddr) {
+    let value = paddr.into(): usize;
+    let current = cpu_index();
+
+    for cpu in 0..MAX_CPU_COUNT {
+        if cpu == current || RUNNING_ADDRESS_SPACES[cpu].load(Ordering::SeqCst) != value {
+            continue;
+        }
+
+        if let Some(apic_id) = cpu_apic_id(cpu) {
+            LOCAL_APIC.lock().send_ipi_to(apic_id, TLB_SHOOTDOWN_INTERRUPT_CODE);
+        }
+
+        while RUNNING_ADDRESS_SPACES[cpu].load(Ordering::SeqCst) == value { }
+    }
+}
@@ -2,13 +2,15 @@ use common::*;
 use core::any::{Any, TypeId};
 use util::{RwLock, align_up};
 use util::managed_arc::{ManagedArc, ManagedArcAny, ManagedWeakPool3Arc};
+use abi::{SystemCall};
 
-use super::{UntypedDescriptor};
+use super::{UntypedDescriptor, TaskCap, TaskStatus};
 
 /// Channel descriptor.
 #[derive(Debug)]
 pub struct ChannelDescriptor {
     value: Option<u64>,
+    waiters: Option<TaskCap>,
     next: Option<ManagedArcAny>,
 }
 /// Channel capability. Reference-counted smart pointer to channel
@@ -27,6 +29,7 @@ impl ChannelCap {
             arc = Some(unsafe {
                 Self::new(paddr, RwLock::new(ChannelDescriptor {
                     value: None,
+                    waiters: None,
                     next: next_child,
                 }))
             });
@@ -36,17 +39,164 @@ impl ChannelCap {
 
         arc.unwrap()
     }
+
+    /// Put a value to the channel. If tasks are waiting on the
+    /// channel, the first of them takes the value and is queued to
+    /// run. Otherwise the value is kept until a task takes it,
+    /// replacing any value not taken yet.
+    ///
+    /// If the first waiting task is passive, the scheduling context of
+    /// `donor`, the task putting the value, is donated along with it.
+    /// The donor then waits until the donation ends, so that the
+    /// context is never used by two tasks at once. Without a context
+    /// to donate, the value is kept for a task that is not passive.
+    pub fn put(&self, value: u64, donor: Option<&TaskCap>) {
+        let sched_context = donor.and_then(|donor| donor.read().sched_context());
+        let mut chan = self.write();
+
+        loop {
+            let passive = match chan.waiters {
+                Some(ref waiter) => waiter.read().is_passive(),
+                None => break,
+            };
+            if passive && sched_context.is_none() {
+                break;
+            }
+
+            let waiter = chan.pop_waiter().unwrap();
+            let delivered = deliver(&waiter, value);
+            if delivered && passive {
+                let donor = donor.unwrap();
+                {
+                    let mut task = waiter.write();
+                    task.set_donated_sched_context(sched_context);
+                    task.set_donor(Some(donor.clone()));
+                }
+                donor.write().set_status(TaskStatus::DonationWait);
+            }
+            ::sched::enqueue(waiter);
+            if delivered {
+                return;
+            }
+        }
+
+        chan.put(value);
+    }
+
+    /// Take a value from the channel for the `ChannelTake` system
+    /// call of a task. If the channel has no value, `None` is
+    /// returned, and the task waits on the channel until a value is
+    /// put. A passive task always waits, as it only runs on a
+    /// scheduling context donated with a value.
+    pub fn take_or_wait(&self, task_cap: &TaskCap) -> Option<u64> {
+        let passive = task_cap.read().is_passive();
+        let mut chan = self.write();
+
+        let value = if passive { None } else { chan.take() };
+        if value.is_none() {
+            chan.push_waiter(task_cap.clone());
+            task_cap.write().set_status(TaskStatus::ChannelWait(self.clone()));
+        }
+        value
+    }
+
+    /// Stop a task from waiting on the channel. Nothing happens if
+    /// the task is not waiting on it.
+    pub fn cancel_wait(&self, task_cap: &TaskCap) {
+        let mut chan = self.write();
+
+        let is_first = chan.waiters.as_ref().map_or(false, |first| first.ptr_eq(task_cap));
+        if is_first {
+            chan.waiters = task_cap.write().set_next_waiting(None);
+            return;
+        }
+
+        let mut next = chan.waiters.clone();
+        while let Some(current) = next {
+            let mut current_task = current.write();
+
+            let is_prev = current_task.next_waiting().map_or(false, |next| next.ptr_eq(task_cap));
+            if is_prev {
+                let after = task_cap.write().set_next_waiting(None);
+                current_task.set_next_waiting(after);
+                return;
+            }
+
+            next = current_task.next_waiting();
+        }
+    }
+}
+
+/// Finish the `ChannelTake` system call of a task waiting on a
+/// channel with `value`, and make the task active. If the task buffer
+/// no longer holds a `ChannelTake`, the wait is abandoned and `false`
+/// is returned.
+fn deliver(waiter: &TaskCap, value: u64) -> bool {
+    let buffer = waiter.read().upgrade_buffer();
+    let call = buffer.as_ref().map(|buffer| buffer.read().read().read_call());
+
+    let delivered = match (buffer, call) {
+        (Some(buffer), Some(Ok(SystemCall::ChannelTake { request, response: _ }))) => {
+            buffer.write().write().write_call(&SystemCall::ChannelTake {
+                request: request,
+                response: Some(value),
+            });
+            true
+        },
+        _ => {
+            warn!("Task {} buffer changed while waiting on a channel", waiter.display_name());
+            false
+        },
+    };
+
+    waiter.write().set_status(TaskStatus::Active);
+    delivered
 }
 
 impl ChannelDescriptor {
     /// Put a value to the channel.
-    pub fn put(&mut self, value: u64) {
+    fn put(&mut self, value: u64) {
         self.value = Some(value);
     }
 
     /// Take a value from the channel. If there's no value in the
     /// channel, `None` is returned.
-    pub fn take(&mut self) -> Option<u64> {
+    fn take(&mut self) -> Option<u64> {
         self.value.take()
     }
+
+    /// Whether the channel holds a value.
+    pub fn has_value(&self) -> bool {
+        self.value.is_some()
+    }
+
+    /// Add a task to the end of the tasks waiting on the channel,
+    /// linked through the `next_waiting` field in their descriptor.
+    fn push_waiter(&mut self, task_cap: TaskCap) {
+        let mut last = match self.waiters.clone() {
+            Some(first) => first,
+            None => {
+                self.waiters = Some(task_cap);
+                return;
+            },
+        };
+
+        loop {
+            let next = last.read().next_waiting();
+            match next {
+                Some(next) => last = next,
+                None => break,
+            }
+        }
+        last.write().set_next_waiting(Some(task_cap));
+    }
+
+    /// Take the first task waiting on the channel.
+    fn pop_waiter(&mut self) -> Option<TaskCap> {
+        let first = self.waiters.take();
+        if let Some(ref first) = first {
+            self.waiters = first.write().set_next_waiting(None);
+        }
+        first
+    }
 }
@@ -0,0 +1,172 @@
+use common::*;
+use core::iter::{Iterator};
+use util::{RwLock, Mutex};
+use util::managed_arc::{ManagedArc, ManagedArcAny, ManagedWeakPool1Arc};
+use arch::{self, IRQ_COUNT, SERIAL_IRQ};
+
+use super::{UntypedDescriptor, ChannelCap};
+
+/// IRQ handler descriptor.
+#[derive(Debug)]
+pub struct IRQHandlerDescriptor {
+    weak_pool: ManagedWeakPool1Arc,
+    irq: u8,
+    masked: bool,
+    next: Option<ManagedArcAny>,
+    next_handler: Option<IRQHandlerCap>,
+}
+/// IRQ handler capability. Reference-counted smart pointer to IRQ
+/// handler descriptor.
+///
+/// An IRQ handler capability owns one IRQ line. When the interrupt
+/// fires, the line is masked and the IRQ number is put to the bound
+/// channel. The line is unmasked only after the userspace driver
+/// acknowledges it.
+pub type IRQHandlerCap = ManagedArc<RwLock<IRQHandlerDescriptor>>;
+
+impl IRQHandlerCap {
+    /// Create an IRQ handler capability for `irq` from an untyped
+    /// capability, and route the IRQ line to the kernel. `None` is
+    /// returned if `irq` is out of range, already has a handler that
+    /// is not deleted, or is the serial port's, which the kernel
+    /// handles itself.
+    pub fn retype_from(untyped: &mut UntypedDescriptor, irq: u8) -> Option<Self> {
+        if irq as usize >= IRQ_COUNT || irq == SERIAL_IRQ || irq_handler(irq).is_some() {
+            return None;
+        }
+
+        let mut arc: Option<Self> = None;
+
+        let weak_pool = unsafe { ManagedWeakPool1Arc::create(
+            untyped.allocate(ManagedWeakPool1Arc::inner_length(),
+                             ManagedWeakPool1Arc::inner_alignment())) };
+
+        unsafe { untyped.derive(Self::inner_length(), Self::inner_alignment(), |paddr, next_child| {
+            arc = Some(unsafe {
+                Self::new(paddr, RwLock::new(IRQHandlerDescriptor {
+                    weak_pool: weak_pool,
+                    irq: irq,
+                    masked: false,
+                    next: next_child,
+                    next_handler: None,
+                }))
+            });
+
+            arc.clone().unwrap().into()
+        }) };
+
+        register_irq_handler(arc.clone().unwrap());
+        arch::enable_irq(irq);
+
+        arc
+    }
+}
+
+impl IRQHandlerCap {
+    /// Delete the IRQ handler. The IRQ line is masked, the handler is
+    /// removed from the handler list so that the line can be retyped
+    /// again, and its channel reference is released. A later `ack` on
+    /// the deleted handler does nothing.
+    pub fn delete(&self) {
+        unregister_irq_handler(self);
+
+        let mut handler = self.write();
+        arch::mask_irq(handler.irq);
+        handler.masked = false;
+        handler.weak_pool.read().remove_at(0);
+    }
+}
+
+impl IRQHandlerDescriptor {
+    /// The IRQ line handled.
+    pub fn irq(&self) -> u8 {
+        self.irq
+    }
+
+    /// Set the channel notified when the interrupt fires.
+    pub fn downgrade_channel(&self, channel: &ChannelCap) {
+        self.weak_pool.read().downgrade_at(channel, 0)
+    }
+
+    /// Read from the channel notified when the interrupt fires.
+    pub fn upgrade_channel(&self) -> Option<ChannelCap> {
+        self.weak_pool.read().upgrade(0)
+    }
+
+    /// Handle the interrupt. Mask the IRQ line and notify the bound
+    /// channel.
+    pub fn notify(&mut self) {
+        arch::mask_irq(self.irq);
+        self.masked = true;
+
+        if let Some(channel) = self.upgrade_channel() {
+            channel.put(self.irq as u64, None);
+        }
+    }
+
+    /// Acknowledge the interrupt, unmasking the IRQ line.
+    pub fn ack(&mut self) {
+        if self.masked {
+            self.masked = false;
+            arch::unmask_irq(self.irq);
+        }
+    }
+}
+
+/// The first IRQ handler created.
+static FIRST_IRQ_HANDLER: Mutex<Option<IRQHandlerCap>> = Mutex::new(None);
+
+/// Register a new IRQ handler. Like tasks, this forms a linked-list
+/// from `FIRST_IRQ_HANDLER`.
+fn register_irq_handler(cap: IRQHandlerCap) {
+    let mut first_handler = FIRST_IRQ_HANDLER.lock();
+    if first_handler.is_none() {
+        *first_handler = Some(cap);
+    } else {
+        let mut first = first_handler.as_mut().unwrap().write();
+        let mut second = cap.write();
+        let third_handler = first.next_handler.take();
+
+        second.next_handler = third_handler;
+        first.next_handler = Some(cap.clone());
+    }
+}
+
+/// Remove an IRQ handler from the linked-list formed from
+/// `FIRST_IRQ_HANDLER`, releasing the list's reference to it.
+fn unregister_irq_handler(cap: &IRQHandlerCap) {
+    let mut first_handler = FIRST_IRQ_HANDLER.lock();
+
+    let is_first = first_handler.as_ref().map_or(false, |first| first.ptr_eq(cap));
+    if is_first {
+        *first_handler = cap.write().next_handler.take();
+        return;
+    }
+
+    let mut next = first_handler.clone();
+    while let Some(current) = next {
+        let mut current_handler = current.write();
+
+        let is_prev = current_handler.next_handler.as_ref().map_or(false, |next| next.ptr_eq(cap));
+        if is_prev {
+            current_handler.next_handler = cap.write().next_handler.take();
+            return;
+        }
+
+        next = current_handler.next_handler.clone();
+    }
+}
+
+/// Find the IRQ handler registered for `irq`.
+pub fn irq_handler(irq: u8) -> Option<IRQHandlerCap> {
+    let mut next = FIRST_IRQ_HANDLER.lock().clone();
+
+    while let Some(current) = next {
+        if current.read().irq() == irq {
+            return Some(current);
+        }
+        next = current.read().next_handler.clone();
+    }
+
+    None
+}
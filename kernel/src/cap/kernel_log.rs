@@ -0,0 +1,35 @@
+use util::{RwLock};
+use util::managed_arc::{ManagedArc, ManagedArcAny};
+
+use super::{UntypedDescriptor};
+
+/// Kernel log descriptor.
+#[derive(Debug)]
+pub struct KernelLogDescriptor {
+    next: Option<ManagedArcAny>,
+}
+/// Kernel log capability. Reference-counted smart pointer to kernel
+/// log descriptor.
+///
+/// A kernel log capability grants reading the in-memory kernel log
+/// ring. The kernel creates one for rinit at boot.
+pub type KernelLogCap = ManagedArc<RwLock<KernelLogDescriptor>>;
+
+impl KernelLogCap {
+    /// Create a kernel log capability from an untyped capability.
+    pub fn retype_from(untyped: &mut UntypedDescriptor) -> Self {
+        let mut arc: Option<Self> = None;
+
+        unsafe { untyped.derive(Self::inner_length(), Self::inner_alignment(), |paddr, next_child| {
+            arc = Some(unsafe {
+                Self::new(paddr, RwLock::new(KernelLogDescriptor {
+                    next: next_child,
+                }))
+            });
+
+            arc.clone().unwrap().into()
+        }) };
+
+        arc.unwrap()
+    }
+}
@@ -6,11 +6,20 @@ mod cpool;
 mod task;
 /// Channel capability implementation.
 mod channel;
+/// IRQ handler capability implementation.
+mod irq;
+/// Scheduling context capability implementation.
+mod sched_context;
+/// Kernel log capability implementation.
+mod kernel_log;
 
 pub use self::untyped::{UntypedDescriptor, UntypedCap};
 pub use self::cpool::{CPoolDescriptor, CPoolCap};
-pub use self::task::{TaskDescriptor, TaskCap, TaskStatus, idle, task_iter};
+pub use self::task::{TaskDescriptor, TaskCap, TaskName, TaskStatus, idle, task_iter};
 pub use self::channel::{ChannelDescriptor, ChannelCap};
+pub use self::irq::{IRQHandlerDescriptor, IRQHandlerCap, irq_handler};
+pub use self::sched_context::{SchedContextDescriptor, SchedContextCap};
+pub use self::kernel_log::{KernelLogDescriptor, KernelLogCap};
 pub use arch::cap::{TopPageTableCap, PageCap, PAGE_LENGTH};
 
 use arch;
@@ -57,6 +66,12 @@ pub unsafe fn upgrade_any(ptr: PAddr, type_id: TypeId) -> Option<ManagedArcAny>
         Some(unsafe { ManagedArc::from_ptr(ptr): TaskBufferPageCap }.into())
     } else if type_id == TypeId::of::<ChannelCap>() {
         Some(unsafe { ManagedArc::from_ptr(ptr): ChannelCap }.into())
+    } else if type_id == TypeId::of::<IRQHandlerCap>() {
+        Some(unsafe { ManagedArc::from_ptr(ptr): IRQHandlerCap }.into())
+    } else if type_id == TypeId::of::<SchedContextCap>() {
+        Some(unsafe { ManagedArc::from_ptr(ptr): SchedContextCap }.into())
+    } else if type_id == TypeId::of::<KernelLogCap>() {
+        Some(unsafe { ManagedArc::from_ptr(ptr): KernelLogCap }.into())
     } else {
         arch::cap::upgrade_any(ptr, type_id)
     }
@@ -78,6 +93,12 @@ pub fn drop_any(any: ManagedArcAny) {
         any.into(): TaskBufferPageCap;
     } else if any.is::<ChannelCap>() {
         any.into(): ChannelCap;
+    } else if any.is::<IRQHandlerCap>() {
+        any.into(): IRQHandlerCap;
+    } else if any.is::<SchedContextCap>() {
+        any.into(): SchedContextCap;
+    } else if any.is::<KernelLogCap>() {
+        any.into(): KernelLogCap;
     } else {
         arch::cap::drop_any(any);
     }
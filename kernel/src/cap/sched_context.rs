@@ -0,0 +1,66 @@
+use common::*;
+use util::{RwLock};
+use util::managed_arc::{ManagedArc, ManagedArcAny};
+use sched::{TimeAccount, DEFAULT_PRIORITY, PRIORITY_COUNT};
+
+use super::{UntypedDescriptor};
+
+/// Scheduling context descriptor.
+#[derive(Debug)]
+pub struct SchedContextDescriptor {
+    priority: u8,
+    time: TimeAccount,
+    next: Option<ManagedArcAny>,
+}
+/// Scheduling context capability. Reference-counted smart pointer to
+/// scheduling context descriptor.
+///
+/// A scheduling context carries the priority, budget and period a
+/// task is scheduled with. It is bound to a task, and can be donated
+/// through a channel to a passive task serving the request.
+pub type SchedContextCap = ManagedArc<RwLock<SchedContextDescriptor>>;
+
+impl SchedContextCap {
+    /// Create a scheduling context capability from an untyped
+    /// capability. It has the default priority and no budget.
+    pub fn retype_from(untyped: &mut UntypedDescriptor) -> Self {
+        let mut arc: Option<Self> = None;
+
+        unsafe { untyped.derive(Self::inner_length(), Self::inner_alignment(), |paddr, next_child| {
+            arc = Some(unsafe {
+                Self::new(paddr, RwLock::new(SchedContextDescriptor {
+                    priority: DEFAULT_PRIORITY,
+                    time: TimeAccount::new(),
+                    next: next_child,
+                }))
+            });
+
+            arc.clone().unwrap().into()
+        }) };
+
+        arc.unwrap()
+    }
+}
+
+impl SchedContextDescriptor {
+    /// Scheduling priority. Higher values run first.
+    pub fn priority(&self) -> u8 {
+        self.priority
+    }
+
+    /// Set the scheduling priority.
+    pub fn set_priority(&mut self, priority: u8) {
+        assert!((priority as usize) < PRIORITY_COUNT);
+        self.priority = priority;
+    }
+
+    /// CPU time accounting of the scheduling context.
+    pub fn time(&self) -> &TimeAccount {
+        &self.time
+    }
+
+    /// Mutable CPU time accounting of the scheduling context.
+    pub fn time_mut(&mut self) -> &mut TimeAccount {
+        &mut self.time
+    }
+}
@@ -1,11 +1,16 @@
 use common::*;
 use core::any::{Any, TypeId};
 use core::iter::{Iterator};
-use util::{RwLock, align_up, Mutex};
-use util::managed_arc::{ManagedArc, ManagedArcAny, ManagedWeakPool3Arc};
+use core::mem;
+use core::fmt;
+use util::{RwLock, align_up, Mutex, LossyStr};
+use util::managed_arc::{ManagedArc, ManagedArcAny, ManagedWeakPool8Arc};
 use arch::{TaskRuntime, Exception};
+use abi::{TaskRegisters, TASK_NAME_LENGTH};
+use sched::{TimeAccount, DEFAULT_PRIORITY, PRIORITY_COUNT};
 
-use super::{UntypedDescriptor, TopPageTableCap, CPoolCap, TaskBufferPageCap, ChannelCap};
+use super::{UntypedDescriptor, UntypedCap, TopPageTableCap, CPoolCap, TaskBufferPageCap, ChannelCap,
+            SchedContextCap};
 
 /// Switch to an idle task that runs in kernel-mode. This is used when
 /// no other tasks is runnable. Like normal context switching, this
@@ -30,16 +35,36 @@ pub fn idle() -> Exception {
 pub enum TaskStatus {
     Active,
     ChannelWait(ChannelCap),
+    DonationWait,
+    Sleep(u64),
     Inactive,
+    Dead,
 }
 
+/// Number of slots in the weak pool of a task.
+const TASK_WEAK_POOL_SIZE: usize = 8;
+
 /// Task descriptor.
 #[derive(Debug)]
 pub struct TaskDescriptor {
-    weak_pool: ManagedWeakPool3Arc,
+    weak_pool: ManagedWeakPool8Arc,
     runtime: TaskRuntime,
     next: Option<ManagedArcAny>,
     next_task: Option<TaskCap>,
+    next_ready: Option<TaskCap>,
+    ready: bool,
+    running: bool,
+    affinity: Option<usize>,
+    next_sleeping: Option<TaskCap>,
+    sleeping: bool,
+    next_waiting: Option<TaskCap>,
+    priority: u8,
+    time: TimeAccount,
+    passive: bool,
+    donated: Option<SchedContextCap>,
+    donor: Option<TaskCap>,
+    name: [u8; TASK_NAME_LENGTH],
+    name_length: usize,
     status: TaskStatus
 }
 /// Task capability. Reference-counted smart pointer to task
@@ -53,9 +78,9 @@ impl TaskCap {
     pub fn retype_from(untyped: &mut UntypedDescriptor) -> Self {
         let mut arc: Option<Self> = None;
 
-        let weak_pool = unsafe { ManagedWeakPool3Arc::create(
-            untyped.allocate(ManagedWeakPool3Arc::inner_length(),
-                             ManagedWeakPool3Arc::inner_alignment())) };
+        let weak_pool = unsafe { ManagedWeakPool8Arc::create(
+            untyped.allocate(ManagedWeakPool8Arc::inner_length(),
+                             ManagedWeakPool8Arc::inner_alignment())) };
 
         unsafe { untyped.derive(Self::inner_length(), Self::inner_alignment(), |paddr, next_child| {
             arc = Some(unsafe {
@@ -64,6 +89,20 @@ impl TaskCap {
                     runtime: TaskRuntime::default(),
                     next: next_child,
                     next_task: None,
+                    next_ready: None,
+                    ready: false,
+                    running: false,
+                    affinity: None,
+                    next_sleeping: None,
+                    sleeping: false,
+                    next_waiting: None,
+                    priority: DEFAULT_PRIORITY,
+                    time: TimeAccount::new(),
+                    passive: false,
+                    donated: None,
+                    donor: None,
+                    name: [0u8; TASK_NAME_LENGTH],
+                    name_length: 0,
                     status: TaskStatus::Inactive,
                 }))
             });
@@ -77,6 +116,80 @@ impl TaskCap {
     }
 }
 
+impl TaskCap {
+    /// Delete the task. It is marked dead and removed from the task
+    /// list, and its references to other capabilities are released.
+    /// The scheduler never runs a dead task again.
+    pub fn delete(&self) {
+        unregister_task(self);
+        ::sched::set_status(self, TaskStatus::Dead);
+        ::sched::end_donation(self);
+
+        let task = self.write();
+        for index in 0..TASK_WEAK_POOL_SIZE {
+            task.weak_pool.read().remove_at(index);
+        }
+    }
+
+    /// Switch to the task. The function is returned when exception
+    /// happens. The task descriptor is not locked while the task
+    /// runs, so that other processors can access it.
+    pub fn switch_to(&self) -> Exception {
+        let (pml4, mut runtime) = {
+            let task = self.read();
+            (task.upgrade_top_page_table(), task.runtime.clone())
+        };
+
+        if let Some(pml4) = pml4 {
+            pml4.switch_to();
+        }
+        let exception = unsafe { runtime.switch_to(true) };
+
+        self.write().runtime = runtime;
+        exception
+    }
+
+    /// Exit the task with `code`. The code is put to the exit channel
+    /// if the task has one, and then the task is deleted.
+    pub fn exit(&self, code: u64) {
+        let exit_channel = self.read().upgrade_exit_channel();
+        if let Some(exit_channel) = exit_channel {
+            exit_channel.put(code, None);
+        }
+
+        self.delete();
+    }
+
+    /// Display the task in kernel logs: its debug name if it has
+    /// one, and the capability otherwise.
+    pub fn display_name(&self) -> TaskName {
+        let task = self.read();
+        TaskName {
+            cap: self.clone(),
+            name: task.name,
+            name_length: task.name_length,
+        }
+    }
+}
+
+/// Debug name of a task, copied out of the task descriptor so that it
+/// can be displayed without holding the lock.
+pub struct TaskName {
+    cap: TaskCap,
+    name: [u8; TASK_NAME_LENGTH],
+    name_length: usize,
+}
+
+impl fmt::Display for TaskName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.name_length == 0 {
+            write!(f, "{:?}", self.cap)
+        } else {
+            write!(f, "\"{}\"", LossyStr(&self.name[0..self.name_length]))
+        }
+    }
+}
+
 impl TaskDescriptor {
     /// Set the task's instruction pointer.
     pub fn set_instruction_pointer(&mut self, instruction_pointer: VAddr) {
@@ -88,6 +201,22 @@ impl TaskDescriptor {
         self.runtime.set_stack_pointer(stack_pointer)
     }
 
+    /// Report the status of the task's fast system call in `rax` and
+    /// its value in `rdx`.
+    pub fn set_fast_call_status(&mut self, status: (u64, u64)) {
+        self.runtime.set_return_values(status.0, status.1)
+    }
+
+    /// Read the task's saved registers.
+    pub fn registers(&self) -> TaskRegisters {
+        self.runtime.registers()
+    }
+
+    /// Overwrite the task's saved registers.
+    pub fn set_registers(&mut self, registers: &TaskRegisters) {
+        self.runtime.set_registers(registers)
+    }
+
     /// Set the task's root capability pool.
     pub fn downgrade_cpool(&self, cpool: &CPoolCap) {
         self.weak_pool.read().downgrade_at(cpool, 0)
@@ -118,23 +247,203 @@ impl TaskDescriptor {
         self.weak_pool.read().upgrade(2)
     }
 
+    /// Set the untyped memory used to resolve the task's page faults,
+    /// such as copying copy-on-write pages.
+    pub fn downgrade_fault_untyped(&self, untyped: &UntypedCap) {
+        self.weak_pool.read().downgrade_at(untyped, 3)
+    }
+
+    /// Read from the task's fault untyped memory.
+    pub fn upgrade_fault_untyped(&self) -> Option<UntypedCap> {
+        self.weak_pool.read().upgrade(3)
+    }
+
+    /// Set the task's pager channel. Page faults that the kernel
+    /// cannot resolve are sent to this channel.
+    pub fn downgrade_pager_channel(&self, channel: &ChannelCap) {
+        self.weak_pool.read().downgrade_at(channel, 4)
+    }
+
+    /// Read from the task's pager channel.
+    pub fn upgrade_pager_channel(&self) -> Option<ChannelCap> {
+        self.weak_pool.read().upgrade(4)
+    }
+
+    /// Bind a scheduling context to the task.
+    pub fn downgrade_sched_context(&self, sched_context: &SchedContextCap) {
+        self.weak_pool.read().downgrade_at(sched_context, 5)
+    }
+
+    /// Read from the scheduling context bound to the task.
+    pub fn upgrade_sched_context(&self) -> Option<SchedContextCap> {
+        self.weak_pool.read().upgrade(5)
+    }
+
+    /// The scheduling context donated to the task through a channel.
+    pub fn donated_sched_context(&self) -> Option<SchedContextCap> {
+        self.donated.clone()
+    }
+
+    /// Set the scheduling context donated to the task, or end the
+    /// donation with `None`.
+    pub fn set_donated_sched_context(&mut self, donated: Option<SchedContextCap>) {
+        self.donated = donated;
+    }
+
+    /// Set the task that donated its scheduling context to the task,
+    /// and waits until the donation ends. Returns the previous one.
+    pub fn set_donor(&mut self, donor: Option<TaskCap>) -> Option<TaskCap> {
+        mem::replace(&mut self.donor, donor)
+    }
+
+    /// The scheduling context the task runs on. A donated context
+    /// takes precedence over the bound one. If neither exists, the
+    /// task is scheduled with its own priority and time account.
+    pub fn sched_context(&self) -> Option<SchedContextCap> {
+        self.donated_sched_context().or_else(|| self.upgrade_sched_context())
+    }
+
+    /// Whether the task is passive. A passive task only runs on a
+    /// scheduling context donated to it.
+    pub fn is_passive(&self) -> bool {
+        self.passive
+    }
+
+    /// Set whether the task is passive.
+    pub fn set_passive(&mut self, passive: bool) {
+        self.passive = passive;
+    }
+
+    /// Set the task's exit channel. The exit code is put to this
+    /// channel when the task exits.
+    pub fn downgrade_exit_channel(&self, channel: &ChannelCap) {
+        self.weak_pool.read().downgrade_at(channel, 6)
+    }
+
+    /// Read from the task's exit channel.
+    pub fn upgrade_exit_channel(&self) -> Option<ChannelCap> {
+        self.weak_pool.read().upgrade(6)
+    }
+
+    /// Debug name of the task. Empty if the task is not named.
+    pub fn name(&self) -> &[u8] {
+        &self.name[0..self.name_length]
+    }
+
+    /// Set the debug name of the task. Names longer than
+    /// `TASK_NAME_LENGTH` are truncated.
+    pub fn set_name(&mut self, name: &[u8]) {
+        let length = ::core::cmp::min(name.len(), TASK_NAME_LENGTH);
+        self.name[0..length].copy_from_slice(&name[0..length]);
+        self.name_length = length;
+    }
+
     /// Current task status.
     pub fn status(&self) -> TaskStatus {
         self.status.clone()
     }
 
-    /// Set the current task status.
+    /// Set the current task status. A dead task stays dead.
     pub fn set_status(&mut self, status: TaskStatus) {
+        if let TaskStatus::Dead = self.status {
+            return;
+        }
         self.status = status;
     }
 
-    /// Switch to the task. The function is returned when exception
-    /// happens.
-    pub fn switch_to(&mut self) -> Exception {
-        if let Some(pml4) = self.upgrade_top_page_table() {
-            pml4.write().switch_to();
-        }
-        unsafe { self.runtime.switch_to(true) }
+    /// Scheduling priority of the task. Higher values run first.
+    pub fn priority(&self) -> u8 {
+        self.priority
+    }
+
+    /// Set the scheduling priority of the task. A task already in a
+    /// ready queue is moved the next time it is queued.
+    pub fn set_priority(&mut self, priority: u8) {
+        assert!((priority as usize) < PRIORITY_COUNT);
+        self.priority = priority;
+    }
+
+    /// CPU time accounting of the task.
+    pub fn time(&self) -> &TimeAccount {
+        &self.time
+    }
+
+    /// Mutable CPU time accounting of the task.
+    pub fn time_mut(&mut self) -> &mut TimeAccount {
+        &mut self.time
+    }
+
+    /// Whether the task is in a ready queue.
+    pub fn is_ready(&self) -> bool {
+        self.ready
+    }
+
+    /// Mark whether the task is in a ready queue. Used by the
+    /// scheduler.
+    pub fn set_ready(&mut self, ready: bool) {
+        self.ready = ready;
+    }
+
+    /// Whether the task is running on a processor.
+    pub fn is_running(&self) -> bool {
+        self.running
+    }
+
+    /// Mark whether the task is running on a processor. Used by the
+    /// scheduler.
+    pub fn set_running(&mut self, running: bool) {
+        self.running = running;
+    }
+
+    /// The processor the task is restricted to run on, if any.
+    pub fn affinity(&self) -> Option<usize> {
+        self.affinity
+    }
+
+    /// Restrict the task to run on a processor, or allow it to run on
+    /// any processor with `None`.
+    pub fn set_affinity(&mut self, affinity: Option<usize>) {
+        self.affinity = affinity;
+    }
+
+    /// Set the next task in the ready queue, returning the previous
+    /// one. Used by the scheduler.
+    pub fn set_next_ready(&mut self, next_ready: Option<TaskCap>) -> Option<TaskCap> {
+        mem::replace(&mut self.next_ready, next_ready)
+    }
+
+    /// Whether the task is in the timer wheel.
+    pub fn is_sleeping(&self) -> bool {
+        self.sleeping
+    }
+
+    /// Mark whether the task is in the timer wheel. Used by the
+    /// scheduler.
+    pub fn set_sleeping(&mut self, sleeping: bool) {
+        self.sleeping = sleeping;
+    }
+
+    /// The next task in the same timer wheel slot. Used by the
+    /// scheduler.
+    pub fn next_sleeping(&self) -> Option<TaskCap> {
+        self.next_sleeping.clone()
+    }
+
+    /// Set the next task in the timer wheel slot, returning the
+    /// previous one. Used by the scheduler.
+    pub fn set_next_sleeping(&mut self, next_sleeping: Option<TaskCap>) -> Option<TaskCap> {
+        mem::replace(&mut self.next_sleeping, next_sleeping)
+    }
+
+    /// The next task waiting on the same channel. Used by channels.
+    pub fn next_waiting(&self) -> Option<TaskCap> {
+        self.next_waiting.clone()
+    }
+
+    /// Set the next task waiting on the same channel, returning the
+    /// previous one. Used by channels.
+    pub fn set_next_waiting(&mut self, next_waiting: Option<TaskCap>) -> Option<TaskCap> {
+        mem::replace(&mut self.next_waiting, next_waiting)
     }
 }
 
@@ -158,6 +467,30 @@ fn register_task(cap: TaskCap) {
     }
 }
 
+/// Remove a task from the linked-list formed from `FIRST_TASK`.
+fn unregister_task(cap: &TaskCap) {
+    let mut first_task = FIRST_TASK.lock();
+
+    let is_first = first_task.as_ref().map_or(false, |first| first.ptr_eq(cap));
+    if is_first {
+        *first_task = cap.write().next_task.take();
+        return;
+    }
+
+    let mut next = first_task.clone();
+    while let Some(current) = next {
+        let mut current_task = current.write();
+
+        let is_prev = current_task.next_task.as_ref().map_or(false, |next| next.ptr_eq(cap));
+        if is_prev {
+            current_task.next_task = cap.write().next_task.take();
+            return;
+        }
+
+        next = current_task.next_task.clone();
+    }
+}
+
 /// A task iterator.
 pub struct TaskIterator {
     next: Option<TaskCap>,
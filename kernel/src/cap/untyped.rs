@@ -28,7 +28,7 @@ impl UntypedCap {
         let des_paddr = align_up(start_paddr, UntypedCap::inner_alignment());
         assert!(des_paddr + UntypedCap::inner_length() <= start_paddr + length);
 
-        log!("des_paddr: {:?}", des_paddr);
+        trace!("des_paddr: {:?}", des_paddr);
 
         Self::new(des_paddr, RwLock::new(UntypedDescriptor {
             start_paddr: start_paddr,
@@ -59,20 +59,29 @@ mod elf;
 /// Capabilities implementation.
 mod cap;
 
+/// Task scheduler, with priorities and ready queues.
+mod sched;
+
 use core::mem;
 use core::slice;
 use common::*;
 use arch::{InitInfo, inportb, outportb, Exception};
-use cap::{UntypedCap, CPoolCap, CPoolDescriptor, RawPageCap, TaskBufferPageCap, TopPageTableCap, TaskCap, TaskDescriptor, TaskStatus, ChannelCap, ChannelDescriptor, PAGE_LENGTH};
+use cap::{UntypedCap, CPoolCap, CPoolDescriptor, RawPageCap, TaskBufferPageCap, TopPageTableCap, TaskCap, TaskDescriptor, TaskStatus, ChannelCap, ChannelDescriptor, IRQHandlerCap, SchedContextCap, KernelLogCap, PAGE_LENGTH};
 use core::ops::{Deref, DerefMut};
-use abi::{SystemCall, TaskBuffer};
-use util::{MemoryObject};
+use abi::{SystemCall, TaskBuffer, MessageError, TASK_NAME_LENGTH, DATA_LENGTH};
+use util::{MemoryObject, Mutex, LossyStr};
+use util::managed_arc::ManagedArc;
 use core::any::{Any, TypeId};
 
 /// Map a stack for the rinit program using the given physical address
-/// and stack size.
+/// and stack size. The page below the stack is reserved as a guard
+/// page.
 fn map_rinit_stack(rinit_stack_vaddr: VAddr, rinit_stack_size: usize,
                    cpool: &mut CPoolCap, untyped: &mut UntypedCap, rinit_pml4: &mut TopPageTableCap) {
+    rinit_pml4.map_guard(VAddr::from(rinit_stack_vaddr.into(): usize - PAGE_LENGTH),
+                         rinit_stack_size * PAGE_LENGTH,
+                         untyped.write().deref_mut(),
+                         cpool.read().deref());
     for i in 0..rinit_stack_size {
         let mut rinit_stack_page = RawPageCap::retype_from(untyped.write().deref_mut());
         cpool.read().downgrade_free(&rinit_stack_page);
@@ -116,15 +125,15 @@ fn bootstrap_rinit_paging(archinfo: &InitInfo, cpool: &mut CPoolCap, untyped: &m
                                                  archinfo.rinit_region().length()) };
     let bin = ElfBinary::new("rinit", bin_raw).unwrap();
 
-    log!("fheader = {:?}", bin.file_header());
-    log!("entry = 0x{:x}", bin.file_header().entry);
+    debug!("fheader = {:?}", bin.file_header());
+    debug!("entry = 0x{:x}", bin.file_header().entry);
     rinit_entry = bin.file_header().entry;
 
     for p in bin.program_headers() {
         use elf::{PT_LOAD};
 
         if p.progtype == PT_LOAD {
-            log!("pheader = {}", p);
+            debug!("pheader = {}", p);
             assert!(p.filesz == p.memsz);
 
             let mut next_page_vaddr = VAddr::from(p.vaddr);
@@ -133,7 +142,7 @@ fn bootstrap_rinit_paging(archinfo: &InitInfo, cpool: &mut CPoolCap, untyped: &m
 
             while next_page_vaddr <= end_vaddr {
                 use core::cmp::{min};
-                log!("mapping from: 0x{:x}", next_page_vaddr);
+                trace!("mapping from: 0x{:x}", next_page_vaddr);
 
                 let page_cap = RawPageCap::retype_from(untyped.write().deref_mut());
                 cpool.read().downgrade_free(&page_cap);
@@ -155,19 +164,19 @@ fn bootstrap_rinit_paging(archinfo: &InitInfo, cpool: &mut CPoolCap, untyped: &m
         }
     }
 
-    log!("mapping the rinit stack ...");
+    debug!("mapping the rinit stack ...");
     map_rinit_stack(rinit_stack_vaddr, rinit_stack_size, cpool, untyped, &mut rinit_pml4);
 
-    log!("mapping the child rinit stack ...");
+    debug!("mapping the child rinit stack ...");
     map_rinit_stack(rinit_child_stack_vaddr, rinit_stack_size, cpool, untyped, &mut rinit_pml4);
 
-    log!("mapping the rinit task buffer ...");
+    debug!("mapping the rinit task buffer ...");
     let rinit_buffer_page = map_rinit_buffer(rinit_buffer_vaddr, cpool, untyped, &mut rinit_pml4);
     let rinit_child_buffer_page = map_rinit_buffer(rinit_child_buffer_vaddr, cpool, untyped, &mut rinit_pml4);
 
     cpool.read().downgrade_at(&rinit_child_buffer_page, 250);
 
-    log!("mapping the rinit vga buffer ...");
+    debug!("mapping the rinit vga buffer ...");
     let mut rinit_vga_page = unsafe { RawPageCap::bootstrap(PAddr::from(0xb8000: usize), untyped.write().deref_mut()) };
     cpool.read().downgrade_free(&rinit_vga_page);
     rinit_pml4.map(rinit_vga_vaddr, &rinit_vga_page,
@@ -177,18 +186,84 @@ fn bootstrap_rinit_paging(archinfo: &InitInfo, cpool: &mut CPoolCap, untyped: &m
     (rinit_pml4, rinit_buffer_page, VAddr::from(rinit_entry), rinit_stack_vaddr + (PAGE_LENGTH * rinit_stack_size - 4))
 }
 
+/// Handle the system call message in the task buffer of a task. The
+/// message is copied and validated before dispatch, and written back
+/// with its response afterwards. Invalid messages are not dispatched;
+/// the error is reported back in the status of the buffer.
+fn handle_buffer_system_call(task_cap: TaskCap, cpool: &CPoolDescriptor) {
+    let buffer = task_cap.read().upgrade_buffer();
+    let buffer = match buffer {
+        Some(buffer) => buffer,
+        None => {
+            warn!("system call from a task without a buffer");
+            return;
+        },
+    };
+
+    let call = buffer.read().read().read_call();
+    match call {
+        Ok(mut call) => {
+            let result = handle_system_call(&mut call, task_cap, cpool);
+            let mut buffer_desc = buffer.write();
+            let mut task_buffer = buffer_desc.write();
+            task_buffer.write_call(&call);
+            if let Err(error) = result {
+                task_buffer.set_error(error);
+            }
+        },
+        Err(error) => {
+            warn!("invalid system call message: {:?}", error);
+            buffer.write().write().set_error(error);
+        },
+    }
+}
+
+/// Handle a system call made with the `syscall` instruction. The
+/// call number is passed in `rax`, and its arguments in `rdi` and
+/// `rsi`. Calls numbered `FAST_CALL_BUFFER` are read from the task
+/// buffer; the others are turned into the corresponding `SystemCall`.
+/// The status is reported back in `rax` and `rdx`.
+fn handle_fast_system_call(task_cap: TaskCap) {
+    let registers = task_cap.read().registers();
+    let cpool = task_cap.read().upgrade_cpool();
+    let cpool = cpool.as_ref().unwrap().read();
+
+    let result = match abi::read_fast_call(registers.rax, registers.rdi, registers.rsi) {
+        Ok(None) => {
+            handle_buffer_system_call(task_cap.clone(), cpool.deref());
+            Ok(())
+        },
+        Ok(Some(mut call)) => handle_system_call(&mut call, task_cap.clone(), cpool.deref()),
+        Err(error) => Err(error),
+    };
+
+    let status = match result {
+        Ok(()) => (abi::STATUS_OK, 0),
+        Err(error) => {
+            warn!("fast system call {} failed: {:?}", registers.rax, error);
+            error.to_status()
+        },
+    };
+    task_cap.write().set_fast_call_status(status);
+}
+
 /// System call handling function. Dispatch based on the type of the
 /// system call.
-fn handle_system_call(call: &mut SystemCall, task_cap: TaskCap, cpool: &CPoolDescriptor) {
+fn handle_system_call(call: &mut SystemCall, task_cap: TaskCap, cpool: &CPoolDescriptor)
+                      -> Result<(), MessageError> {
     match call {
         &mut SystemCall::Print {
             request: ref request
         } => {
-            use core::str;
-            let buffer = request.0.clone();
-            let slice = &buffer[0..request.1];
-            let s = str::from_utf8(slice).unwrap();
-            log!("Userspace print: {}", s);
+            let (offset, length) = *request;
+            let mut bytes = [0u8; DATA_LENGTH];
+            {
+                let buffer = task_cap.read().upgrade_buffer().unwrap();
+                let buffer_desc = buffer.read();
+                let task_buffer = buffer_desc.read();
+                bytes[0..length].copy_from_slice(&task_buffer.data[offset..(offset + length)]);
+            }
+            log!("Task {} print: {}", task_cap.display_name(), LossyStr(&bytes[0..length]));
         },
         &mut SystemCall::CPoolListDebug => {
             for i in 0..256 {
@@ -200,7 +275,9 @@ fn handle_system_call(call: &mut SystemCall, task_cap: TaskCap, cpool: &CPoolDes
                     } else if arc.is::<UntypedCap>() {
                         log!("CPool index {} => {:?}", i, arc.into(): UntypedCap);
                     } else if arc.is::<TaskCap>() {
-                        log!("CPool index {} => {:?}", i, arc.into(): TaskCap);
+                        let task_cap = arc.into(): TaskCap;
+                        log!("CPool index {} => {:?} name: \"{}\"",
+                             i, task_cap, LossyStr(task_cap.read().name()));
                     } else if arc.is::<RawPageCap>() {
                         log!("CPool index {} => {:?}", i, arc.into(): RawPageCap);
                     } else if arc.is::<TaskBufferPageCap>() {
@@ -209,6 +286,12 @@ fn handle_system_call(call: &mut SystemCall, task_cap: TaskCap, cpool: &CPoolDes
                         log!("CPool index {} => {:?}", i, arc.into(): TopPageTableCap);
                     } else if arc.is::<ChannelCap>() {
                         log!("CPool index {} => {:?}", i, arc.into(): ChannelCap);
+                    } else if arc.is::<IRQHandlerCap>() {
+                        log!("CPool index {} => {:?}", i, arc.into(): IRQHandlerCap);
+                    } else if arc.is::<SchedContextCap>() {
+                        log!("CPool index {} => {:?}", i, arc.into(): SchedContextCap);
+                    } else if arc.is::<KernelLogCap>() {
+                        log!("CPool index {} => {:?}", i, arc.into(): KernelLogCap);
                     } else {
                         log!("CPool index {} (arch specific) => {:?}", i, arc);
                         cap::drop_any(arc);
@@ -219,92 +302,603 @@ fn handle_system_call(call: &mut SystemCall, task_cap: TaskCap, cpool: &CPoolDes
         &mut SystemCall::RetypeCPool {
             request: ref request,
         } => {
-            let source: Option<UntypedCap> = cpool.upgrade(request.0);
-            if source.is_some() {
-                let source = source.unwrap();
-                let target = CPoolCap::retype_from(source.write().deref_mut());
-                let result = cpool.downgrade_at(&target, request.1);
-            }
+            let source: UntypedCap = try!(upgrade_argument(cpool, request.0, 0));
+            let target = CPoolCap::retype_from(source.write().deref_mut());
+            cpool.downgrade_at(&target, request.1);
         },
         &mut SystemCall::RetypeTask {
             request: ref request,
         } => {
-            let source: Option<UntypedCap> = cpool.upgrade(request.0);
-            if source.is_some() {
-                let source = source.unwrap();
-                let target = TaskCap::retype_from(source.write().deref_mut());
-                let result = cpool.downgrade_at(&target, request.1);
-            }
+            let source: UntypedCap = try!(upgrade_argument(cpool, request.0, 0));
+            let target = TaskCap::retype_from(source.write().deref_mut());
+            cpool.downgrade_at(&target, request.1);
         },
         &mut SystemCall::TaskSetInstructionPointer {
             request: ref request,
         } => {
-            let target: Option<TaskCap> = cpool.upgrade(request.0);
-            if target.is_some() {
-                let target = target.unwrap();
-                target.write().set_instruction_pointer(VAddr::from(request.1));
+            let target_task: TaskCap = try!(upgrade_argument(cpool, request.0, 0));
+            let mut target = target_task.write();
+            if target.is_running() {
+                warn!("TaskSetInstructionPointer: task is running");
+                return Err(MessageError::State(0));
+            }
+            if !arch::is_user_vaddr(VAddr::from(request.1)) {
+                return Err(MessageError::Argument(1));
             }
+            target.set_instruction_pointer(VAddr::from(request.1));
         },
         &mut SystemCall::TaskSetStackPointer {
             request: ref request,
         } => {
-            let target: Option<TaskCap> = cpool.upgrade(request.0);
-            if target.is_some() {
-                let target = target.unwrap();
-                target.write().set_stack_pointer(VAddr::from(request.1));
+            let target_task: TaskCap = try!(upgrade_argument(cpool, request.0, 0));
+            let mut target = target_task.write();
+            if target.is_running() {
+                warn!("TaskSetStackPointer: task is running");
+                return Err(MessageError::State(0));
             }
+            if !arch::is_user_vaddr(VAddr::from(request.1)) {
+                return Err(MessageError::Argument(1));
+            }
+            target.set_stack_pointer(VAddr::from(request.1));
         },
         &mut SystemCall::TaskSetCPool {
             request: ref request,
         } => {
-            let target_task: TaskCap = cpool.upgrade(request.0).unwrap();
-            let target_cpool: CPoolCap = cpool.upgrade(request.1).unwrap();
+            let target_task: TaskCap = try!(upgrade_argument(cpool, request.0, 0));
+            let target_cpool: CPoolCap = try!(upgrade_argument(cpool, request.1, 1));
             target_task.read().downgrade_cpool(&target_cpool);
         },
         &mut SystemCall::TaskSetTopPageTable {
             request: ref request,
         } => {
-            let target_task: TaskCap = cpool.upgrade(request.0).unwrap();
-            let target_table: TopPageTableCap = cpool.upgrade(request.1).unwrap();
+            let target_task: TaskCap = try!(upgrade_argument(cpool, request.0, 0));
+            let target_table: TopPageTableCap = try!(upgrade_argument(cpool, request.1, 1));
             target_task.read().downgrade_top_page_table(&target_table);
         },
         &mut SystemCall::TaskSetBuffer {
             request: ref request,
         } => {
-            let target_task: TaskCap = cpool.upgrade(request.0).unwrap();
-            let target_buffer: TaskBufferPageCap = cpool.upgrade(request.1).unwrap();
+            let target_task: TaskCap = try!(upgrade_argument(cpool, request.0, 0));
+            let target_buffer: TaskBufferPageCap = try!(upgrade_argument(cpool, request.1, 1));
             target_task.read().downgrade_buffer(&target_buffer);
         },
         &mut SystemCall::TaskSetActive {
             request: ref request,
         } => {
-            let target_task: TaskCap = cpool.upgrade(*request).unwrap();
-            target_task.write().set_status(TaskStatus::Active);
+            let target_task: TaskCap = try!(upgrade_argument(cpool, *request, 0));
+            sched::set_status(&target_task, TaskStatus::Active);
         },
         &mut SystemCall::TaskSetInactive {
             request: ref request,
         } => {
-            let target_task: TaskCap = cpool.upgrade(*request).unwrap();
-            target_task.write().set_status(TaskStatus::Inactive);
+            let target_task: TaskCap = try!(upgrade_argument(cpool, *request, 0));
+            sched::set_status(&target_task, TaskStatus::Inactive);
+        },
+        &mut SystemCall::RetypeSchedContext {
+            request: ref request,
+        } => {
+            let source: UntypedCap = try!(upgrade_argument(cpool, request.0, 0));
+            let target = SchedContextCap::retype_from(source.write().deref_mut());
+            cpool.downgrade_at(&target, request.1);
+        },
+        &mut SystemCall::SchedContextConfigure {
+            request: ref request,
+        } => {
+            let target: SchedContextCap = try!(upgrade_argument(cpool, request.0, 0));
+            if (request.1 as usize) >= sched::PRIORITY_COUNT {
+                return Err(MessageError::Argument(1));
+            }
+            let mut target_desc = target.write();
+            target_desc.set_priority(request.1);
+            let budget = if request.2 == 0 {
+                None
+            } else {
+                Some((request.2, request.3))
+            };
+            target_desc.time_mut().set_budget(budget);
+        },
+        &mut SystemCall::TaskBindSchedContext {
+            request: ref request,
+        } => {
+            let target_task: TaskCap = try!(upgrade_argument(cpool, request.0, 0));
+            let target_sched_context: SchedContextCap = try!(upgrade_argument(cpool, request.1, 1));
+            {
+                let task = target_task.read();
+                if task.upgrade_sched_context().is_none() {
+                    task.downgrade_sched_context(&target_sched_context);
+                }
+            }
+            sched::enqueue(target_task);
+        },
+        &mut SystemCall::TaskSetPassive {
+            request: ref request,
+        } => {
+            let target_task: TaskCap = try!(upgrade_argument(cpool, request.0, 0));
+            target_task.write().set_passive(request.1);
+            sched::enqueue(target_task);
+        },
+        &mut SystemCall::TaskSetName {
+            request: ref request,
+        } => {
+            let (target, offset, length) = *request;
+            let mut name = [0u8; TASK_NAME_LENGTH];
+            {
+                let buffer = task_cap.read().upgrade_buffer().unwrap();
+                let buffer_desc = buffer.read();
+                let task_buffer = buffer_desc.read();
+                name[0..length].copy_from_slice(&task_buffer.data[offset..(offset + length)]);
+            }
+            let target_task: TaskCap = try!(upgrade_argument(cpool, target, 0));
+            target_task.write().set_name(&name[0..length]);
+        },
+        &mut SystemCall::KernelLogRead {
+            request: ref request,
+            response: ref mut response,
+        } => {
+            let (log, position, offset, length) = *request;
+            let _log_cap: KernelLogCap = try!(upgrade_argument(cpool, log, 0));
+            let mut bytes = [0u8; DATA_LENGTH];
+            let (next, read) = logging::read_ring(position, &mut bytes[0..length]);
+            {
+                let buffer = task_cap.read().upgrade_buffer().unwrap();
+                let mut buffer_desc = buffer.write();
+                let mut task_buffer = buffer_desc.write();
+                task_buffer.data[offset..(offset + read)].copy_from_slice(&bytes[0..read]);
+            }
+            *response = Some((next, read));
+        },
+        &mut SystemCall::TaskReadRegisters {
+            request: ref request,
+            response: ref mut response,
+        } => {
+            let target_task: TaskCap = try!(upgrade_argument(cpool, *request, 0));
+            let target = target_task.read();
+            match target.status() {
+                TaskStatus::Inactive if !target.is_running() => {
+                    *response = Some(target.registers());
+                },
+                _ => {
+                    warn!("TaskReadRegisters: task is not suspended");
+                    return Err(MessageError::State(0));
+                },
+            }
+        },
+        &mut SystemCall::TaskWriteRegisters {
+            request: ref request,
+        } => {
+            let target_task: TaskCap = try!(upgrade_argument(cpool, request.0, 0));
+            let mut target = target_task.write();
+            if target.is_running() {
+                warn!("TaskWriteRegisters: task is running");
+                return Err(MessageError::State(0));
+            }
+            if !arch::is_user_vaddr(VAddr::from(request.1.rip)) ||
+                !arch::is_user_vaddr(VAddr::from(request.1.rsp))
+            {
+                return Err(MessageError::Argument(1));
+            }
+            if let TaskStatus::Inactive = target.status() {
+                target.set_registers(&request.1);
+            } else {
+                warn!("TaskWriteRegisters: task is not suspended");
+                return Err(MessageError::State(0));
+            }
+        },
+        &mut SystemCall::TaskDelete {
+            request: ref request,
+        } => {
+            let target_task: TaskCap = try!(upgrade_argument(cpool, *request, 0));
+            target_task.delete();
+        },
+        &mut SystemCall::TaskExit {
+            request: ref request,
+        } => {
+            task_cap.exit(*request);
+        },
+        &mut SystemCall::TaskSetExitChannel {
+            request: ref request,
+        } => {
+            let target_task: TaskCap = try!(upgrade_argument(cpool, request.0, 0));
+            let target_channel: ChannelCap = try!(upgrade_argument(cpool, request.1, 1));
+            let target = target_task.read();
+            if target.upgrade_exit_channel().is_none() {
+                target.downgrade_exit_channel(&target_channel);
+            }
+        },
+        &mut SystemCall::TaskYield => {
+            task_cap.write().time_mut().yield_slice();
+            let sched_context = task_cap.read().sched_context();
+            if let Some(sched_context) = sched_context {
+                sched_context.write().time_mut().yield_slice();
+            }
+        },
+        &mut SystemCall::TaskSleep {
+            request: ref request,
+        } => {
+            sched::sleep(&task_cap, *request);
+        },
+        &mut SystemCall::TaskWakeup {
+            request: ref request,
+        } => {
+            let target_task: TaskCap = try!(upgrade_argument(cpool, *request, 0));
+            let sleeping = match target_task.read().status() {
+                TaskStatus::Sleep(_) => true,
+                _ => false,
+            };
+            if sleeping {
+                sched::set_status(&target_task, TaskStatus::Active);
+            }
+        },
+        &mut SystemCall::TaskSetTimeslice {
+            request: ref request,
+        } => {
+            let target_task: TaskCap = try!(upgrade_argument(cpool, request.0, 0));
+            if request.1 > 0 {
+                target_task.write().time_mut().set_timeslice(request.1);
+            }
+        },
+        &mut SystemCall::TaskSetBudget {
+            request: ref request,
+        } => {
+            let target_task: TaskCap = try!(upgrade_argument(cpool, request.0, 0));
+            let budget = if request.1 == 0 {
+                None
+            } else {
+                Some((request.1, request.2))
+            };
+            target_task.write().time_mut().set_budget(budget);
+            sched::enqueue(target_task);
+        },
+        &mut SystemCall::TaskCpuTime {
+            request: ref request,
+            response: ref mut response,
+        } => {
+            let target_task: TaskCap = try!(upgrade_argument(cpool, *request, 0));
+            *response = Some(target_task.read().time().cpu_ticks());
+        },
+        &mut SystemCall::TaskSetPriority {
+            request: ref request,
+        } => {
+            let target_task: TaskCap = try!(upgrade_argument(cpool, request.0, 0));
+            if (request.1 as usize) >= sched::PRIORITY_COUNT {
+                return Err(MessageError::Argument(1));
+            }
+            if target_task.read().sched_context().is_some() {
+                warn!("TaskSetPriority: task {} runs on a scheduling context",
+                      target_task.display_name());
+                return Err(MessageError::State(0));
+            }
+            target_task.write().set_priority(request.1);
+        },
+        &mut SystemCall::TaskSetAffinity {
+            request: ref request,
+        } => {
+            let target_task: TaskCap = try!(upgrade_argument(cpool, request.0, 0));
+            match request.1 {
+                Some(cpu) if cpu >= arch::cpu_count() => {
+                    warn!("TaskSetAffinity: processor {} does not exist", cpu);
+                    return Err(MessageError::Argument(1));
+                },
+                affinity => {
+                    target_task.write().set_affinity(affinity);
+                },
+            }
         },
         &mut SystemCall::ChannelTake {
             request: ref request,
             response: ref mut response,
         } => {
-            let mut chan_option: Option<ChannelCap> = cpool.upgrade(*request);
-            if let Some(chan) = chan_option {
-                task_cap.write().set_status(TaskStatus::ChannelWait(chan))
+            let chan: ChannelCap = try!(upgrade_argument(cpool, *request, 0));
+            sched::end_donation(&task_cap);
+            *response = chan.take_or_wait(&task_cap);
+            // A byte taken from the serial channel makes room for the
+            // next buffered one.
+            if response.is_some() {
+                feed_serial_channel();
             }
         },
         &mut SystemCall::ChannelPut {
             request: ref request,
         } => {
-            let chan_option: Option<ChannelCap> = cpool.upgrade(request.0);
-            if let Some(chan) = chan_option {
-                chan.write().put(request.1);
+            let chan: ChannelCap = try!(upgrade_argument(cpool, request.0, 0));
+            chan.put(request.1, Some(&task_cap));
+        },
+        &mut SystemCall::RetypeIRQHandler {
+            request: ref request,
+        } => {
+            let source: UntypedCap = try!(upgrade_argument(cpool, request.0, 0));
+            match IRQHandlerCap::retype_from(source.write().deref_mut(), request.1) {
+                Some(target) => cpool.downgrade_at(&target, request.2),
+                None => return Err(MessageError::Argument(1)),
+            }
+        },
+        &mut SystemCall::IRQHandlerSetChannel {
+            request: ref request,
+        } => {
+            let target_handler: IRQHandlerCap = try!(upgrade_argument(cpool, request.0, 0));
+            let target_channel: ChannelCap = try!(upgrade_argument(cpool, request.1, 1));
+            target_handler.read().downgrade_channel(&target_channel);
+        },
+        &mut SystemCall::IRQAck {
+            request: ref request,
+        } => {
+            let handler: IRQHandlerCap = try!(upgrade_argument(cpool, *request, 0));
+            handler.write().ack();
+        },
+        &mut SystemCall::IRQHandlerDelete {
+            request: ref request,
+        } => {
+            let handler: IRQHandlerCap = try!(upgrade_argument(cpool, *request, 0));
+            handler.delete();
+        },
+        &mut SystemCall::RetypeTopPageTable {
+            request: ref request,
+        } => {
+            let source: UntypedCap = try!(upgrade_argument(cpool, request.0, 0));
+            let target = TopPageTableCap::retype_from(source.write().deref_mut());
+            cpool.downgrade_at(&target, request.1);
+        },
+        &mut SystemCall::TopPageTableMapCopyOnWrite {
+            request: ref request,
+        } => {
+            let mut source_table: TopPageTableCap = try!(upgrade_argument(cpool, request.0, 0));
+            let mut target_table: TopPageTableCap = try!(upgrade_argument(cpool, request.1, 1));
+            let untyped: UntypedCap = try!(upgrade_argument(cpool, request.3, 3));
+            let vaddr = VAddr::from(request.2);
+            if !source_table.map_copy_on_write(&mut target_table, vaddr,
+                                               untyped.write().deref_mut(), cpool) {
+                warn!("TopPageTableMapCopyOnWrite: cannot share 0x{:x}", vaddr);
+                return Err(MessageError::Argument(2));
+            }
+        },
+        &mut SystemCall::TaskSetFaultUntyped {
+            request: ref request,
+        } => {
+            let target_task: TaskCap = try!(upgrade_argument(cpool, request.0, 0));
+            let target_untyped: UntypedCap = try!(upgrade_argument(cpool, request.1, 1));
+            target_task.read().downgrade_fault_untyped(&target_untyped);
+        },
+        &mut SystemCall::TaskSetPagerChannel {
+            request: ref request,
+        } => {
+            let target_task: TaskCap = try!(upgrade_argument(cpool, request.0, 0));
+            let target_channel: ChannelCap = try!(upgrade_argument(cpool, request.1, 1));
+            target_task.read().downgrade_pager_channel(&target_channel);
+        },
+        &mut SystemCall::RetypeRawPage {
+            request: ref request,
+        } => {
+            let source: UntypedCap = try!(upgrade_argument(cpool, request.0, 0));
+            let target = RawPageCap::retype_from(source.write().deref_mut());
+            cpool.downgrade_at(&target, request.1);
+        },
+        &mut SystemCall::RetypeTaskBuffer {
+            request: ref request,
+        } => {
+            let source: UntypedCap = try!(upgrade_argument(cpool, request.0, 0));
+            let target = TaskBufferPageCap::retype_from(source.write().deref_mut());
+            cpool.downgrade_at(&target, request.1);
+        },
+        &mut SystemCall::TopPageTableMap {
+            request: ref request,
+        } => {
+            let mut target_table: TopPageTableCap = try!(upgrade_argument(cpool, request.0, 0));
+            let untyped: UntypedCap = try!(upgrade_argument(cpool, request.3, 3));
+            let vaddr = VAddr::from(request.1);
+
+            if !arch::is_user_vaddr(vaddr) {
+                warn!("TopPageTableMap: 0x{:x} is not a user address", vaddr);
+                return Err(MessageError::Argument(1));
+            } else if target_table.read().entry(vaddr).is_some() {
+                warn!("TopPageTableMap: 0x{:x} is already mapped", vaddr);
+                return Err(MessageError::Argument(1));
+            } else if target_table.read().guarded_stack(vaddr).is_some() {
+                warn!("TopPageTableMap: 0x{:x} is a guard page", vaddr);
+                return Err(MessageError::Argument(1));
+            } else {
+                let raw_page: Option<RawPageCap> = cpool.upgrade(request.2);
+                let buffer_page: Option<TaskBufferPageCap> = cpool.upgrade(request.2);
+
+                if let Some(raw_page) = raw_page {
+                    target_table.map(vaddr, &raw_page, untyped.write().deref_mut(), cpool);
+                } else if let Some(buffer_page) = buffer_page {
+                    target_table.map(vaddr, &buffer_page, untyped.write().deref_mut(), cpool);
+                } else {
+                    warn!("TopPageTableMap: {} is not a page", request.2);
+                    return Err(MessageError::Capability(2));
+                }
+            }
+        },
+        &mut SystemCall::TopPageTableMapGuard {
+            request: ref request,
+        } => {
+            let mut target_table: TopPageTableCap = try!(upgrade_argument(cpool, request.0, 0));
+            let untyped: UntypedCap = try!(upgrade_argument(cpool, request.3, 3));
+            let vaddr = VAddr::from(request.1);
+
+            // The stack length is kept in the address field of the
+            // guard entry, so it must be whole pages, and the stack
+            // must end in the user address space.
+            let stack_length = request.2 as u64;
+            let stack_end = request.1.checked_add(PAGE_LENGTH as u64)
+                .and_then(|stack_start| stack_start.checked_add(stack_length));
+
+            if stack_length == 0 || stack_length % PAGE_LENGTH as u64 != 0 ||
+                stack_end.map_or(true, |stack_end| stack_end > arch::USER_END) {
+                warn!("TopPageTableMapGuard: invalid stack of 0x{:x} bytes above 0x{:x}",
+                      stack_length, vaddr);
+                return Err(MessageError::Argument(2));
+            } else if target_table.read().entry(vaddr).is_none() {
+                target_table.map_guard(vaddr, request.2, untyped.write().deref_mut(), cpool);
+            } else {
+                warn!("TopPageTableMapGuard: 0x{:x} is already mapped", vaddr);
+                return Err(MessageError::Argument(1));
+            }
+        },
+    }
+
+    Ok(())
+}
+
+/// Upgrade the capability in `slot`, which was passed as argument
+/// `index` of a system call. A missing capability or one of another
+/// type is reported as an error of that argument.
+fn upgrade_argument<T: Any>(cpool: &CPoolDescriptor, slot: usize, index: usize)
+                            -> Result<ManagedArc<T>, MessageError>
+    where ManagedArc<T>: Any {
+    match cpool.upgrade(slot) {
+        Some(arc) => Ok(arc),
+        None => {
+            warn!("argument {} of a system call is not the expected capability", index);
+            Err(MessageError::Capability(index))
+        },
+    }
+}
+
+/// Handle a page fault of a task. Write faults on copy-on-write pages
+/// are resolved by copying the page using the task's fault
+/// untyped. Other faults are forwarded to the task's pager channel,
+/// or stop the task if it has none. In both cases the task is set
+/// inactive, and the pager resumes it after mapping the page. Faults
+/// on guard pages are reported as stack overflows.
+fn handle_page_fault(task_cap: TaskCap, vaddr: VAddr, present: bool, write: bool) {
+    use abi::{PAGE_FAULT_PRESENT, PAGE_FAULT_WRITE, PAGE_FAULT_STACK_OVERFLOW,
+              PAGE_FAULT_ADDRESS_MASK};
+
+    if present && write {
+        let table = task_cap.read().upgrade_top_page_table();
+        let untyped = task_cap.read().upgrade_fault_untyped();
+        let cpool = task_cap.read().upgrade_cpool();
+
+        if let (Some(mut table), Some(untyped), Some(cpool)) = (table, untyped, cpool) {
+            if table.resolve_copy_on_write(vaddr, untyped.write().deref_mut(),
+                                           cpool.read().deref()) {
+                return;
             }
         }
     }
+
+    sched::set_status(&task_cap, TaskStatus::Inactive);
+
+    let guarded_stack = task_cap.read().upgrade_top_page_table().and_then(|table| {
+        let stack = table.read().guarded_stack(vaddr);
+        stack
+    });
+    if let Some((stack_vaddr, stack_length)) = guarded_stack {
+        warn!("Task {} stack overflow at 0x{:x}, stack 0x{:x}-0x{:x}",
+             task_cap.display_name(), vaddr, stack_vaddr, stack_vaddr + stack_length);
+    }
+
+    let pager = task_cap.read().upgrade_pager_channel();
+    if let Some(pager) = pager {
+        let mut message = (vaddr.into(): u64) & PAGE_FAULT_ADDRESS_MASK;
+        if present {
+            message |= PAGE_FAULT_PRESENT;
+        }
+        if write {
+            message |= PAGE_FAULT_WRITE;
+        }
+        if guarded_stack.is_some() {
+            message |= PAGE_FAULT_STACK_OVERFLOW;
+        }
+        pager.put(message, None);
+    } else if guarded_stack.is_none() {
+        warn!("Task {} page fault at 0x{:x} (present: {}, write: {}), stopping",
+             task_cap.display_name(), vaddr, present, write);
+    }
+}
+
+/// Channel receiving the bytes read from the serial port.
+static SERIAL_CHANNEL: Mutex<Option<ChannelCap>> = Mutex::new(None);
+
+/// Number of bytes received on the serial port that are kept until
+/// they are taken from the serial channel.
+const SERIAL_BUFFER_LENGTH: usize = 256;
+
+/// Bytes received on the serial port and not yet put to the serial
+/// channel, in the order they were received.
+struct SerialBuffer {
+    bytes: [u8; SERIAL_BUFFER_LENGTH],
+    head: usize,
+    length: usize,
+}
+
+impl SerialBuffer {
+    /// Append a byte. If the buffer is full, the byte is dropped and
+    /// `false` is returned.
+    fn push(&mut self, byte: u8) -> bool {
+        if self.length == SERIAL_BUFFER_LENGTH {
+            return false;
+        }
+        self.bytes[(self.head + self.length) % SERIAL_BUFFER_LENGTH] = byte;
+        self.length += 1;
+        true
+    }
+
+    /// Take the oldest byte.
+    fn pop(&mut self) -> Option<u8> {
+        if self.length == 0 {
+            return None;
+        }
+        let byte = self.bytes[self.head];
+        self.head = (self.head + 1) % SERIAL_BUFFER_LENGTH;
+        self.length -= 1;
+        Some(byte)
+    }
+}
+
+/// Serial input buffer.
+static SERIAL_BUFFER: Mutex<SerialBuffer> = Mutex::new(SerialBuffer {
+    bytes: [0; SERIAL_BUFFER_LENGTH],
+    head: 0,
+    length: 0,
+});
+
+/// Buffer every byte received on the serial port, and feed the
+/// serial channel.
+fn handle_serial_input() {
+    {
+        let mut buffer = SERIAL_BUFFER.lock();
+        while let Some(byte) = arch::serial_read() {
+            if !buffer.push(byte) {
+                warn!("serial input buffer is full, dropping 0x{:x}", byte);
+            }
+        }
+    }
+    feed_serial_channel();
+}
+
+/// Put buffered serial bytes to the serial channel while it holds no
+/// value, so that no byte is overwritten before it is taken. Tasks
+/// waiting on the channel take a byte each; the next byte is then
+/// kept in the channel.
+fn feed_serial_channel() {
+    let channel = match SERIAL_CHANNEL.lock().clone() {
+        Some(channel) => channel,
+        None => return,
+    };
+
+    while !channel.read().has_value() {
+        let byte = SERIAL_BUFFER.lock().pop();
+        match byte {
+            Some(byte) => channel.put(byte as u64, None),
+            None => break,
+        }
+    }
+}
+
+/// Deliver an IRQ to the IRQ handler capability registered for
+/// it. Interrupts without a handler are masked. The serial port is
+/// handled by the kernel itself, which shares it with the log output.
+fn handle_irq(irq: u8) {
+    if irq == arch::SERIAL_IRQ {
+        handle_serial_input();
+    } else if let Some(handler) = cap::irq_handler(irq) {
+        handler.write().notify();
+    } else {
+        warn!("IRQ {} has no handler, masking", irq);
+        arch::mask_irq(irq);
+    }
 }
 
 /// The kernel main function. It initialize the rinit program, and
@@ -312,7 +906,7 @@ fn handle_system_call(call: &mut SystemCall, task_cap: TaskCap, cpool: &CPoolDes
 #[no_mangle]
 pub fn kmain(archinfo: InitInfo)
 {
-    log!("archinfo: {:?}", &archinfo);
+    debug!("archinfo: {:?}", &archinfo);
     let mut region_iter = archinfo.free_regions();
 
     let (mut cpool, mut untyped) = {
@@ -340,92 +934,119 @@ pub fn kmain(archinfo: InitInfo)
         (cpool, untyped_target)
     };
 
-    log!("CPool: {:?}", cpool);
-    log!("Untyped: {:?}", untyped);
+    debug!("CPool: {:?}", cpool);
+    debug!("Untyped: {:?}", untyped);
 
-    log!("type_id: {:?}", TypeId::of::<CPoolCap>());
+    debug!("type_id: {:?}", TypeId::of::<CPoolCap>());
     {
         use util::{RwLock};
         use util::managed_arc::{ManagedArc};
         use cap::{CPoolDescriptor};
-        log!("type_id: {:?}", TypeId::of::<ManagedArc<RwLock<CPoolDescriptor>>>());
+        debug!("type_id: {:?}", TypeId::of::<ManagedArc<RwLock<CPoolDescriptor>>>());
     }
 
     {
         let (rinit_pml4, rinit_buffer_page, rinit_entry, rinit_stack) =
             bootstrap_rinit_paging(&archinfo, &mut cpool, &mut untyped);
         let rinit_task_cap = TaskCap::retype_from(untyped.write().deref_mut());
-        let mut rinit_task = rinit_task_cap.write();
-        rinit_task.set_instruction_pointer(rinit_entry);
-        rinit_task.set_stack_pointer(rinit_stack);
-        rinit_task.set_status(TaskStatus::Active);
-        rinit_task.downgrade_cpool(&cpool);
-        rinit_task.downgrade_top_page_table(&rinit_pml4);
-        rinit_task.downgrade_buffer(&rinit_buffer_page);
+        {
+            let mut rinit_task = rinit_task_cap.write();
+            rinit_task.set_instruction_pointer(rinit_entry);
+            rinit_task.set_stack_pointer(rinit_stack);
+            rinit_task.set_name(b"rinit");
+            rinit_task.downgrade_cpool(&cpool);
+            rinit_task.downgrade_top_page_table(&rinit_pml4);
+            rinit_task.downgrade_buffer(&rinit_buffer_page);
+            rinit_task.downgrade_fault_untyped(&untyped);
+        }
+        sched::set_status(&rinit_task_cap, TaskStatus::Active);
     }
 
     let mut keyboard_cap = ChannelCap::retype_from(untyped.write().deref_mut());
     cpool.read().downgrade_at(&keyboard_cap, 254);
 
+    let keyboard_irq_cap = IRQHandlerCap::retype_from(untyped.write().deref_mut(),
+                                                      arch::KEYBOARD_IRQ).unwrap();
+    keyboard_irq_cap.read().downgrade_channel(&keyboard_cap);
+    cpool.read().downgrade_at(&keyboard_irq_cap, 253);
+
     let mut util_chan_cap = ChannelCap::retype_from(untyped.write().deref_mut());
     cpool.read().downgrade_at(&util_chan_cap, 255);
 
+    let mut exit_chan_cap = ChannelCap::retype_from(untyped.write().deref_mut());
+    cpool.read().downgrade_at(&exit_chan_cap, 252);
+
+    let kernel_log_cap = KernelLogCap::retype_from(untyped.write().deref_mut());
+    cpool.read().downgrade_at(&kernel_log_cap, 248);
+
+    let serial_chan_cap = ChannelCap::retype_from(untyped.write().deref_mut());
+    cpool.read().downgrade_at(&serial_chan_cap, 251);
+    *SERIAL_CHANNEL.lock() = Some(serial_chan_cap);
+    arch::enable_serial_input();
+
     log!("hello, world!");
+    arch::start_application_processors();
     arch::enable_timer();
+    schedule();
+}
+
+/// Kernel main function of application processors. Called by
+/// architecture-specific initialization after the boot processor has
+/// initialized the kernel.
+pub fn kmain_ap() -> ! {
+    arch::enable_timer();
+    schedule();
+}
+
+/// Big kernel lock. A processor holds it while running in the kernel,
+/// and releases it while running a task or idling.
+static KERNEL_LOCK: Mutex<()> = Mutex::new(());
+
+/// Scheduling loop of a processor. Run the next task picked by the
+/// scheduler, and handle its exceptions, or idle if no task is
+/// runnable.
+fn schedule() -> ! {
     loop {
-        let mut idle = true;
-
-        for task_cap in cap::task_iter() {
-            let status = task_cap.read().status();
-            let exception = match status {
-                TaskStatus::Inactive => None,
-                TaskStatus::Active => {
-                    idle = false;
-                    Some(task_cap.write().switch_to())
-                },
-                TaskStatus::ChannelWait(ref chan) => {
-                    let value = chan.write().take();
-                    if let Some(value) = value {
-                        let buffer = task_cap.read().upgrade_buffer();
-                        let mut buffer_desc = buffer.as_ref().unwrap().write().write();
-                        let system_call = buffer_desc.deref_mut().call.as_mut().unwrap();
-                        match system_call {
-                            &mut SystemCall::ChannelTake {
-                                request: ref request,
-                                response: ref mut response,
-                            } => {
-                                idle = false;
-                                *response = Some(value);
-                                task_cap.write().set_status(TaskStatus::Active);
-                                Some(task_cap.write().switch_to())
-                            }
-                            _ => panic!(),
-                        }
-                    } else {
-                        None
-                    }
-                }
-            };
+        let next = {
+            let _kernel_lock = KERNEL_LOCK.lock();
+            let next = sched::next();
+            if next.is_none() {
+                sched::set_idle(true);
+            }
+            next
+        };
+
+        if let Some(task_cap) = next {
+            let exception = task_cap.switch_to();
+            let _kernel_lock = KERNEL_LOCK.lock();
             match exception {
-                Some(Exception::SystemCall) => {
+                Exception::SystemCall => {
                     let cpool = task_cap.read().upgrade_cpool();
-                    let buffer = task_cap.read().upgrade_buffer();
-                    handle_system_call(buffer.as_ref().unwrap().write().write().deref_mut().call.as_mut().unwrap(),
-                                       task_cap,
-                                       cpool.as_ref().unwrap().read().deref());
+                    handle_buffer_system_call(task_cap.clone(),
+                                              cpool.as_ref().unwrap().read().deref());
+                },
+                Exception::FastSystemCall => {
+                    handle_fast_system_call(task_cap.clone());
+                },
+                Exception::IRQ(irq) => {
+                    handle_irq(irq);
                 },
-                Some(Exception::Keyboard) => {
-                    keyboard_cap.write().put(unsafe { arch::inportb(0x60) } as u64);
+                Exception::PageFault { vaddr, present, write } => {
+                    handle_page_fault(task_cap.clone(), vaddr, present, write);
                 },
                 _ => (),
             }
-        }
-
-        if idle {
+            sched::requeue(task_cap, &exception);
+        } else {
             let exception = cap::idle();
+            let _kernel_lock = KERNEL_LOCK.lock();
+            sched::set_idle(false);
             match exception {
-                Exception::Keyboard => {
-                    keyboard_cap.write().put(unsafe { arch::inportb(0x60) } as u64);
+                Exception::IRQ(irq) => {
+                    handle_irq(irq);
+                },
+                Exception::Timer => {
+                    sched::tick();
                 },
                 _ => (),
             }
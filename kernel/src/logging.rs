@@ -1,5 +1,212 @@
 use core::sync::atomic;
 use core::fmt;
+use spin::{RwLock, Mutex};
+
+/// Importance of a log message. Lower levels are more important.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level
+{
+	Error = 1,
+	Warn = 2,
+	Info = 3,
+	Debug = 4,
+	Trace = 5,
+}
+
+impl Level
+{
+	/// Name of the level, as printed before each message.
+	pub fn name(&self) -> &'static str {
+		match *self {
+			Level::Error => "ERROR",
+			Level::Warn => "WARN",
+			Level::Info => "INFO",
+			Level::Debug => "DEBUG",
+			Level::Trace => "TRACE",
+		}
+	}
+}
+
+/// Parse a level filter from the command line. `off` is 0, and the
+/// levels are their numeric value.
+fn parse_filter(s: &str) -> Option<u8> {
+	match s {
+		"off" => Some(0),
+		"error" => Some(Level::Error as u8),
+		"warn" => Some(Level::Warn as u8),
+		"info" => Some(Level::Info as u8),
+		"debug" => Some(Level::Debug as u8),
+		"trace" => Some(Level::Trace as u8),
+		_ => None,
+	}
+}
+
+/// Most verbose level compiled in, set with the `max_level_*`
+/// features. Messages above it are removed at compile time, whatever
+/// the command line says.
+#[cfg(feature = "max_level_off")]
+pub const MAX_LEVEL: u8 = 0;
+#[cfg(feature = "max_level_error")]
+pub const MAX_LEVEL: u8 = Level::Error as u8;
+#[cfg(feature = "max_level_warn")]
+pub const MAX_LEVEL: u8 = Level::Warn as u8;
+#[cfg(feature = "max_level_info")]
+pub const MAX_LEVEL: u8 = Level::Info as u8;
+#[cfg(feature = "max_level_debug")]
+pub const MAX_LEVEL: u8 = Level::Debug as u8;
+#[cfg(not(any(feature = "max_level_off", feature = "max_level_error", feature = "max_level_warn",
+              feature = "max_level_info", feature = "max_level_debug")))]
+pub const MAX_LEVEL: u8 = Level::Trace as u8;
+
+/// Level filter used for modules without a filter of their own.
+const DEFAULT_FILTER: u8 = Level::Info as u8;
+/// Maximum number of per-module filters.
+const FILTER_COUNT: usize = 8;
+/// Maximum length of the module path of a filter.
+const FILTER_MODULE_LENGTH: usize = 64;
+
+/// Level filter of a module and all its submodules.
+#[derive(Clone, Copy)]
+struct Filter
+{
+	module: [u8; FILTER_MODULE_LENGTH],
+	module_length: usize,
+	level: u8,
+}
+
+impl Filter
+{
+	/// Whether the filter applies to `module`.
+	fn matches(&self, module: &str) -> bool {
+		let prefix = &self.module[0..self.module_length];
+		let module = module.as_bytes();
+		module.starts_with(prefix) &&
+			(module.len() == prefix.len() || module[prefix.len()..].starts_with(b"::"))
+	}
+}
+
+/// Level filters, set from the command line.
+struct Filters
+{
+	default: u8,
+	filters: [Filter; FILTER_COUNT],
+	count: usize,
+}
+
+const EMPTY_FILTER: Filter = Filter { module: [0; FILTER_MODULE_LENGTH], module_length: 0, level: 0 };
+
+static FILTERS: RwLock<Filters> = RwLock::new(Filters {
+	default: DEFAULT_FILTER,
+	filters: [EMPTY_FILTER; FILTER_COUNT],
+	count: 0,
+});
+
+/// Whether a message at `level` from `module` is written. The filter
+/// with the longest module path matching `module` applies.
+#[inline]
+pub fn enabled(level: Level, module: &str) -> bool {
+	if level as u8 > MAX_LEVEL {
+		return false;
+	}
+
+	let filters = FILTERS.read();
+	let mut filter = filters.default;
+	let mut filter_length = 0;
+	for current in filters.filters[0..filters.count].iter() {
+		if current.module_length >= filter_length && current.matches(module) {
+			filter = current.level;
+			filter_length = current.module_length;
+		}
+	}
+	level as u8 <= filter
+}
+
+/// Set the level filters from the kernel command line. The `log`
+/// option takes comma-separated directives: a level sets the default
+/// filter, and `module=level` the filter of a module and its
+/// submodules, as in `log=warn,kernel::sched=trace`. Levels are
+/// `off`, `error`, `warn`, `info`, `debug` and `trace`.
+pub fn init(command_line: &str) {
+	let mut filters = Filters {
+		default: DEFAULT_FILTER,
+		filters: [EMPTY_FILTER; FILTER_COUNT],
+		count: 0,
+	};
+
+	for option in command_line.split(' ') {
+		if !option.starts_with("log=") {
+			continue;
+		}
+
+		for directive in option[4..].split(',').filter(|d| d.len() > 0) {
+			let mut parts = directive.splitn(2, '=');
+			let first = parts.next().unwrap();
+			match (parts.next(), parse_filter(first)) {
+				(None, Some(level)) => filters.default = level,
+				(Some(level), _) if filters.count < FILTER_COUNT &&
+					first.len() <= FILTER_MODULE_LENGTH && parse_filter(level).is_some() =>
+				{
+					let filter = &mut filters.filters[filters.count];
+					filter.module[0..first.len()].copy_from_slice(first.as_bytes());
+					filter.module_length = first.len();
+					filter.level = parse_filter(level).unwrap();
+					filters.count += 1;
+				},
+				_ => warn!("ignoring log directive \"{}\"", directive),
+			}
+		}
+	}
+
+	*FILTERS.write() = filters;
+}
+
+/// Length of the in-memory log ring, holding the most recent output.
+pub const RING_LENGTH: usize = 16 * 1024;
+
+/// In-memory copy of the log output. Positions count every byte ever
+/// written, so that a reader can continue where it stopped.
+struct Ring
+{
+	data: [u8; RING_LENGTH],
+	end: u64,
+}
+
+static RING: Mutex<Ring> = Mutex::new(Ring {
+	data: [0; RING_LENGTH],
+	end: 0,
+});
+
+impl Ring
+{
+	/// Append bytes, overwriting the oldest ones.
+	fn push(&mut self, bytes: &[u8]) {
+		for b in bytes {
+			self.data[(self.end % RING_LENGTH as u64) as usize] = *b;
+			self.end += 1;
+		}
+	}
+
+	/// Position of the oldest byte still in the ring.
+	fn start(&self) -> u64 {
+		if self.end > RING_LENGTH as u64 { self.end - RING_LENGTH as u64 } else { 0 }
+	}
+}
+
+/// Read the log output from `position` into `buf`. A position older
+/// than the ring is moved to the oldest byte kept. Returns the
+/// position after the bytes read and the number of bytes read.
+pub fn read_ring(position: u64, buf: &mut [u8]) -> (u64, usize) {
+	let ring = RING.lock();
+	let mut position = ::core::cmp::min(::core::cmp::max(position, ring.start()), ring.end);
+	let length = ::core::cmp::min(buf.len() as u64, ring.end - position) as usize;
+
+	for b in buf[0..length].iter_mut() {
+		*b = ring.data[(position % RING_LENGTH as u64) as usize];
+		position += 1;
+	}
+
+	(position, length)
+}
 
 /// A formatter object
 pub struct Writer(bool);
@@ -12,21 +219,28 @@ static LOGGING_LOCK: atomic::AtomicBool = atomic::ATOMIC_BOOL_INIT;
 
 impl Writer
 {
-	/// Obtain a logger for the specified module
-	pub fn get(module: &str) -> Writer {
+	/// Obtain a logger for the specified level and module
+	pub fn get(level: Level, module: &str) -> Writer {
 		// This "acquires" the lock (actually just disables output if paralel writes are attempted
 		let mut ret = Writer( ! LOGGING_LOCK.swap(true, atomic::Ordering::Acquire) );
 		
-		// Print the module name before returning (prefixes all messages)
+		// Print the level and the module name before returning (prefixes all messages)
 		{
 			use core::fmt::Write;
-			let _ = write!(&mut ret, "[{}] ", module);
+			let _ = write!(&mut ret, "[{} {}] ", level.name(), module);
 		}
 		
 		ret
 	}
 }
 
+/// Release the output lock, whoever holds it. Used by the panic
+/// handler, as a panic may happen while a message is being written.
+pub unsafe fn force_unlock()
+{
+	LOGGING_LOCK.store(false, atomic::Ordering::Release);
+}
+
 impl ::core::ops::Drop for Writer
 {
 	fn drop(&mut self)
@@ -53,6 +267,10 @@ impl fmt::Write for Writer
 			unsafe {
 				::arch::debug::puts( s );
 			}
+			// The ring is skipped if it is busy, so that a panic while it is locked still logs
+			if let Some(mut ring) = RING.try_lock() {
+				ring.push( s.as_bytes() );
+			}
 		}
 		Ok( () )
 	}
@@ -1,11 +1,46 @@
-/// A very primitive logging macro
+/// Log a message at a level
 ///
-/// Obtaines a logger instance (locking the log channel) with the current module name passed
-/// then passes the standard format! arguments to it
-macro_rules! log{
-	( $($arg:tt)* ) => ({
+/// Checks the message against the compile-time maximum level and the
+/// command line filters of the current module, then obtains a logger
+/// instance (locking the log channel) and passes the standard format!
+/// arguments to it
+macro_rules! log_at{
+	( $level:expr, $($arg:tt)* ) => ({
 		// Import the Writer trait (required by write!)
 		use core::fmt::Write;
-		let _ = write!(&mut ::logging::Writer::get(module_path!()), $($arg)*);
+		let level = $level;
+		if ::logging::enabled(level, module_path!()) {
+			let _ = write!(&mut ::logging::Writer::get(level, module_path!()), $($arg)*);
+		}
 	})
 }
+
+/// Log an error: the kernel or a task cannot continue as expected
+macro_rules! error{
+	( $($arg:tt)* ) => (log_at!(::logging::Level::Error, $($arg)*))
+}
+
+/// Log a warning: a request was rejected or something unexpected was ignored
+macro_rules! warn{
+	( $($arg:tt)* ) => (log_at!(::logging::Level::Warn, $($arg)*))
+}
+
+/// Log an informational message
+macro_rules! info{
+	( $($arg:tt)* ) => (log_at!(::logging::Level::Info, $($arg)*))
+}
+
+/// Log a debugging message, such as the boot memory layout
+macro_rules! debug{
+	( $($arg:tt)* ) => (log_at!(::logging::Level::Debug, $($arg)*))
+}
+
+/// Log a tracing message, such as each page mapped
+macro_rules! trace{
+	( $($arg:tt)* ) => (log_at!(::logging::Level::Trace, $($arg)*))
+}
+
+/// A very primitive logging macro, logging at the info level
+macro_rules! log{
+	( $($arg:tt)* ) => (info!($($arg)*))
+}
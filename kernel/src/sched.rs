@@ -0,0 +1,668 @@
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering, ATOMIC_BOOL_INIT, ATOMIC_USIZE_INIT};
+use util::{Mutex};
+use arch::{self, Exception, MAX_CPU_COUNT};
+use cap::{TaskCap, TaskStatus};
+
+/// Number of task priorities. Priority 0 is the lowest.
+pub const PRIORITY_COUNT: usize = 8;
+
+/// Priority of newly created tasks.
+pub const DEFAULT_PRIORITY: u8 = 4;
+
+/// Number of timer ticks a task runs before it is moved to the back of
+/// its ready queue, unless set otherwise.
+pub const DEFAULT_TIMESLICE: u64 = 2;
+
+/// Timer ticks since the scheduler started.
+static TICKS: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Whether each processor is idling, indexed by processor index.
+static IDLE: [AtomicBool; MAX_CPU_COUNT] = [
+    ATOMIC_BOOL_INIT, ATOMIC_BOOL_INIT, ATOMIC_BOOL_INIT, ATOMIC_BOOL_INIT,
+    ATOMIC_BOOL_INIT, ATOMIC_BOOL_INIT, ATOMIC_BOOL_INIT, ATOMIC_BOOL_INIT,
+];
+
+/// Number of slots in the timer wheel.
+const TIMER_WHEEL_LENGTH: usize = 64;
+
+/// Sleeping tasks, in slots indexed by their wake-up tick modulo
+/// `TIMER_WHEEL_LENGTH`. Tasks in a slot are linked through the
+/// `next_sleeping` field in their descriptor.
+static TIMER_WHEEL: Mutex<[Option<TaskCap>; TIMER_WHEEL_LENGTH]> = Mutex::new([
+    None, None, None, None, None, None, None, None,
+    None, None, None, None, None, None, None, None,
+    None, None, None, None, None, None, None, None,
+    None, None, None, None, None, None, None, None,
+    None, None, None, None, None, None, None, None,
+    None, None, None, None, None, None, None, None,
+    None, None, None, None, None, None, None, None,
+    None, None, None, None, None, None, None, None,
+]);
+
+/// Count a timer tick, and wake up tasks whose sleep has ended or
+/// whose budget has been replenished. Only timer ticks of the boot
+/// processor are counted.
+pub fn tick() {
+    if arch::cpu_index() != 0 {
+        return;
+    }
+
+    let now = TICKS.fetch_add(1, Ordering::SeqCst) as u64 + 1;
+
+    let mut next = {
+        let mut wheel = TIMER_WHEEL.lock();
+        wheel[(now as usize) % TIMER_WHEEL_LENGTH].take()
+    };
+
+    while let Some(task_cap) = next {
+        let status = {
+            let mut task = task_cap.write();
+            next = task.set_next_sleeping(None);
+            task.set_sleeping(false);
+            task.status()
+        };
+
+        match status {
+            TaskStatus::Sleep(until) if until > now => {
+                insert_timer(task_cap, until);
+            },
+            TaskStatus::Sleep(_) => {
+                task_cap.write().set_status(TaskStatus::Active);
+                enqueue(task_cap);
+            },
+            TaskStatus::Active => {
+                enqueue(task_cap);
+            },
+            _ => (),
+        }
+    }
+}
+
+/// Put a task into the timer wheel slot of tick `until`, unless it is
+/// in the timer wheel already.
+fn insert_timer(task_cap: TaskCap, until: u64) {
+    let mut wheel = TIMER_WHEEL.lock();
+    let slot = (until as usize) % TIMER_WHEEL_LENGTH;
+
+    let mut task = task_cap.write();
+    if !task.is_sleeping() {
+        task.set_sleeping(true);
+        task.set_next_sleeping(wheel[slot].take());
+        wheel[slot] = Some(task_cap.clone());
+    }
+}
+
+/// Take a task out of the timer wheel, if it is in it, so that it can
+/// be put into another slot.
+fn remove_timer(task_cap: &TaskCap) {
+    let mut wheel = TIMER_WHEEL.lock();
+    if !task_cap.read().is_sleeping() {
+        return;
+    }
+
+    for slot in wheel.iter_mut() {
+        let is_first = slot.as_ref().map_or(false, |first| first.ptr_eq(task_cap));
+        if is_first {
+            let mut task = task_cap.write();
+            *slot = task.set_next_sleeping(None);
+            task.set_sleeping(false);
+            return;
+        }
+
+        let mut next = slot.clone();
+        while let Some(current) = next {
+            let mut current_task = current.write();
+
+            let is_prev = current_task.next_sleeping().map_or(false, |next| next.ptr_eq(task_cap));
+            if is_prev {
+                let mut task = task_cap.write();
+                current_task.set_next_sleeping(task.set_next_sleeping(None));
+                task.set_sleeping(false);
+                return;
+            }
+
+            next = current_task.next_sleeping();
+        }
+    }
+}
+
+/// Mark whether the current processor is idling. A processor must be
+/// marked idle before it leaves the kernel to idle, so that a task
+/// made runnable in the meantime still wakes it up.
+pub fn set_idle(idle: bool) {
+    IDLE[arch::cpu_index()].store(idle, Ordering::SeqCst);
+}
+
+/// Send a reschedule IPI to processor `cpu` if it is idling, so that
+/// it picks up a task queued for it without waiting for its next
+/// timer tick.
+fn wake_idle(cpu: usize) {
+    if cpu != arch::cpu_index() && IDLE[cpu].swap(false, Ordering::SeqCst) {
+        arch::send_reschedule(cpu);
+    }
+}
+
+/// Put a task to sleep for `ticks` timer ticks. A task sleeping for no
+/// ticks stays active, since the wheel slot of the current tick has
+/// already been processed.
+pub fn sleep(task_cap: &TaskCap, ticks: u64) {
+    let now = self::ticks();
+    let until = now.saturating_add(ticks);
+    if until <= now {
+        set_status(task_cap, TaskStatus::Active);
+        return;
+    }
+
+    remove_timer(task_cap);
+    task_cap.write().set_status(TaskStatus::Sleep(until));
+    insert_timer(task_cap.clone(), until);
+}
+
+/// End the donation of a scheduling context to a task, when it waits
+/// on a channel again or is deleted. The task that donated the
+/// context becomes active again, unless its status has been changed
+/// in the meantime.
+pub fn end_donation(task_cap: &TaskCap) {
+    let donor = {
+        let mut task = task_cap.write();
+        task.set_donated_sched_context(None);
+        task.set_donor(None)
+    };
+
+    if let Some(donor) = donor {
+        let waiting = match donor.read().status() {
+            TaskStatus::DonationWait => true,
+            _ => false,
+        };
+        if waiting {
+            set_status(&donor, TaskStatus::Active);
+        }
+    }
+}
+
+/// Change the status of a task. A task waiting on a channel stops
+/// waiting, a task in the timer wheel is taken out of it, and a task
+/// that becomes active is queued.
+pub fn set_status(task_cap: &TaskCap, status: TaskStatus) {
+    remove_timer(task_cap);
+
+    let waiting = match task_cap.read().status() {
+        TaskStatus::ChannelWait(chan) => Some(chan),
+        _ => None,
+    };
+    if let Some(chan) = waiting {
+        chan.cancel_wait(task_cap);
+    }
+
+    let active = match status {
+        TaskStatus::Active => true,
+        _ => false,
+    };
+    task_cap.write().set_status(status);
+    if active {
+        enqueue(task_cap.clone());
+    }
+}
+
+/// Timer ticks since the scheduler started.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::SeqCst) as u64
+}
+
+/// Maximum number of pending budget replenishments of a time account.
+const MAX_REPLENISHMENTS: usize = 8;
+
+/// Budget ticks given back to a time account at tick `at`.
+#[derive(Debug, Clone, Copy)]
+struct Replenishment {
+    at: u64,
+    amount: u64,
+}
+
+const NO_REPLENISHMENT: Replenishment = Replenishment { at: 0, amount: 0 };
+
+/// CPU time accounting of a task or a scheduling context, in timer
+/// ticks. A task runs for a timeslice before other tasks of the same
+/// priority get to run. A task with a budget may only run `budget`
+/// ticks in any `period` ticks. The budget is a sporadic server: the
+/// ticks used by each run of the task are given back `period` ticks
+/// after the run started.
+#[derive(Debug, Clone)]
+pub struct TimeAccount {
+    timeslice: u64,
+    slice_left: u64,
+    budget: Option<(u64, u64)>,
+    replenishments: [Replenishment; MAX_REPLENISHMENTS],
+    replenishment_count: usize,
+    cpu_ticks: u64,
+}
+
+impl TimeAccount {
+    /// Create a time account with the default timeslice and no
+    /// budget.
+    pub const fn new() -> TimeAccount {
+        TimeAccount {
+            timeslice: DEFAULT_TIMESLICE,
+            slice_left: DEFAULT_TIMESLICE,
+            budget: None,
+            replenishments: [NO_REPLENISHMENT; MAX_REPLENISHMENTS],
+            replenishment_count: 0,
+            cpu_ticks: 0,
+        }
+    }
+
+    /// Set the timeslice length.
+    pub fn set_timeslice(&mut self, timeslice: u64) {
+        assert!(timeslice > 0);
+        self.timeslice = timeslice;
+        self.slice_left = timeslice;
+    }
+
+    /// Set the budget as `(budget, period)`, or remove it with `None`.
+    pub fn set_budget(&mut self, budget: Option<(u64, u64)>) {
+        self.budget = budget;
+        self.replenishment_count = 0;
+    }
+
+    /// Total ticks the task has run.
+    pub fn cpu_ticks(&self) -> u64 {
+        self.cpu_ticks
+    }
+
+    /// Budget ticks used and not yet given back.
+    fn budget_used(&self) -> u64 {
+        self.replenishments[..self.replenishment_count].iter()
+            .fold(0, |used, replenishment| used + replenishment.amount)
+    }
+
+    /// The tick at which some budget is given back, if it is used up.
+    pub fn replenish_at(&self) -> Option<u64> {
+        match self.budget {
+            Some((budget, _)) if self.budget_used() >= budget => {
+                Some(self.replenishments[0].at)
+            },
+            _ => None,
+        }
+    }
+
+    /// Count a tick run on another time account, without charging
+    /// this one.
+    pub fn count_tick(&mut self) {
+        self.cpu_ticks += 1;
+    }
+
+    /// Give back the budget of replenishments that are due at `now`.
+    fn replenish(&mut self, now: u64) {
+        let due = self.replenishments[..self.replenishment_count].iter()
+            .take_while(|replenishment| replenishment.at <= now)
+            .count();
+
+        for i in due..self.replenishment_count {
+            self.replenishments[i - due] = self.replenishments[i];
+        }
+        self.replenishment_count -= due;
+    }
+
+    /// Whether the task is allowed to run at `now`.
+    pub fn is_eligible(&mut self, now: u64) -> bool {
+        self.replenish(now);
+
+        match self.budget {
+            Some((budget, _)) => self.budget_used() < budget,
+            None => true,
+        }
+    }
+
+    /// Use a budget tick at `now`. The tick is given back together
+    /// with the ticks of the same run, or in a new replenishment if
+    /// the task did not run at the previous tick. If there are too
+    /// many pending replenishments, the tick is merged into the last
+    /// one, which is then delayed to when the tick is due.
+    fn use_budget(&mut self, now: u64, period: u64) {
+        let at = now.saturating_add(period);
+
+        if self.replenishment_count > 0 {
+            let last = &mut self.replenishments[self.replenishment_count - 1];
+            if last.at.saturating_add(last.amount) == at {
+                last.amount += 1;
+                return;
+            }
+            if self.replenishment_count == MAX_REPLENISHMENTS {
+                last.at = at;
+                last.amount += 1;
+                return;
+            }
+        }
+
+        self.replenishments[self.replenishment_count] = Replenishment {
+            at: at,
+            amount: 1,
+        };
+        self.replenishment_count += 1;
+    }
+
+    /// Charge a timer tick to the task.
+    pub fn charge(&mut self, now: u64) {
+        self.cpu_ticks += 1;
+
+        self.replenish(now);
+        if let Some((_, period)) = self.budget {
+            self.use_budget(now, period);
+        }
+
+        self.slice_left = self.slice_left.saturating_sub(1);
+    }
+
+    /// Give up the rest of the current timeslice.
+    pub fn yield_slice(&mut self) {
+        self.slice_left = 0;
+    }
+
+    /// Whether the timeslice is used up. If so, a new one is started.
+    pub fn take_expired(&mut self) -> bool {
+        if self.slice_left == 0 {
+            self.slice_left = self.timeslice;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// A ready queue of tasks having the same priority. Tasks are linked
+/// through the `next_ready` field in their descriptor.
+struct ReadyQueue {
+    head: Option<TaskCap>,
+    tail: Option<TaskCap>,
+    length: usize,
+}
+
+impl ReadyQueue {
+    /// Create an empty ready queue.
+    const fn new() -> ReadyQueue {
+        ReadyQueue {
+            head: None,
+            tail: None,
+            length: 0,
+        }
+    }
+
+    /// Put a task at the end of the queue.
+    fn push_back(&mut self, task_cap: TaskCap) {
+        task_cap.write().set_ready(true);
+
+        if let Some(tail) = self.tail.take() {
+            tail.write().set_next_ready(Some(task_cap.clone()));
+        } else {
+            self.head = Some(task_cap.clone());
+        }
+        self.tail = Some(task_cap);
+        self.length += 1;
+    }
+
+    /// Put a task at the front of the queue.
+    fn push_front(&mut self, task_cap: TaskCap) {
+        {
+            let mut task = task_cap.write();
+            task.set_ready(true);
+            task.set_next_ready(self.head.take());
+        }
+
+        if self.tail.is_none() {
+            self.tail = Some(task_cap.clone());
+        }
+        self.head = Some(task_cap);
+        self.length += 1;
+    }
+
+    /// Take the task at the front of the queue.
+    fn pop_front(&mut self) -> Option<TaskCap> {
+        let head = self.head.take();
+
+        if let Some(ref head) = head {
+            let mut task = head.write();
+            task.set_ready(false);
+            self.head = task.set_next_ready(None);
+            self.length -= 1;
+        }
+        if self.head.is_none() {
+            self.tail = None;
+        }
+
+        head
+    }
+}
+
+/// Create ready queues of a processor, indexed by priority.
+const fn ready_queues() -> [ReadyQueue; PRIORITY_COUNT] {
+    [ReadyQueue::new(), ReadyQueue::new(), ReadyQueue::new(), ReadyQueue::new(),
+     ReadyQueue::new(), ReadyQueue::new(), ReadyQueue::new(), ReadyQueue::new()]
+}
+
+/// Ready queues of all processors.
+type ReadyQueues = [[ReadyQueue; PRIORITY_COUNT]; MAX_CPU_COUNT];
+
+/// Ready queues, indexed by processor and then by priority.
+static READY_QUEUES: Mutex<ReadyQueues> = Mutex::new([
+    ready_queues(), ready_queues(), ready_queues(), ready_queues(),
+    ready_queues(), ready_queues(), ready_queues(), ready_queues(),
+]);
+
+/// Task each processor is running, indexed by processor. It is set
+/// from `next` until `requeue`.
+static CURRENT: Mutex<[Option<TaskCap>; MAX_CPU_COUNT]> = Mutex::new([
+    None, None, None, None, None, None, None, None,
+]);
+
+/// The task the current processor is running, if any. `None` is also
+/// returned if the record is locked, as this is meant for the panic
+/// handler.
+pub fn try_current() -> Option<TaskCap> {
+    CURRENT.try_lock().and_then(|current| current[arch::cpu_index()].clone())
+}
+
+/// Priority of a task, taken from its scheduling context if it has
+/// one.
+fn priority(task_cap: &TaskCap) -> u8 {
+    let sched_context = task_cap.read().sched_context();
+    match sched_context {
+        Some(sched_context) => {
+            let sched_context_desc = sched_context.read();
+            sched_context_desc.priority()
+        },
+        None => {
+            let task = task_cap.read();
+            task.priority()
+        },
+    }
+}
+
+/// Run `f` with the time account of a task, taken from its
+/// scheduling context if it has one.
+fn with_time<R, F: FnOnce(&mut TimeAccount) -> R>(task_cap: &TaskCap, f: F) -> R {
+    let sched_context = task_cap.read().sched_context();
+    match sched_context {
+        Some(sched_context) => {
+            let mut sched_context_desc = sched_context.write();
+            f(sched_context_desc.time_mut())
+        },
+        None => {
+            let mut task = task_cap.write();
+            f(task.time_mut())
+        },
+    }
+}
+
+/// Whether the task is active, and has a scheduling context to run
+/// on.
+fn is_active(task_cap: &TaskCap) -> bool {
+    let task = task_cap.read();
+    let active = match task.status() { TaskStatus::Active => true, _ => false };
+    active && (!task.is_passive() || task.donated_sched_context().is_some())
+}
+
+/// Whether the task is active, has a scheduling context to run on,
+/// and is within its budget.
+fn is_runnable(task_cap: &TaskCap) -> bool {
+    is_active(task_cap) && with_time(task_cap, |time| time.is_eligible(ticks()))
+}
+
+/// Whether the task is allowed to run on processor `cpu` by its
+/// affinity.
+fn is_allowed_on(task_cap: &TaskCap, cpu: usize) -> bool {
+    task_cap.read().affinity().map_or(true, |affinity| affinity == cpu)
+}
+
+/// Queue a task that may have become runnable. Tasks already queued
+/// or running are left alone; a running task is queued again by
+/// `requeue`. This is called on the state transitions that can make
+/// a task runnable, such as activation, wake-ups and channel
+/// deliveries, so that picking a task never scans all tasks.
+pub fn enqueue(task_cap: TaskCap) {
+    let mut queues = READY_QUEUES.lock();
+    push(&mut queues, task_cap, false);
+}
+
+/// Number of tasks queued on or running on processor `cpu`.
+fn load(queues: &ReadyQueues, cpu: usize) -> usize {
+    let queued = queues[cpu].iter().fold(0, |load, queue| load + queue.length);
+    let running = if CURRENT.lock()[cpu].is_some() { 1 } else { 0 };
+    queued + running
+}
+
+/// The processor with the fewest tasks queued or running, preferring
+/// the current one.
+fn least_loaded(queues: &ReadyQueues) -> usize {
+    let current = arch::cpu_index();
+    let mut best = (current, load(queues, current));
+
+    for cpu in 0..arch::cpu_count() {
+        let load = load(queues, cpu);
+        if load < best.1 {
+            best = (cpu, load);
+        }
+    }
+
+    best.0
+}
+
+/// Put a task that is neither queued nor running into the ready queue
+/// of its priority, at the front or the back. The task goes to the
+/// processor it is restricted to. Otherwise, a task keeping its place
+/// at the front stays on the current processor, and others go to the
+/// least loaded processor, which is woken up if it idles. An active task
+/// that has used up its budget waits in the timer wheel until the
+/// budget is replenished instead, and other tasks are left out.
+fn push(queues: &mut ReadyQueues, task_cap: TaskCap, front: bool) {
+    let (ready, running, affinity) = {
+        let task = task_cap.read();
+        (task.is_ready(), task.is_running(), task.affinity())
+    };
+
+    if ready || running || !is_active(&task_cap) {
+        return;
+    }
+
+    if !with_time(&task_cap, |time| time.is_eligible(ticks())) {
+        let replenish_at = with_time(&task_cap, |time| time.replenish_at());
+        if let Some(replenish_at) = replenish_at {
+            insert_timer(task_cap, replenish_at);
+        }
+        return;
+    }
+
+    let cpu = match affinity {
+        Some(cpu) => cpu,
+        None if front => arch::cpu_index(),
+        None => least_loaded(queues),
+    };
+    let priority = priority(&task_cap) as usize;
+    if front {
+        queues[cpu][priority].push_front(task_cap);
+    } else {
+        queues[cpu][priority].push_back(task_cap);
+    }
+    wake_idle(cpu);
+}
+
+/// Pick the next task to run on the current processor. This is the
+/// first runnable task in the highest-priority non-empty ready queue
+/// of the processor. If the processor has no runnable task, one is
+/// stolen from another processor. The task is marked running until it
+/// is requeued. Queued tasks that are no longer runnable are dropped
+/// from the queues, and those no longer allowed on the processor are
+/// moved. `None` is returned if no task is runnable.
+pub fn next() -> Option<TaskCap> {
+    let cpu = arch::cpu_index();
+
+    let mut queues = READY_QUEUES.lock();
+    let next = pick(&mut queues, cpu).or_else(|| steal(&mut queues, cpu));
+    if let Some(ref task_cap) = next {
+        task_cap.write().set_running(true);
+        CURRENT.lock()[cpu] = Some(task_cap.clone());
+    }
+    next
+}
+
+/// Take the first runnable task in the highest-priority non-empty
+/// ready queue of processor `cpu`.
+fn pick(queues: &mut ReadyQueues, cpu: usize) -> Option<TaskCap> {
+    for priority in (0..PRIORITY_COUNT).rev() {
+        while let Some(task_cap) = queues[cpu][priority].pop_front() {
+            if is_allowed_on(&task_cap, cpu) && is_runnable(&task_cap) {
+                return Some(task_cap);
+            }
+            push(queues, task_cap, false);
+        }
+    }
+
+    None
+}
+
+/// Take a runnable task of the highest priority from the front of
+/// another processor's ready queues, for the idle processor `cpu`.
+/// Tasks restricted to their processor are left in place.
+fn steal(queues: &mut ReadyQueues, cpu: usize) -> Option<TaskCap> {
+    for priority in (0..PRIORITY_COUNT).rev() {
+        for other in 0..arch::cpu_count() {
+            if other == cpu {
+                continue;
+            }
+
+            let allowed = queues[other][priority].head.as_ref()
+                .map_or(false, |head| is_allowed_on(head, cpu) && is_runnable(head));
+            if allowed {
+                return queues[other][priority].pop_front();
+            }
+        }
+    }
+
+    None
+}
+
+/// Put a task back to a ready queue after it has run and the
+/// exception has been handled. Timer ticks are charged to the task.
+/// A task that has used up or yielded its timeslice goes to the back
+/// of the queue, so that other tasks of the same priority get to run.
+/// Otherwise it keeps its place at the front. A task that has used up
+/// its budget is queued again when the budget is replenished.
+pub fn requeue(task_cap: TaskCap, exception: &Exception) {
+    let cpu = arch::cpu_index();
+
+    if let &Exception::Timer = exception {
+        tick();
+        with_time(&task_cap, |time| time.charge(ticks()));
+
+        let has_sched_context = task_cap.read().sched_context().is_some();
+        if has_sched_context {
+            task_cap.write().time_mut().count_tick();
+        }
+    }
+
+    let expired = with_time(&task_cap, |time| time.take_expired());
+
+    let mut queues = READY_QUEUES.lock();
+    task_cap.write().set_running(false);
+    CURRENT.lock()[cpu] = None;
+    push(&mut queues, task_cap, !expired);
+}
@@ -1,10 +1,59 @@
+use core::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
+use util::LossyStr;
+
+/// Value written to the QEMU isa-debug-exit device on a panic, so that
+/// QEMU exits with status 3.
+pub const PANIC_EXIT_CODE: u8 = 0x1;
+
+/// Set by the first panic. A processor panicking after it, including
+/// while the first panic is being reported, halts right away.
+static PANICKING: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// Log an error regardless of the log filters, so that a panic report
+/// is never hidden
+macro_rules! panic_log{
+	( $($arg:tt)* ) => ({
+		use core::fmt::Write;
+		let _ = write!(&mut ::logging::Writer::get(::logging::Level::Error, module_path!()), $($arg)*);
+	})
+}
+
 #[lang="panic_fmt"]
 #[no_mangle]
 pub extern "C" fn rust_begin_unwind(args: ::core::fmt::Arguments, file: &str, line: usize) -> !
 {
+	unsafe { ::arch::disable_interrupt(); }
+	if PANICKING.swap(true, Ordering::SeqCst) {
+		::arch::halt();
+	}
+	::arch::halt_others();
+	unsafe { ::logging::force_unlock(); }
+
 	// 'args' will print to the formatted string passed to panic!
-	log!("file='{}', line={} :: {}", file, line, args);
-	loop {}
+	panic_log!("panic: file='{}', line={} :: {}", file, line, args);
+
+	// The task descriptor may be locked by the code that panicked
+	match ::sched::try_current() {
+		Some(task_cap) => match task_cap.try_read() {
+			Some(task) => {
+				panic_log!("current task: {:?} name: \"{}\"", task_cap, LossyStr(task.name()));
+				panic_log!("saved registers: {:?}", task.registers());
+			},
+			None => panic_log!("current task: {:?} (locked)", task_cap),
+		},
+		None => panic_log!("no current task"),
+	}
+
+	panic_log!("backtrace:");
+	::arch::backtrace(|addr| {
+		match ::arch::symbol(addr) {
+			Some((name, offset)) => panic_log!("  0x{:016x} {}+0x{:x}", addr, name, offset),
+			None => panic_log!("  0x{:016x}", addr),
+		}
+	});
+
+	::arch::exit_qemu(PANIC_EXIT_CODE);
+	::arch::halt();
 }
 
 #[allow(non_camel_case_types)]
@@ -0,0 +1,24 @@
+use core::fmt;
+use core::str;
+
+/// Bytes displayed as UTF-8 text. Each byte that is not part of a
+/// valid UTF-8 sequence is displayed as U+FFFD.
+pub struct LossyStr<'a>(pub &'a [u8]);
+
+impl<'a> fmt::Display for LossyStr<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut rest = self.0;
+
+        loop {
+            match str::from_utf8(rest) {
+                Ok(s) => return f.write_str(s),
+                Err(error) => {
+                    let valid = error.valid_up_to();
+                    try!(f.write_str(unsafe { str::from_utf8_unchecked(&rest[0..valid]) }));
+                    try!(f.write_str("\u{FFFD}"));
+                    rest = &rest[(valid + 1)..];
+                },
+            }
+        }
+    }
+}
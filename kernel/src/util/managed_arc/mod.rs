@@ -16,7 +16,8 @@ mod rwlock;
 mod weak_pool;
 
 pub use self::rwlock::{ManagedArcRwLockReadGuard, ManagedArcRwLockWriteGuard};
-pub use self::weak_pool::{ManagedWeakPool1Arc, ManagedWeakPool3Arc, ManagedWeakPool256Arc};
+pub use self::weak_pool::{ManagedWeakPool1Arc, ManagedWeakPool3Arc, ManagedWeakPool8Arc,
+                          ManagedWeakPool256Arc};
 
 /// A weak node (entry of a weak pool).
 #[derive(Debug)]
@@ -36,7 +37,9 @@ struct ManagedWeakAddr {
 }
 
 /// Inner of an Arc, containing strong pointers and weak pointers
-/// information. Wrap the actual data.
+/// information. Wrap the actual data. The layout is fixed so that
+/// `lead` and `first_weak` can be accessed without knowing `T`.
+#[repr(C)]
 struct ManagedArcInner<T> {
     lead: Mutex<usize>,
     // TODO: Implement weak pool lock.
@@ -129,7 +132,7 @@ impl<T: Any> Into<ManagedArcAny> for ManagedArc<T> {
 
 impl Drop for ManagedArcAny {
     fn drop(&mut self) {
-        log!("Error: trying to drop a ManagedArcAny.");
+        error!("trying to drop a ManagedArcAny.");
         panic!();
     }
 }
@@ -175,6 +178,11 @@ impl<T> ManagedArc<T> {
         unsafe { MemoryObject::<ManagedArcInner<T>>::new(self.ptr) }
     }
 
+    /// Whether two Arcs point to the same inner object.
+    pub fn ptr_eq(&self, other: &Self) -> bool {
+        self.ptr == other.ptr
+    }
+
     /// Get the strong pointers count.
     pub fn lead_count(&self) -> usize {
         let inner = self.inner_object();
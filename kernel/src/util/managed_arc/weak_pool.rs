@@ -14,6 +14,8 @@ use super::{ManagedArc, ManagedArcAny, ManagedArcInner, ManagedWeakAddr, Managed
 pub struct ManagedWeakPool1([Mutex<Option<ManagedWeakNode>>; 1], PAddr);
 /// Managed weak pool of size 3.
 pub struct ManagedWeakPool3([Mutex<Option<ManagedWeakNode>>; 3], PAddr);
+/// Managed weak pool of size 8.
+pub struct ManagedWeakPool8([Mutex<Option<ManagedWeakNode>>; 8], PAddr);
 /// Managed weak pool of size 256.
 pub struct ManagedWeakPool256([Mutex<Option<ManagedWeakNode>>; 256], PAddr);
 
@@ -21,6 +23,8 @@ pub struct ManagedWeakPool256([Mutex<Option<ManagedWeakNode>>; 256], PAddr);
 pub type ManagedWeakPool1Arc = ManagedArc<ManagedWeakPool1>;
 /// Managed Arc for weak pool of size 3.
 pub type ManagedWeakPool3Arc = ManagedArc<ManagedWeakPool3>;
+/// Managed Arc for weak pool of size 8.
+pub type ManagedWeakPool8Arc = ManagedArc<ManagedWeakPool8>;
 /// Managed Arc for weak pool of size 256.
 pub type ManagedWeakPool256Arc = ManagedArc<ManagedWeakPool256>;
 
@@ -137,6 +141,47 @@ macro_rules! weak_pool {
                 }
             }
 
+            /// Remove the weak pointer at `index` in this weak pool,
+            /// unlinking it from the weak pointers of its Arc. Does
+            /// nothing if the slot is empty.
+            pub fn remove_at(&self, index: usize) {
+                let mut weak_node_option = self.0[index].lock();
+                let weak_node = match weak_node_option.take() {
+                    Some(weak_node) => weak_node,
+                    None => return,
+                };
+
+                if let Some(prev_addr) = weak_node.prev {
+                    set_weak_node(prev_addr, |prev_weak_node| {
+                        assert!(prev_weak_node.is_some());
+
+                        prev_weak_node.map(|mut prev_weak_node| {
+                            prev_weak_node.next = weak_node.next;
+                            prev_weak_node
+                        })
+                    });
+                } else {
+                    // This is the first weak of the ArcInner. The
+                    // `first_weak` field does not depend on the type
+                    // of the data, as `ManagedArcInner` is `repr(C)`.
+                    let arc_inner_obj: MemoryObject<ManagedArcInner<()>> =
+                        unsafe { MemoryObject::new(weak_node.ptr) };
+                    let arc_inner = unsafe { arc_inner_obj.as_ref().unwrap() };
+                    *arc_inner.first_weak.lock() = weak_node.next;
+                }
+
+                if let Some(next_addr) = weak_node.next {
+                    set_weak_node(next_addr, |next_weak_node| {
+                        assert!(next_weak_node.is_some());
+
+                        next_weak_node.map(|mut next_weak_node| {
+                            next_weak_node.prev = weak_node.prev;
+                            next_weak_node
+                        })
+                    });
+                }
+            }
+
             /// Downgrade a strong pointer to a weak pointer, and then
             /// store it in a free slot in this weak pool.
             pub fn downgrade_free<T: Any>(&self, arc: &ManagedArc<T>) -> Option<usize>
@@ -157,6 +202,7 @@ macro_rules! weak_pool {
 
 weak_pool!(ManagedWeakPool1);
 weak_pool!(ManagedWeakPool3);
+weak_pool!(ManagedWeakPool8);
 weak_pool!(ManagedWeakPool256);
 
 fn set_weak_node<F>(addr: ManagedWeakAddr, f: F) where F: FnOnce(Option<ManagedWeakNode>) -> Option<ManagedWeakNode> {
@@ -178,6 +224,12 @@ fn set_weak_node<F>(addr: ManagedWeakAddr, f: F) where F: FnOnce(Option<ManagedW
         let inner = unsafe { inner_obj.as_ref().unwrap() };
         let mut weak_node = inner.data.0[addr.offset].lock();
         *weak_node = f((*weak_node).take());
+    } else if addr.inner_type_id == TypeId::of::<ManagedArcInner<ManagedWeakPool8>>() {
+        let inner_obj: MemoryObject<ManagedArcInner<ManagedWeakPool8>> =
+            unsafe { MemoryObject::new(addr.inner_addr) };
+        let inner = unsafe { inner_obj.as_ref().unwrap() };
+        let mut weak_node = inner.data.0[addr.offset].lock();
+        *weak_node = f((*weak_node).take());
     } else {
         panic!();
     }
@@ -7,6 +7,9 @@ mod guard;
 /// Streaming iterator
 mod streamer;
 
+/// Display of byte strings that may not be valid UTF-8.
+mod lossy;
+
 /// Managed reference-counted pointers that erases all weak pointers
 /// when the last strong pointer goes out.
 pub mod managed_arc;
@@ -18,6 +21,7 @@ pub mod field_offset;
 pub use self::object::{ExternMutex, ExternReadonlyObject, MutexGuard, MemoryObject};
 pub use self::guard::{UniqueReadGuard, UniqueWriteGuard};
 pub use self::streamer::{Streamer};
+pub use self::lossy::{LossyStr};
 pub use spin::{Mutex, RwLock};
 
 use common::{PAddr, VAddr};
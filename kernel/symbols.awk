@@ -0,0 +1,29 @@
+# Generate the kernel symbol table, looked up by the panic handler,
+# from the output of `nm -n -C`. Only text symbols are kept, and Rust
+# symbol hashes are dropped. With no input, an empty table is
+# generated for the first link.
+
+BEGIN {
+	print "\t.section .rodata.symbols, \"a\""
+	print "\t.global kernel_symbols_start"
+	print "\t.global kernel_symbols_end"
+	print "\t.balign 8"
+	print "kernel_symbols_start:"
+}
+
+$2 ~ /^[tTwW]$/ {
+	name = $3
+	for (i = 4; i <= NF; i++)
+		name = name " " $i
+	sub(/::h[0-9a-f]+$/, "", name)
+
+	length_name = length(name)
+	gsub(/\\/, "\\\\", name)
+	gsub(/"/, "\\\"", name)
+
+	printf "\t.quad 0x%s\n\t.quad %d\n\t.ascii \"%s\"\n\t.balign 8\n", $1, length_name, name
+}
+
+END {
+	print "kernel_symbols_end:"
+}
@@ -10,8 +10,14 @@ extern crate spin;
 
 #[macro_use]
 mod vga_buffer;
+/// Shell output to the serial port.
+mod serial;
+/// Kernel test scenarios, run instead of the shells in test builds.
+#[cfg(feature = "test")]
+mod test;
 
 use core::ops::{Deref};
+use core::fmt::{self, Write};
 
 /// Decode a code in the PS/2 scan code set 1 (legacy set).
 ///
@@ -108,7 +114,7 @@ fn start(_argc: isize, _argv: *const *const u8) {
         unsafe { IS_PARENT = false; }
         parent_main();
     } else {
-        child_main();
+        child_main(0x90003000);
     }
     loop {};
 }
@@ -117,15 +123,23 @@ fn parent_main() {
     let task_buffer = 0x90001000;
 
     system_print!(task_buffer, "parent rinit started.");
+    run_tests(task_buffer);
     print!("Child entry should be at: 0x{:x} ({})\nChild stack pointer should be at: 0x{:x} ({})\n",
            start as *const () as usize, start as *const () as usize,
            0x70000000 + (0x1000 * 4 - 4), 0x70000000 + (0x1000 * 4 - 4));
     print!(">>> ");
+    start_serial_shell(task_buffer);
     let mut lastkey = Key::Nonprintable;
     let mut command = [0u8; 32];
     let mut command_size = 0;
     while true {
-        let key = from_scancode(system::channel_take(task_buffer, 254) as usize);
+        // Wait for the keyboard IRQ notification, read the scan code,
+        // and then acknowledge the IRQ handler at 253.
+        system::channel_take(task_buffer, 254).unwrap();
+        let code = unsafe { vga_buffer::inportb(0x60) };
+        system::irq_ack(task_buffer, 253).unwrap();
+
+        let key = from_scancode(code as usize);
         if key == lastkey {
             continue;
         } else {
@@ -141,7 +155,8 @@ fn parent_main() {
             }
             Key::Enter => {
                 print!("\n");
-                execute_command(task_buffer, ::core::str::from_utf8(&command[0..command_size]).unwrap());
+                execute_command(task_buffer, ::core::str::from_utf8(&command[0..command_size]).unwrap(),
+                                &mut vga_buffer::Console);
                 command = [0u8; 32];
                 command_size = 0;
             }
@@ -150,23 +165,116 @@ fn parent_main() {
     }
 }
 
-fn start_child(task_buffer: usize) {
-    system::retype_task(task_buffer, 2, 249);
-    system::task_set_stack_pointer(task_buffer, 249, 0x70000000 + (0x1000 * 4 - 4));
-    system::task_set_instruction_pointer(task_buffer, 249, start as *const () as u64);
-    system::task_set_cpool(task_buffer, 249, 0);
-    system::task_set_top_page_table(task_buffer, 249, 3);
-    system::task_set_buffer(task_buffer, 249, 250);
-    system::task_set_active(task_buffer, 249);
+/// Run the kernel test scenarios and exit QEMU with the result.
+#[cfg(feature = "test")]
+fn run_tests(task_buffer: usize) {
+    test::run(task_buffer);
 }
 
-fn child_main() {
-    let task_buffer = 0x90003000;
+#[cfg(not(feature = "test"))]
+fn run_tests(_task_buffer: usize) { }
+
+/// First of the root CPool slots used by the child started with
+/// `start child`.
+const CHILD_SLOTS: usize = 240;
 
+fn start_child(task_buffer: usize) -> Result<(), system::MessageError> {
+    try!(system::thread::Builder::new(task_buffer, 2, CHILD_SLOTS)
+         .stack(0x60000000, 4)
+         .buffer(0x90004000)
+         .exit_channel(252)
+         .name("child")
+         .spawn(child_main));
+    Ok(())
+}
+
+extern "C" fn child_main(task_buffer: usize) -> ! {
     system_print!(task_buffer, "child rinit started.");
-    while true {
-        let value = system::channel_take(task_buffer, 255);
+    loop {
+        let value = system::channel_take(task_buffer, 255).unwrap();
         system_print!(task_buffer, "Received from master: {}", value);
+        if value == 0 {
+            system_print!(task_buffer, "child rinit exiting.");
+            system::task_exit(task_buffer, 0);
+        }
+    }
+}
+
+/// Root CPool slot of the kernel log capability.
+const KERNEL_LOG_SLOT: usize = 248;
+
+/// Print the kernel log ring to the shell output.
+fn dmesg(task_buffer: usize, out: &mut fmt::Write) {
+    use system::{DATA_LENGTH};
+
+    let mut position = 0;
+    loop {
+        let (next, length) = system::kernel_log_read(task_buffer, KERNEL_LOG_SLOT, position,
+                                                     0, DATA_LENGTH).unwrap();
+        if length == 0 {
+            break;
+        }
+
+        let buffer = unsafe { &*(task_buffer as *const system::TaskBuffer) };
+        for b in buffer.data[0..length].iter() {
+            out.write_char(*b as char).unwrap();
+        }
+        position = next;
+    }
+}
+
+/// Root CPool slot of the channel receiving bytes from the serial
+/// port.
+const SERIAL_CHANNEL: usize = 251;
+
+/// First of the root CPool slots used by the serial shell thread.
+const SERIAL_SHELL_SLOTS: usize = 232;
+
+fn start_serial_shell(task_buffer: usize) {
+    system::thread::Builder::new(task_buffer, 2, SERIAL_SHELL_SLOTS)
+        .stack(0x61000000, 4)
+        .buffer(0x90005000)
+        .name("serial shell")
+        .spawn(serial_shell_main)
+        .unwrap();
+}
+
+/// The same shell as on the VGA buffer, driven over the serial port so
+/// that it can be used headless. Input is echoed back, and either CR,
+/// LF or CR LF ends a command.
+extern "C" fn serial_shell_main(task_buffer: usize) -> ! {
+    let mut console = serial::Console;
+    let mut command = [0u8; 32];
+    let mut command_size = 0;
+    let mut last = 0u8;
+
+    write!(console, ">>> ").unwrap();
+    loop {
+        let byte = system::channel_take(task_buffer, SERIAL_CHANNEL).unwrap() as u8;
+        match byte {
+            b'\n' if last == b'\r' => (),
+            b'\r' | b'\n' => {
+                write!(console, "\n").unwrap();
+                execute_command(task_buffer, ::core::str::from_utf8(&command[0..command_size]).unwrap(),
+                                &mut console);
+                command_size = 0;
+            },
+            0x08 | 0x7f => {
+                if command_size > 0 {
+                    command_size -= 1;
+                    write!(console, "\x08 \x08").unwrap();
+                }
+            },
+            0x20...0x7e => {
+                if command_size < 32 {
+                    command[command_size] = byte;
+                    command_size += 1;
+                    write!(console, "{}", byte as char).unwrap();
+                }
+            },
+            _ => (),
+        }
+        last = byte;
     }
 }
 
@@ -182,51 +290,62 @@ fn parse_usize(s: &str, prefix: &str) -> Option<(usize, usize)> {
     }
 }
 
-fn execute_command(task_buffer: usize, s: &str) {
+fn execute_command(task_buffer: usize, s: &str, out: &mut fmt::Write) {
     if s == "list" {
-        print!("Listing task cpool ...\n");
-        system::cpool_list_debug(task_buffer);
+        write!(out, "Listing task cpool ...\n").unwrap();
+        system::cpool_list_debug(task_buffer).unwrap();
+    } else if s == "dmesg" {
+        dmesg(task_buffer, out);
     } else if s == "start child" {
-        start_child(task_buffer);
-        print!("Child started.\n");
+        match start_child(task_buffer) {
+            Ok(()) => write!(out, "Child started.\n").unwrap(),
+            Err(error) => write!(out, "Operation failed: {:?}\n", error).unwrap(),
+        }
     } else if s.len() >= 6 && &s[0..4] == "echo" {
-        print!("{}\n", &s[5..s.len()]);
+        write!(out, "{}\n", &s[5..s.len()]).unwrap();
+    } else if s.len() >= 7 && &s[0..5] == "sleep" {
+        let ticks: u64 = (&s[6..s.len()]).parse().unwrap();
+        system::task_sleep(task_buffer, ticks).unwrap();
+        write!(out, "Slept for {} ticks\n", ticks).unwrap();
+    } else if s == "wait child" {
+        let code = system::channel_take(task_buffer, 252).unwrap();
+        write!(out, "Child exited with code {}\n", code).unwrap();
     } else if s.len() >= 6 && &s[0..4] == "send" {
         let value: u64 = (&s[5..s.len()]).parse().unwrap();
-        system::channel_put(task_buffer, 255, value);
-        print!("Sent to child through channel 255\n");
+        system::channel_put(task_buffer, 255, value).unwrap();
+        write!(out, "Sent to child through channel 255\n").unwrap();
     } else if let Some((source, target)) = parse_usize(s, "retype cpool") {
-        system::retype_cpool(task_buffer, source, target);
-        print!("Operation finished.\n");
+        report(out, system::retype_cpool(task_buffer, source, target));
     } else if let Some((source, target)) = parse_usize(s, "retype task") {
-        system::retype_task(task_buffer, source, target);
-        print!("Operation finished.\n");
+        report(out, system::retype_task(task_buffer, source, target));
     } else if let Some((target, ptr)) = parse_usize(s, "set stack") {
-        system::task_set_stack_pointer(task_buffer, target, ptr as u64);
-        print!("Operation finished.\n");
+        report(out, system::task_set_stack_pointer(task_buffer, target, ptr as u64));
     } else if let Some((target, ptr)) = parse_usize(s, "set instruction") {
-        system::task_set_instruction_pointer(task_buffer, target, ptr as u64);
-        print!("Operation finished.\n");
+        report(out, system::task_set_instruction_pointer(task_buffer, target, ptr as u64));
     } else if let Some((target, cpool)) = parse_usize(s, "set cpool") {
-        system::task_set_cpool(task_buffer, target, cpool);
-        print!("Operation finished.\n");
+        report(out, system::task_set_cpool(task_buffer, target, cpool));
     } else if let Some((target, table)) = parse_usize(s, "set table") {
-        system::task_set_top_page_table(task_buffer, target, table);
-        print!("Operation finished.\n");
+        report(out, system::task_set_top_page_table(task_buffer, target, table));
     } else if let Some((target, buffer)) = parse_usize(s, "set buffer") {
-        system::task_set_buffer(task_buffer, target, buffer);
-        print!("Operation finished.\n");
+        report(out, system::task_set_buffer(task_buffer, target, buffer));
     } else if let Some((target, status)) = parse_usize(s, "set active") {
         if status == 0 {
-            system::task_set_inactive(task_buffer, target);
+            report(out, system::task_set_inactive(task_buffer, target));
         } else {
-            system::task_set_active(task_buffer, target);
+            report(out, system::task_set_active(task_buffer, target));
         }
-        print!("Operation finished.\n");
     } else {
-        print!("Unknown command.\n");
+        write!(out, "Unknown command.\n").unwrap();
+    }
+    write!(out, ">>> ").unwrap();
+}
+
+/// Report the result of a shell command making a system call.
+fn report(out: &mut fmt::Write, result: Result<(), system::MessageError>) {
+    match result {
+        Ok(()) => write!(out, "Operation finished.\n").unwrap(),
+        Err(error) => write!(out, "Operation failed: {:?}\n", error).unwrap(),
     }
-    print!(">>> ");
 }
 
 fn divide_by_zero() {
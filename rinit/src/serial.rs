@@ -0,0 +1,29 @@
+use core::fmt;
+use vga_buffer::{inportb, outportb};
+
+/// I/O port of the first serial port, COM1.
+const COM1: u16 = 0x3F8;
+
+/// Write a byte to the serial port, waiting until it can take one.
+fn write_byte(byte: u8) {
+    unsafe {
+        while (inportb(COM1 + 5) & 0x20) == 0 { }
+        outportb(COM1, byte);
+    }
+}
+
+/// Shell output to the serial port. Line feeds are sent as CR LF for
+/// terminals.
+pub struct Console;
+
+impl fmt::Write for Console {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            if byte == b'\n' {
+                write_byte(b'\r');
+            }
+            write_byte(byte);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,208 @@
+use core::fmt::Write;
+use core::ptr;
+use core::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use system;
+use system::thread::Builder;
+use serial::Console;
+use vga_buffer::outportb;
+
+/// I/O port of the QEMU `isa-debug-exit` device.
+const DEBUG_EXIT_PORT: u16 = 0xf4;
+/// Written to the exit device when every scenario passes. QEMU exits
+/// with status `(0x10 << 1) | 1`, which is 33.
+const EXIT_SUCCESS: u8 = 0x10;
+/// Written to the exit device when a scenario fails. QEMU exits with
+/// status 35.
+const EXIT_FAILURE: u8 = 0x11;
+
+/// Root CPool slot of the untyped capability scenarios retype from.
+const UNTYPED: usize = 2;
+/// Root CPool slot of the channel between scenarios and their threads.
+const CHANNEL: usize = 255;
+/// Root CPool slot of the channel receiving exit codes and page
+/// faults of scenario threads.
+const EXIT_CHANNEL: usize = 252;
+
+/// An unmapped address the page fault scenario touches.
+const FAULT_ADDRESS: u64 = 0x50000000;
+
+/// How far the thread of the sleep scenario has got: 1 once it is
+/// woken up from its long sleep, 2 once its short sleep has ended.
+static SLEEP_STAGE: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Run every scenario in order, report each of them and a summary over
+/// the serial port, and exit QEMU with the result. If the exit device
+/// is missing, the task just yields forever.
+pub fn run(task_buffer: usize) -> ! {
+    let scenarios: [(&'static str, fn(usize) -> bool); 7] = [
+        ("retype", retype),
+        ("channels", channels),
+        ("task spawn", task_spawn),
+        ("page fault", page_fault),
+        ("stack overflow", stack_overflow),
+        ("wakeup then shorter sleep", wakeup_then_sleep),
+        ("IRQ handler delete", irq_handler_delete),
+    ];
+
+    let mut console = Console;
+    let mut failed = 0;
+    writeln!(console, "running {} kernel test scenarios", scenarios.len()).unwrap();
+    for &(name, scenario) in scenarios.iter() {
+        let passed = scenario(task_buffer);
+        writeln!(console, "test {} ... {}", name, if passed { "ok" } else { "FAILED" }).unwrap();
+        if !passed {
+            failed += 1;
+        }
+    }
+    writeln!(console, "test result: {}. {} passed; {} failed",
+             if failed == 0 { "ok" } else { "FAILED" },
+             scenarios.len() - failed, failed).unwrap();
+
+    unsafe { outportb(DEBUG_EXIT_PORT, if failed == 0 { EXIT_SUCCESS } else { EXIT_FAILURE }); }
+    loop {
+        system::task_yield(task_buffer).unwrap();
+    }
+}
+
+/// An untyped capability retyped into a task shows up in the target
+/// slot, which was empty before.
+fn retype(task_buffer: usize) -> bool {
+    const SLOT: usize = 220;
+
+    if system::task_read_registers(task_buffer, SLOT).is_ok() {
+        return false;
+    }
+    system::retype_task(task_buffer, UNTYPED, SLOT).unwrap();
+    system::task_read_registers(task_buffer, SLOT).is_ok()
+}
+
+/// A value put to a channel is taken back, and a value put before the
+/// previous one is taken replaces it.
+fn channels(task_buffer: usize) -> bool {
+    system::channel_put(task_buffer, CHANNEL, 1).unwrap();
+    let first = system::channel_take(task_buffer, CHANNEL).unwrap();
+    system::channel_put(task_buffer, CHANNEL, 2).unwrap();
+    system::channel_put(task_buffer, CHANNEL, 3).unwrap();
+    let second = system::channel_take(task_buffer, CHANNEL).unwrap();
+    first == 1 && second == 3
+}
+
+/// A spawned thread runs, and its exit code is put to its exit channel.
+fn task_spawn(task_buffer: usize) -> bool {
+    Builder::new(task_buffer, UNTYPED, 200)
+        .stack(0x62000000, 4)
+        .buffer(0x90006000)
+        .exit_channel(EXIT_CHANNEL)
+        .name("test spawn")
+        .spawn(spawned_main).unwrap();
+
+    let value = system::channel_take(task_buffer, CHANNEL).unwrap();
+    let code = system::channel_take(task_buffer, EXIT_CHANNEL).unwrap();
+    value == 42 && code == 7
+}
+
+extern "C" fn spawned_main(task_buffer: usize) -> ! {
+    system::channel_put(task_buffer, CHANNEL, 42).unwrap();
+    system::task_exit(task_buffer, 7);
+}
+
+/// A fault on an unmapped address is put to the pager channel of the
+/// faulting thread, which is then stopped.
+fn page_fault(task_buffer: usize) -> bool {
+    let thread = Builder::new(task_buffer, UNTYPED, 208)
+        .stack(0x63000000, 4)
+        .buffer(0x90007000)
+        .name("test fault")
+        .spawn(page_fault_main).unwrap();
+    system::task_set_pager_channel(task_buffer, thread.task(), EXIT_CHANNEL).unwrap();
+    system::channel_put(task_buffer, CHANNEL, 0).unwrap();
+
+    let message = system::channel_take(task_buffer, EXIT_CHANNEL).unwrap();
+    thread.delete().unwrap();
+    message & system::PAGE_FAULT_ADDRESS_MASK == FAULT_ADDRESS &&
+        message & (system::PAGE_FAULT_PRESENT | system::PAGE_FAULT_STACK_OVERFLOW) == 0
+}
+
+extern "C" fn page_fault_main(task_buffer: usize) -> ! {
+    // Wait until the pager channel is set.
+    system::channel_take(task_buffer, CHANNEL).unwrap();
+    unsafe { ptr::read_volatile(FAULT_ADDRESS as *const u64); }
+    loop { }
+}
+
+/// Running off the end of a thread stack hits its guard page, which is
+/// reported as a stack overflow.
+fn stack_overflow(task_buffer: usize) -> bool {
+    let thread = Builder::new(task_buffer, UNTYPED, 216)
+        .stack(0x64000000, 1)
+        .buffer(0x90008000)
+        .name("test overflow")
+        .spawn(stack_overflow_main).unwrap();
+    system::task_set_pager_channel(task_buffer, thread.task(), EXIT_CHANNEL).unwrap();
+    system::channel_put(task_buffer, CHANNEL, 0).unwrap();
+
+    let message = system::channel_take(task_buffer, EXIT_CHANNEL).unwrap();
+    thread.delete().unwrap();
+    message & system::PAGE_FAULT_STACK_OVERFLOW != 0 &&
+        message & system::PAGE_FAULT_ADDRESS_MASK == 0x64000000 - 0x1000
+}
+
+extern "C" fn stack_overflow_main(task_buffer: usize) -> ! {
+    // Wait until the pager channel is set.
+    system::channel_take(task_buffer, CHANNEL).unwrap();
+    recurse(0);
+    loop { }
+}
+
+#[allow(unconditional_recursion)]
+fn recurse(depth: u64) -> u64 {
+    let frame = [depth; 64];
+    unsafe { ptr::read_volatile(&frame[0]) + recurse(depth + 1) }
+}
+
+/// A thread woken up from a long sleep and then put to a short one
+/// wakes up when the short sleep ends, not when the long one would
+/// have.
+fn wakeup_then_sleep(task_buffer: usize) -> bool {
+    let thread = Builder::new(task_buffer, UNTYPED, 224)
+        .stack(0x65000000, 4)
+        .buffer(0x90009000)
+        .name("test sleep")
+        .spawn(wakeup_then_sleep_main).unwrap();
+
+    // Wait until the thread is in its long sleep.
+    system::channel_take(task_buffer, CHANNEL).unwrap();
+    system::task_sleep(task_buffer, 5).unwrap();
+    system::task_wakeup(task_buffer, thread.task()).unwrap();
+
+    system::task_sleep(task_buffer, 50).unwrap();
+    let stage = SLEEP_STAGE.load(Ordering::SeqCst);
+    thread.delete().unwrap();
+    stage == 2
+}
+
+extern "C" fn wakeup_then_sleep_main(task_buffer: usize) -> ! {
+    system::channel_put(task_buffer, CHANNEL, 0).unwrap();
+    system::task_sleep(task_buffer, 10000).unwrap();
+    SLEEP_STAGE.store(1, Ordering::SeqCst);
+    system::task_sleep(task_buffer, 2).unwrap();
+    SLEEP_STAGE.store(2, Ordering::SeqCst);
+    loop {
+        system::task_yield(task_buffer).unwrap();
+    }
+}
+
+/// An IRQ line with a handler cannot be retyped again until the
+/// handler is deleted.
+fn irq_handler_delete(task_buffer: usize) -> bool {
+    const IRQ: u8 = 5;
+    const FIRST_SLOT: usize = 230;
+    const SECOND_SLOT: usize = 231;
+
+    system::retype_irq_handler(task_buffer, UNTYPED, IRQ, FIRST_SLOT).unwrap();
+    if system::retype_irq_handler(task_buffer, UNTYPED, IRQ, SECOND_SLOT).is_ok() {
+        return false;
+    }
+    system::irq_handler_delete(task_buffer, FIRST_SLOT).unwrap();
+    system::retype_irq_handler(task_buffer, UNTYPED, IRQ, SECOND_SLOT).is_ok()
+}
@@ -159,6 +159,16 @@ pub static WRITER: Mutex<Writer> = Mutex::new(Writer {
     buffer: unsafe { Unique::new(0x90002000 as *mut _) },
 });
 
+/// Shell output to the VGA buffer. The writer is locked for each
+/// write only.
+pub struct Console;
+
+impl fmt::Write for Console {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        WRITER.lock().write_str(s)
+    }
+}
+
 pub fn clear_screen() {
     WRITER.lock().clear_screen();
 }
@@ -1,107 +1,392 @@
-use abi::{SystemCall, TaskBuffer};
+use abi::{SystemCall, TaskBuffer, TaskRegisters, MessageError, TASK_NAME_LENGTH,
+          FAST_CALL_BUFFER, FAST_CALL_CHANNEL_PUT, FAST_CALL_TASK_YIELD,
+          FAST_CALL_TASK_SLEEP, FAST_CALL_TASK_WAKEUP};
 use spin::{Mutex};
 
-pub fn retype_cpool(addr: usize, source: usize, target: usize) {
-    system_call(SystemCall::RetypeCPool {
+pub fn retype_cpool(addr: usize, source: usize, target: usize) -> Result<(), MessageError> {
+    try!(system_call(SystemCall::RetypeCPool {
         request: (source, target),
-    }, addr);
+    }, addr));
+    Ok(())
 }
 
-pub fn retype_task(addr: usize, source: usize, target: usize) {
-    system_call(SystemCall::RetypeTask {
+pub fn retype_task(addr: usize, source: usize, target: usize) -> Result<(), MessageError> {
+    try!(system_call(SystemCall::RetypeTask {
         request: (source, target),
-    }, addr);
+    }, addr));
+    Ok(())
 }
 
-pub fn task_set_instruction_pointer(addr: usize, target: usize, ptr: u64) {
-    system_call(SystemCall::TaskSetInstructionPointer {
+pub fn task_set_instruction_pointer(addr: usize, target: usize, ptr: u64) -> Result<(), MessageError> {
+    try!(system_call(SystemCall::TaskSetInstructionPointer {
         request: (target, ptr),
-    }, addr);
+    }, addr));
+    Ok(())
 }
 
-pub fn task_set_stack_pointer(addr: usize, target: usize, ptr: u64) {
-    system_call(SystemCall::TaskSetStackPointer {
+pub fn task_set_stack_pointer(addr: usize, target: usize, ptr: u64) -> Result<(), MessageError> {
+    try!(system_call(SystemCall::TaskSetStackPointer {
         request: (target, ptr),
-    }, addr);
+    }, addr));
+    Ok(())
 }
 
-pub fn task_set_cpool(addr: usize, target: usize, cpool: usize) {
-    system_call(SystemCall::TaskSetCPool {
+pub fn task_set_cpool(addr: usize, target: usize, cpool: usize) -> Result<(), MessageError> {
+    try!(system_call(SystemCall::TaskSetCPool {
         request: (target, cpool),
-    }, addr);
+    }, addr));
+    Ok(())
 }
 
-pub fn task_set_top_page_table(addr: usize, target: usize, table: usize) {
-    system_call(SystemCall::TaskSetTopPageTable {
+pub fn task_set_top_page_table(addr: usize, target: usize, table: usize) -> Result<(), MessageError> {
+    try!(system_call(SystemCall::TaskSetTopPageTable {
         request: (target, table),
-    }, addr);
+    }, addr));
+    Ok(())
 }
 
-pub fn task_set_buffer(addr: usize, target: usize, buffer: usize) {
-    system_call(SystemCall::TaskSetBuffer {
+pub fn task_set_buffer(addr: usize, target: usize, buffer: usize) -> Result<(), MessageError> {
+    try!(system_call(SystemCall::TaskSetBuffer {
         request: (target, buffer),
-    }, addr);
+    }, addr));
+    Ok(())
 }
 
-pub fn task_set_active(addr: usize, target: usize) {
-    system_call(SystemCall::TaskSetActive {
+pub fn task_set_active(addr: usize, target: usize) -> Result<(), MessageError> {
+    try!(system_call(SystemCall::TaskSetActive {
         request: target
-    }, addr);
+    }, addr));
+    Ok(())
 }
 
-pub fn task_set_inactive(addr: usize, target: usize) {
-    system_call(SystemCall::TaskSetInactive {
+pub fn task_set_inactive(addr: usize, target: usize) -> Result<(), MessageError> {
+    try!(system_call(SystemCall::TaskSetInactive {
         request: target
-    }, addr);
+    }, addr));
+    Ok(())
 }
 
-pub fn channel_take(addr: usize, target: usize) -> u64 {
-    let result = system_call(SystemCall::ChannelTake {
+pub fn task_set_priority(addr: usize, target: usize, priority: u8) -> Result<(), MessageError> {
+    try!(system_call(SystemCall::TaskSetPriority {
+        request: (target, priority),
+    }, addr));
+    Ok(())
+}
+
+pub fn task_set_affinity(addr: usize, target: usize, cpu: Option<usize>) -> Result<(), MessageError> {
+    try!(system_call(SystemCall::TaskSetAffinity {
+        request: (target, cpu),
+    }, addr));
+    Ok(())
+}
+
+pub fn task_set_timeslice(addr: usize, target: usize, ticks: u64) -> Result<(), MessageError> {
+    try!(system_call(SystemCall::TaskSetTimeslice {
+        request: (target, ticks),
+    }, addr));
+    Ok(())
+}
+
+pub fn task_set_budget(addr: usize, target: usize, budget: u64, period: u64) -> Result<(), MessageError> {
+    try!(system_call(SystemCall::TaskSetBudget {
+        request: (target, budget, period),
+    }, addr));
+    Ok(())
+}
+
+pub fn task_cpu_time(addr: usize, target: usize) -> Result<u64, MessageError> {
+    let result = try!(system_call(SystemCall::TaskCpuTime {
+        request: target,
+        response: None
+    }, addr));
+    match result {
+        SystemCall::TaskCpuTime {
+            request: _,
+            response: response,
+        } => {
+            return Ok(response.unwrap())
+        },
+        _ => panic!(),
+    };
+}
+
+pub fn retype_sched_context(addr: usize, source: usize, target: usize) -> Result<(), MessageError> {
+    try!(system_call(SystemCall::RetypeSchedContext {
+        request: (source, target),
+    }, addr));
+    Ok(())
+}
+
+pub fn sched_context_configure(addr: usize, target: usize, priority: u8, budget: u64, period: u64) -> Result<(), MessageError> {
+    try!(system_call(SystemCall::SchedContextConfigure {
+        request: (target, priority, budget, period),
+    }, addr));
+    Ok(())
+}
+
+pub fn task_bind_sched_context(addr: usize, target: usize, sched_context: usize) -> Result<(), MessageError> {
+    try!(system_call(SystemCall::TaskBindSchedContext {
+        request: (target, sched_context),
+    }, addr));
+    Ok(())
+}
+
+pub fn task_set_passive(addr: usize, target: usize, passive: bool) -> Result<(), MessageError> {
+    try!(system_call(SystemCall::TaskSetPassive {
+        request: (target, passive),
+    }, addr));
+    Ok(())
+}
+
+pub fn task_read_registers(addr: usize, target: usize) -> Result<TaskRegisters, MessageError> {
+    let result = try!(system_call(SystemCall::TaskReadRegisters {
         request: target,
         response: None
-    }, addr);
+    }, addr));
+    match result {
+        SystemCall::TaskReadRegisters {
+            request: _,
+            response: response,
+        } => {
+            return Ok(response.unwrap())
+        },
+        _ => panic!(),
+    };
+}
+
+pub fn task_write_registers(addr: usize, target: usize, registers: TaskRegisters) -> Result<(), MessageError> {
+    try!(system_call(SystemCall::TaskWriteRegisters {
+        request: (target, registers),
+    }, addr));
+    Ok(())
+}
+
+pub fn task_delete(addr: usize, target: usize) -> Result<(), MessageError> {
+    try!(system_call(SystemCall::TaskDelete {
+        request: target,
+    }, addr));
+    Ok(())
+}
+
+pub fn task_exit(addr: usize, code: u64) -> ! {
+    system_call(SystemCall::TaskExit {
+        request: code,
+    }, addr).unwrap();
+    unreachable!();
+}
+
+pub fn task_set_exit_channel(addr: usize, target: usize, channel: usize) -> Result<(), MessageError> {
+    try!(system_call(SystemCall::TaskSetExitChannel {
+        request: (target, channel),
+    }, addr));
+    Ok(())
+}
+
+pub fn task_yield(_addr: usize) -> Result<(), MessageError> {
+    unsafe { fast_system_call_raw(FAST_CALL_TASK_YIELD, 0, 0) }
+}
+
+pub fn task_sleep(_addr: usize, ticks: u64) -> Result<(), MessageError> {
+    unsafe { fast_system_call_raw(FAST_CALL_TASK_SLEEP, ticks, 0) }
+}
+
+pub fn task_wakeup(_addr: usize, target: usize) -> Result<(), MessageError> {
+    unsafe { fast_system_call_raw(FAST_CALL_TASK_WAKEUP, target as u64, 0) }
+}
+
+pub fn retype_irq_handler(addr: usize, source: usize, irq: u8, target: usize) -> Result<(), MessageError> {
+    try!(system_call(SystemCall::RetypeIRQHandler {
+        request: (source, irq, target),
+    }, addr));
+    Ok(())
+}
+
+pub fn irq_handler_set_channel(addr: usize, target: usize, channel: usize) -> Result<(), MessageError> {
+    try!(system_call(SystemCall::IRQHandlerSetChannel {
+        request: (target, channel),
+    }, addr));
+    Ok(())
+}
+
+pub fn irq_ack(addr: usize, target: usize) -> Result<(), MessageError> {
+    try!(system_call(SystemCall::IRQAck {
+        request: target
+    }, addr));
+    Ok(())
+}
+
+pub fn irq_handler_delete(addr: usize, target: usize) -> Result<(), MessageError> {
+    try!(system_call(SystemCall::IRQHandlerDelete {
+        request: target
+    }, addr));
+    Ok(())
+}
+
+pub fn retype_top_page_table(addr: usize, source: usize, target: usize) -> Result<(), MessageError> {
+    try!(system_call(SystemCall::RetypeTopPageTable {
+        request: (source, target),
+    }, addr));
+    Ok(())
+}
+
+pub fn top_page_table_map_copy_on_write(addr: usize, source: usize, target: usize, vaddr: u64, untyped: usize) -> Result<(), MessageError> {
+    try!(system_call(SystemCall::TopPageTableMapCopyOnWrite {
+        request: (source, target, vaddr, untyped),
+    }, addr));
+    Ok(())
+}
+
+pub fn task_set_fault_untyped(addr: usize, target: usize, untyped: usize) -> Result<(), MessageError> {
+    try!(system_call(SystemCall::TaskSetFaultUntyped {
+        request: (target, untyped),
+    }, addr));
+    Ok(())
+}
+
+pub fn task_set_pager_channel(addr: usize, target: usize, channel: usize) -> Result<(), MessageError> {
+    try!(system_call(SystemCall::TaskSetPagerChannel {
+        request: (target, channel),
+    }, addr));
+    Ok(())
+}
+
+pub fn retype_raw_page(addr: usize, source: usize, target: usize) -> Result<(), MessageError> {
+    try!(system_call(SystemCall::RetypeRawPage {
+        request: (source, target),
+    }, addr));
+    Ok(())
+}
+
+pub fn retype_task_buffer(addr: usize, source: usize, target: usize) -> Result<(), MessageError> {
+    try!(system_call(SystemCall::RetypeTaskBuffer {
+        request: (source, target),
+    }, addr));
+    Ok(())
+}
+
+pub fn top_page_table_map(addr: usize, target: usize, vaddr: u64, page: usize, untyped: usize) -> Result<(), MessageError> {
+    try!(system_call(SystemCall::TopPageTableMap {
+        request: (target, vaddr, page, untyped),
+    }, addr));
+    Ok(())
+}
+
+pub fn top_page_table_map_guard(addr: usize, target: usize, vaddr: u64, stack_length: usize, untyped: usize) -> Result<(), MessageError> {
+    try!(system_call(SystemCall::TopPageTableMapGuard {
+        request: (target, vaddr, stack_length, untyped),
+    }, addr));
+    Ok(())
+}
+
+pub fn channel_take(addr: usize, target: usize) -> Result<u64, MessageError> {
+    let result = try!(system_call(SystemCall::ChannelTake {
+        request: target,
+        response: None
+    }, addr));
     match result {
         SystemCall::ChannelTake {
             request: _,
             response: response,
         } => {
-            return response.unwrap()
+            return Ok(response.unwrap())
         },
         _ => panic!(),
     };
 }
 
-pub fn channel_put(addr: usize, target: usize, value: u64) {
-    system_call(SystemCall::ChannelPut {
-        request: (target, value)
-    }, addr);
+pub fn channel_put(_addr: usize, target: usize, value: u64) -> Result<(), MessageError> {
+    unsafe { fast_system_call_raw(FAST_CALL_CHANNEL_PUT, target as u64, value) }
+}
+
+/// Print the bytes at `(offset, length)` of the data area of the task
+/// buffer at `addr`.
+pub fn print(addr: usize, offset: usize, length: usize) -> Result<(), MessageError> {
+    try!(system_call(SystemCall::Print {
+        request: (offset, length)
+    }, addr));
+    Ok(())
+}
+
+/// Name the task at `target` for kernel logs. The name is copied to
+/// the start of the data area of the task buffer at `addr`, and is
+/// truncated to `TASK_NAME_LENGTH` bytes.
+pub fn task_set_name(addr: usize, target: usize, name: &str) -> Result<(), MessageError> {
+    let length = ::core::cmp::min(name.len(), TASK_NAME_LENGTH);
+    {
+        let buffer = unsafe { &mut *(addr as *mut TaskBuffer) };
+        buffer.data[0..length].copy_from_slice(&name.as_bytes()[0..length]);
+    }
+    try!(system_call(SystemCall::TaskSetName {
+        request: (target, 0, length)
+    }, addr));
+    Ok(())
+}
+
+/// Read the kernel log from `position` into `(offset, length)` of the
+/// data area of the task buffer at `addr`, using the kernel log
+/// capability at `log`. Returns the position to continue from and the
+/// number of bytes read, or an error if `log` is not a kernel log
+/// capability.
+pub fn kernel_log_read(addr: usize, log: usize, position: u64,
+                       offset: usize, length: usize) -> Result<(u64, usize), MessageError> {
+    let result = try!(system_call(SystemCall::KernelLogRead {
+        request: (log, position, offset, length),
+        response: None
+    }, addr));
+    match result {
+        SystemCall::KernelLogRead {
+            request: _,
+            response: read,
+        } => Ok(read.unwrap()),
+        _ => panic!(),
+    }
 }
 
-pub fn print(addr: usize, buffer: [u8; 32], size: usize) {
-    let result = system_call(SystemCall::Print {
-        request: (buffer, size)
-    }, addr);
+pub fn cpool_list_debug(addr: usize) -> Result<(), MessageError> {
+    try!(system_call(SystemCall::CPoolListDebug, addr));
+    Ok(())
 }
 
-pub fn cpool_list_debug(addr: usize) {
-    system_call(SystemCall::CPoolListDebug, addr);
+/// Status of the last system call made through the task buffer at
+/// `addr`. The kernel reports malformed messages here, leaving the rest
+/// of the buffer untouched.
+pub fn call_status(addr: usize) -> Result<(), MessageError> {
+    let buffer = unsafe { &*(addr as *const TaskBuffer) };
+    buffer.status()
 }
 
-fn system_call(message: SystemCall, addr: usize) -> SystemCall {
+/// Make the system call `message` through the task buffer at `addr`,
+/// and read back its response. If the kernel reports an error, the
+/// buffer still holds the request, and the error is returned instead.
+fn system_call(message: SystemCall, addr: usize) -> Result<SystemCall, MessageError> {
     unsafe {
         let buffer = unsafe { &mut *(addr as *mut TaskBuffer) };
-        buffer.call = Some(message);
-        system_call_raw();
-        buffer.call.take().unwrap()
+        buffer.write_call(&message);
+        try!(system_call_raw());
+        try!(buffer.status());
+        Ok(buffer.read_call().unwrap())
     }
 }
 
-unsafe fn system_call_raw() {
-    unsafe {
-        asm!("int 80h"
-             ::
-             : "rax", "rbx", "rcx", "rdx",
-               "r8", "r9", "r10", "r11", "r12", "r13", "r14", "r15"
-             : "volatile", "intel");
+unsafe fn system_call_raw() -> Result<(), MessageError> {
+    fast_system_call_raw(FAST_CALL_BUFFER, 0, 0)
+}
+
+/// Enter the kernel with the `syscall` instruction. The call number
+/// is passed in `rax`, and its arguments in `rdi` and `rsi`. The
+/// status comes back in `rax` and `rdx`. The instruction clobbers
+/// `rcx` and `r11`. The kernel still accepts system calls made with
+/// `int 80h`.
+unsafe fn fast_system_call_raw(number: u64, arg0: u64, arg1: u64) -> Result<(), MessageError> {
+    let status: u64;
+    let value: u64;
+    asm!("syscall"
+         : "={rax}"(status), "={rdx}"(value)
+         : "{rax}"(number), "{rdi}"(arg0), "{rsi}"(arg1)
+         : "rcx", "r11", "memory"
+         : "volatile", "intel");
+    match MessageError::from_status(status, value) {
+        Some(error) => Err(error),
+        None => Ok(()),
     }
 }
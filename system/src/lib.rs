@@ -8,17 +8,33 @@ extern crate abi;
 extern crate spin;
 
 pub mod unwind;
+pub mod thread;
 mod call;
 
 pub use self::call::{cpool_list_debug, retype_cpool, retype_task,
                      channel_put, channel_take, task_set_stack_pointer, task_set_instruction_pointer,
                      task_set_cpool, task_set_top_page_table, task_set_buffer,
-                     task_set_active, task_set_inactive};
+                     task_set_active, task_set_inactive, task_set_priority, task_set_affinity,
+                     task_set_timeslice, task_set_budget, task_cpu_time,
+                     retype_sched_context, sched_context_configure,
+                     task_bind_sched_context, task_set_passive,
+                     task_delete, task_exit, task_set_exit_channel,
+                     task_yield, task_sleep, task_wakeup,
+                     task_read_registers, task_write_registers,
+                     retype_irq_handler, irq_handler_set_channel, irq_ack, irq_handler_delete,
+                     retype_top_page_table, top_page_table_map_copy_on_write,
+                     task_set_fault_untyped, task_set_pager_channel, task_set_name,
+                     retype_raw_page, retype_task_buffer, top_page_table_map, top_page_table_map_guard,
+                     kernel_log_read, call_status};
+pub use abi::{TaskBuffer, MessageError, DATA_LENGTH, PAGE_FAULT_PRESENT, PAGE_FAULT_WRITE,
+              PAGE_FAULT_STACK_OVERFLOW, PAGE_FAULT_ADDRESS_MASK};
 
 use core::fmt;
 
+/// Formatted output printed by the kernel log. Text is collected in
+/// the data area of the task buffer, and printed when the area is full
+/// or the writer is dropped.
 pub struct PrintWriter {
-    buffer: [u8; 32],
     size: usize,
     addr: usize,
 }
@@ -26,7 +42,6 @@ pub struct PrintWriter {
 impl PrintWriter {
     pub fn new(addr: usize) -> Self {
         PrintWriter {
-            buffer: [0u8; 32],
             size: 0,
             addr: addr,
         }
@@ -34,8 +49,7 @@ impl PrintWriter {
 
     pub fn flush(&mut self) {
         if self.size > 0 {
-            call::print(self.addr, self.buffer.clone(), self.size);
-            self.buffer = [0u8; 32];
+            call::print(self.addr, 0, self.size).unwrap();
             self.size = 0;
         }
     }
@@ -43,11 +57,14 @@ impl PrintWriter {
 
 impl fmt::Write for PrintWriter {
     fn write_str(&mut self, s: &str) -> fmt::Result {
+        use abi::{TaskBuffer, DATA_LENGTH};
+
         for u in s.as_bytes().iter() {
-            self.buffer[self.size] = *u;
+            let buffer = unsafe { &mut *(self.addr as *mut TaskBuffer) };
+            buffer.data[self.size] = *u;
             self.size += 1;
 
-            if self.size >= 32 {
+            if self.size >= DATA_LENGTH {
                 self.flush();
             }
         }
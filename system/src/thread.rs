@@ -0,0 +1,204 @@
+use call;
+use abi::{MessageError};
+
+/// Length of a page.
+const PAGE_LENGTH: usize = 0x1000;
+
+/// Entry of a thread. It is called with the virtual address of the
+/// thread's task buffer, and must not return.
+pub type Entry = extern "C" fn(usize) -> !;
+
+/// A spawned thread.
+pub struct Thread {
+    addr: usize,
+    task: usize,
+    buffer: usize,
+}
+
+impl Thread {
+    /// Capability pool slot of the thread's task.
+    pub fn task(&self) -> usize {
+        self.task
+    }
+
+    /// Virtual address of the thread's task buffer.
+    pub fn buffer(&self) -> usize {
+        self.buffer
+    }
+
+    /// Stop the thread.
+    pub fn stop(&self) -> Result<(), MessageError> {
+        call::task_set_inactive(self.addr, self.task)
+    }
+
+    /// Resume a stopped thread.
+    pub fn resume(&self) -> Result<(), MessageError> {
+        call::task_set_active(self.addr, self.task)
+    }
+
+    /// Delete the thread's task. The task buffer and stack pages stay
+    /// in their capability pool slots and stay mapped, as pages cannot
+    /// be unmapped. Their slots and virtual addresses are not reused.
+    pub fn delete(self) -> Result<(), MessageError> {
+        call::task_delete(self.addr, self.task)
+    }
+}
+
+/// Thread builder. A new thread shares the capability pool and the top
+/// page table of the spawning task.
+///
+/// Capabilities of the thread are put in consecutive capability pool
+/// slots: the task, the task buffer page, and then the stack pages.
+/// All of them must be empty.
+pub struct Builder {
+    addr: usize,
+    untyped: usize,
+    cpool: usize,
+    table: usize,
+    slots: usize,
+    stack: usize,
+    stack_pages: usize,
+    buffer: usize,
+    priority: Option<u8>,
+    exit_channel: Option<usize>,
+    name: Option<&'static str>,
+}
+
+impl Builder {
+    /// Create a thread builder. `addr` is the task buffer of the
+    /// spawning task, `untyped` the slot of the untyped capability the
+    /// thread is retyped from, and `slots` the first of the empty slots
+    /// the thread's capabilities are put in. The capability pool and
+    /// the top page table default to slots 0 and 3, as set up for
+    /// rinit.
+    pub fn new(addr: usize, untyped: usize, slots: usize) -> Builder {
+        Builder {
+            addr: addr,
+            untyped: untyped,
+            cpool: 0,
+            table: 3,
+            slots: slots,
+            stack: 0,
+            stack_pages: 4,
+            buffer: 0,
+            priority: None,
+            exit_channel: None,
+            name: None,
+        }
+    }
+
+    /// Capability pool slot of the root capability pool to share.
+    pub fn cpool(mut self, cpool: usize) -> Builder {
+        self.cpool = cpool;
+        self
+    }
+
+    /// Capability pool slot of the top page table to share.
+    pub fn table(mut self, table: usize) -> Builder {
+        self.table = table;
+        self
+    }
+
+    /// Map the stack at `vaddr` with `pages` pages. The page below it
+    /// becomes a guard page.
+    pub fn stack(mut self, vaddr: usize, pages: usize) -> Builder {
+        assert!(vaddr % PAGE_LENGTH == 0 && pages > 0);
+        self.stack = vaddr;
+        self.stack_pages = pages;
+        self
+    }
+
+    /// Map the task buffer at `vaddr`.
+    pub fn buffer(mut self, vaddr: usize) -> Builder {
+        assert!(vaddr % PAGE_LENGTH == 0);
+        self.buffer = vaddr;
+        self
+    }
+
+    /// Scheduling priority of the thread.
+    pub fn priority(mut self, priority: u8) -> Builder {
+        self.priority = Some(priority);
+        self
+    }
+
+    /// Channel the exit code of the thread is put in.
+    pub fn exit_channel(mut self, channel: usize) -> Builder {
+        self.exit_channel = Some(channel);
+        self
+    }
+
+    /// Debug name of the thread, shown in kernel logs.
+    pub fn name(mut self, name: &'static str) -> Builder {
+        self.name = Some(name);
+        self
+    }
+
+    /// Number of capability pool slots used by the thread.
+    pub fn slot_count(&self) -> usize {
+        2 + self.stack_pages
+    }
+
+    /// Retype, map and activate the thread, starting at `entry`. The
+    /// first failing system call is returned, leaving the capabilities
+    /// and mappings made before it in place.
+    pub fn spawn(self, entry: Entry) -> Result<Thread, MessageError> {
+        assert!(self.stack != 0 && self.buffer != 0);
+
+        let task = self.slots;
+        let buffer_page = self.slots + 1;
+
+        try!(call::retype_task_buffer(self.addr, self.untyped, buffer_page));
+        try!(call::top_page_table_map(self.addr, self.table, self.buffer as u64,
+                                      buffer_page, self.untyped));
+
+        try!(call::top_page_table_map_guard(self.addr, self.table, (self.stack - PAGE_LENGTH) as u64,
+                                            self.stack_pages * PAGE_LENGTH, self.untyped));
+        for i in 0..self.stack_pages {
+            let stack_page = self.slots + 2 + i;
+            try!(call::retype_raw_page(self.addr, self.untyped, stack_page));
+            try!(call::top_page_table_map(self.addr, self.table, (self.stack + i * PAGE_LENGTH) as u64,
+                                          stack_page, self.untyped));
+        }
+
+        try!(call::retype_task(self.addr, self.untyped, task));
+        try!(call::task_set_cpool(self.addr, task, self.cpool));
+        try!(call::task_set_top_page_table(self.addr, task, self.table));
+        try!(call::task_set_buffer(self.addr, task, buffer_page));
+        if let Some(priority) = self.priority {
+            try!(call::task_set_priority(self.addr, task, priority));
+        }
+        if let Some(channel) = self.exit_channel {
+            try!(call::task_set_exit_channel(self.addr, task, channel));
+        }
+        if let Some(name) = self.name {
+            try!(call::task_set_name(self.addr, task, name));
+        }
+
+        // The stack pointer is kept 16-byte aligned plus a return
+        // address slot, as it would be after a call.
+        let mut registers = try!(call::task_read_registers(self.addr, task));
+        registers.rip = entry as usize as u64;
+        registers.rsp = (self.stack + self.stack_pages * PAGE_LENGTH - 8) as u64;
+        registers.rdi = self.buffer as u64;
+        try!(call::task_write_registers(self.addr, task, registers));
+
+        try!(call::task_set_active(self.addr, task));
+
+        Ok(Thread {
+            addr: self.addr,
+            task: task,
+            buffer: self.buffer,
+        })
+    }
+}
+
+/// Spawn a thread at `entry`, with `stack_pages` stack pages mapped at
+/// `stack` and the task buffer mapped at `buffer`. See `Builder` for
+/// the slots used.
+pub fn spawn(addr: usize, entry: Entry, stack: usize, stack_pages: usize, buffer: usize,
+             untyped: usize, slots: usize) -> Result<Thread, MessageError> {
+    Builder::new(addr, untyped, slots)
+        .stack(stack, stack_pages)
+        .buffer(buffer)
+        .spawn(entry)
+}